@@ -1,5 +1,7 @@
 //! A thin wrapper of [prost](https://docs.rs/prost/0.6.1/prost/)
 
+use std::io;
+
 /// A labcodec message.
 pub trait Message: prost::Message + Default {}
 impl<T: prost::Message + Default> Message for T {}
@@ -16,11 +18,64 @@ pub fn encode<M: Message>(message: &M, buf: &mut Vec<u8>) -> Result<(), EncodeEr
     Ok(())
 }
 
+/// Returns the exact size of the encoded message, it can be used to
+/// pre-allocate buffers.
+pub fn size_hint<M: Message>(message: &M) -> usize {
+    message.encoded_len()
+}
+
+/// Encodes the message to a slice, returns the number of bytes written.
+///
+/// An error is returned if the slice does not have enough capacity.
+pub fn encode_into<M: Message>(message: &M, mut buf: &mut [u8]) -> Result<usize, EncodeError> {
+    let len = message.encoded_len();
+    message.encode(&mut buf)?;
+    Ok(len)
+}
+
 /// Decodes an message from the buffer.
 pub fn decode<M: Message>(buf: &[u8]) -> Result<M, DecodeError> {
     M::decode(buf)
 }
 
+/// Encodes the message to a writer, e.g. a `BufWriter<File>`.
+pub fn encode_to_writer<M: Message, W: io::Write>(message: &M, w: &mut W) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut buf).map_err(io::Error::other)?;
+    w.write_all(&buf)
+}
+
+/// Decodes an message from a reader.
+///
+/// The message is not length-prefixed, it reads the reader to the end, just
+/// like `decode` takes the whole buffer.
+pub fn decode_from_reader<M: Message, R: io::Read>(r: &mut R) -> io::Result<M> {
+    let mut buf = vec![];
+    r.read_to_end(&mut buf)?;
+    M::decode(&*buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Encodes the message as JSON to a writer, so that it can be read when
+/// debugging. It is not meant for the wire, use `encode` instead.
+#[cfg(feature = "debug-json")]
+pub fn json_encode<M, W>(message: &M, w: &mut W) -> io::Result<()>
+where
+    M: Message + serde::Serialize,
+    W: io::Write,
+{
+    serde_json::to_writer(w, message).map_err(io::Error::from)
+}
+
+/// Decodes a message from the JSON written by `json_encode`.
+#[cfg(feature = "debug-json")]
+pub fn json_decode<M, R>(r: &mut R) -> io::Result<M>
+where
+    M: Message + serde::de::DeserializeOwned,
+    R: io::Read,
+{
+    serde_json::from_reader(r).map_err(io::Error::from)
+}
+
 #[cfg(test)]
 mod tests {
     mod fixture {
@@ -55,7 +110,9 @@ mod tests {
         include!(concat!(env!("OUT_DIR"), "/fixture.rs"));
     }
 
-    use super::{decode, encode};
+    use std::io;
+
+    use super::{decode, decode_from_reader, encode, encode_into, encode_to_writer, size_hint};
 
     #[test]
     fn test_basic_encode_decode() {
@@ -71,6 +128,52 @@ mod tests {
         assert_eq!(msg, msg1);
     }
 
+    #[test]
+    fn test_size_hint_encode_into() {
+        let msg = fixture::Msg {
+            r#type: fixture::msg::Type::Get as _,
+            id: 7,
+            name: "size".to_owned(),
+            paylad: vec![vec![1; 100]],
+        };
+        let mut buf = Vec::with_capacity(size_hint(&msg));
+        encode(&msg, &mut buf).unwrap();
+        assert_eq!(buf.len(), size_hint(&msg));
+        assert_eq!(buf.capacity(), size_hint(&msg));
+
+        let mut slice = vec![0; size_hint(&msg) + 10];
+        let n = encode_into(&msg, &mut slice).unwrap();
+        assert_eq!(n, size_hint(&msg));
+        assert_eq!(&slice[..n], &buf[..]);
+        let msg1: fixture::Msg = decode(&slice[..n]).unwrap();
+        assert_eq!(msg, msg1);
+
+        let mut short = vec![0; size_hint(&msg) - 1];
+        encode_into(&msg, &mut short).unwrap_err();
+    }
+
+    #[test]
+    fn test_writer_reader() {
+        let msg = fixture::Msg {
+            r#type: fixture::msg::Type::Del as _,
+            id: 9,
+            name: "io".to_owned(),
+            paylad: vec![vec![2; 1000]],
+        };
+        let mut w = io::BufWriter::new(vec![]);
+        encode_to_writer(&msg, &mut w).unwrap();
+        let buf = w.into_inner().unwrap();
+        let mut expected = vec![];
+        encode(&msg, &mut expected).unwrap();
+        assert_eq!(buf, expected);
+
+        let msg1: fixture::Msg = decode_from_reader(&mut &buf[..]).unwrap();
+        assert_eq!(msg, msg1);
+
+        let err = decode_from_reader::<fixture::Msg, _>(&mut &buf[..10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn test_default() {
         let msg = fixture::Msg::default();
@@ -0,0 +1,88 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use futures::executor::block_on;
+use prost_derive::Message;
+
+use labrpc::{service, Network, Result, ServerBuilder};
+
+// Counts the bytes allocated, to see how many times a payload is copied.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+service! {
+    /// A bench-purpose service carrying large payloads.
+    service bulk {
+        rpc echo(BulkArgs) returns (BulkReply);
+    }
+}
+use bulk::{add_service, Client as BulkClient, Service};
+
+// Hand-written protobuf messages.
+#[derive(Clone, PartialEq, Message)]
+pub struct BulkArgs {
+    #[prost(bytes, tag = "1")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct BulkReply {
+    #[prost(bytes, tag = "1")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct BulkService;
+
+#[async_trait::async_trait]
+impl Service for BulkService {
+    async fn echo(&self, args: BulkArgs) -> Result<BulkReply> {
+        Ok(BulkReply { data: args.data })
+    }
+}
+
+const PAYLOAD: usize = 1024 * 1024;
+
+fn bench_payload(c: &mut Criterion) {
+    let net = Network::new();
+    let mut builder = ServerBuilder::new("test_server".to_owned());
+    add_service(BulkService, &mut builder).unwrap();
+    net.add_server(builder.build());
+    let client = BulkClient::new(net.create_client("client".to_owned()));
+    net.connect("client", "test_server");
+    net.enable("client", true);
+
+    let args = BulkArgs {
+        data: vec![1; PAYLOAD],
+    };
+    let call = || block_on(async { client.echo(&args).await.unwrap() });
+    call();
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    black_box(call());
+    let copies = (ALLOCATED.load(Ordering::Relaxed) - before) as f64 / PAYLOAD as f64;
+    // encoding and decoding the request and the reply, once each.
+    println!("payload 1MB: {:.2} copies per RPC", copies);
+
+    c.bench_function("payload 1MB", |b| {
+        b.iter(|| black_box(call()));
+    });
+}
+
+criterion_group!(benches, bench_payload);
+criterion_main!(benches);
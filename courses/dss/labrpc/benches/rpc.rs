@@ -80,6 +80,8 @@ fn bench_rpc(c: &mut Criterion) {
             }));
         })
         // i7-8650U, 13 microseconds per RPC
+        // 1 vCPU Xeon VM, 22 microseconds per RPC, 9 microseconds at 3aebf38 on the
+        // same VM
     });
 }
 
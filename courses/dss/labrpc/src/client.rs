@@ -1,23 +1,47 @@
+use std::borrow::Cow;
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use futures::channel::mpsc::UnboundedSender;
 use futures::channel::oneshot;
 use futures::executor::ThreadPool;
 use futures::future::{self, FutureExt};
+use futures_timer::Delay;
 
 use crate::error::{Error, Result};
+use crate::health::{HealthArgs, HealthReply};
 use crate::server::RpcFuture;
+use crate::stream::ChunkStream;
+
+static RPC_ID_ALLOC: AtomicU64 = AtomicU64::new(0);
 
 pub struct Rpc {
     pub(crate) client_name: String,
-    pub(crate) fq_name: &'static str,
+    pub(crate) rpc_id: u64,
+    pub(crate) fq_name: Cow<'static, str>,
     pub(crate) req: Option<Vec<u8>>,
     pub(crate) resp: Option<oneshot::Sender<Result<Vec<u8>>>>,
     pub(crate) hooks: Arc<Mutex<Option<Arc<dyn RpcHooks>>>>,
 }
 
 impl Rpc {
+    /// The name of the Client that sent it.
+    pub fn client_name(&self) -> &str {
+        &self.client_name
+    }
+
+    /// The fully qualified name of the method, e.g. `junk.handler2`.
+    pub fn fq_name(&self) -> &str {
+        &self.fq_name
+    }
+
+    /// The encoded request.
+    pub fn req(&self) -> &[u8] {
+        self.req.as_deref().unwrap_or_default()
+    }
+
     pub(crate) fn take_resp_sender(&mut self) -> Option<oneshot::Sender<Result<Vec<u8>>>> {
         self.resp.take()
     }
@@ -27,16 +51,131 @@ impl fmt::Debug for Rpc {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Rpc")
             .field("client_name", &self.client_name)
+            .field("rpc_id", &self.rpc_id)
             .field("fq_name", &self.fq_name)
             .finish()
     }
 }
 
+/// The calls made through a Client, see `Client::connection_stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub calls_sent: u64,
+    pub calls_succeeded: u64,
+    pub calls_timed_out: u64,
+    /// Calls that failed for reasons other than timeout.
+    pub calls_failed: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct ClientStats {
+    calls_sent: AtomicU64,
+    calls_succeeded: AtomicU64,
+    calls_timed_out: AtomicU64,
+    calls_failed: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+impl ClientStats {
+    fn record(&self, res: &Result<Vec<u8>>) {
+        match res {
+            Ok(resp) => {
+                self.calls_succeeded.fetch_add(1, Ordering::Relaxed);
+                self.bytes_received
+                    .fetch_add(resp.len() as u64, Ordering::Relaxed);
+            }
+            Err(Error::Timeout) => {
+                self.calls_timed_out.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.calls_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
 pub trait RpcHooks: Sync + Send + 'static {
     fn before_dispatch(&self, fq_name: &str, req: &[u8]) -> Result<()>;
     fn after_dispatch(&self, fq_name: &str, resp: Result<Vec<u8>>) -> Result<Vec<u8>>;
 }
 
+/// How the generated clients make a call, see `Client::call_with_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallPolicy {
+    /// Each attempt fails with `Error::Timeout` if there is no reply within
+    /// it.
+    pub timeout: Option<Duration>,
+    /// How many times a call failing with `Error::Timeout` is retried.
+    pub retries: usize,
+    /// How a call failing with a retryable error is retried once the
+    /// retries above are used up.
+    pub retry: RetryPolicy,
+}
+
+impl CallPolicy {
+    pub fn timeout_ms(mut self, ms: u64) -> CallPolicy {
+        self.timeout = Some(Duration::from_millis(ms));
+        self
+    }
+
+    pub fn retries(mut self, retries: usize) -> CallPolicy {
+        self.retries = retries;
+        self
+    }
+
+    pub fn retry_policy(mut self, retry: RetryPolicy) -> CallPolicy {
+        self.retry = retry;
+        self
+    }
+}
+
+/// How a call failing with a retryable error is retried, see
+/// `Client::call_with_retry`. The clients generated by `service!` retry
+/// every call with the policy they are created with.
+///
+/// The default policy makes a single attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many attempts a call makes at most, including the first one.
+    pub max_attempts: usize,
+    /// How long to wait before each retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::attempts(1)
+    }
+}
+
+impl RetryPolicy {
+    /// Makes up to `max_attempts` attempts without waiting in between.
+    pub fn attempts(max_attempts: usize) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            backoff: Duration::from_millis(0),
+        }
+    }
+
+    pub fn backoff_ms(mut self, ms: u64) -> RetryPolicy {
+        self.backoff = Duration::from_millis(ms);
+        self
+    }
+
+    /// Whether a call failing with `err` may succeed if it is made again,
+    /// i.e. the request or the reply has been lost, or the server has been
+    /// busy or replaced.
+    pub fn is_retryable(err: &Error) -> bool {
+        matches!(
+            err,
+            Error::Timeout | Error::Overloaded | Error::ServerKilled
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     // this end-point's name
@@ -44,12 +183,95 @@ pub struct Client {
     // copy of Network.sender
     pub(crate) sender: UnboundedSender<Rpc>,
     pub(crate) hooks: Arc<Mutex<Option<Arc<dyn RpcHooks>>>>,
+    // shared by the clones of the client
+    pub(crate) stats: Arc<ClientStats>,
 
     pub worker: ThreadPool,
 }
 
 impl Client {
-    pub fn call<Req, Rsp>(&self, fq_name: &'static str, req: &Req) -> RpcFuture<Result<Rsp>>
+    /// The name of this end.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Sends a request to the connected server and decodes its reply.
+    ///
+    /// `Rsp` only needs to be a `labcodec::Message`, which already implies
+    /// `Default`, so messages generated by prost can be used as they are.
+    ///
+    /// `fq_name` is like `junk.handler2`, the service name can be computed at
+    /// runtime, e.g. `format!("shard{}.get", 7)`.
+    pub fn call<Req, Rsp>(
+        &self,
+        fq_name: impl Into<Cow<'static, str>>,
+        req: &Req,
+    ) -> RpcFuture<Result<Rsp>>
+    where
+        Req: labcodec::Message,
+        Rsp: labcodec::Message + 'static,
+    {
+        let mut buf = vec![];
+        if let Err(e) = labcodec::encode(req, &mut buf) {
+            return Box::pin(future::err(Error::Encode(e)));
+        }
+
+        Box::pin(self.call_raw(fq_name, buf).map(|res| {
+            let resp = res?;
+            labcodec::decode(&resp).map_err(Error::Decode)
+        }))
+    }
+
+    /// Like `call`, but each attempt times out and a call timing out is
+    /// retried as the policy says. It is the same as `call` with the
+    /// default policy.
+    pub fn call_with_policy<Req, Rsp>(
+        &self,
+        fq_name: impl Into<Cow<'static, str>>,
+        req: &Req,
+        policy: CallPolicy,
+    ) -> RpcFuture<Result<Rsp>>
+    where
+        Req: labcodec::Message,
+        Rsp: labcodec::Message + 'static,
+    {
+        if policy == CallPolicy::default() {
+            return self.call(fq_name, req);
+        }
+        let mut buf = vec![];
+        if let Err(e) = labcodec::encode(req, &mut buf) {
+            return Box::pin(future::err(Error::Encode(e)));
+        }
+
+        Box::pin(
+            self.call_raw_with_policy(fq_name.into(), buf, policy)
+                .map(|res| res.and_then(|resp| labcodec::decode(&resp).map_err(Error::Decode))),
+        )
+    }
+
+    /// Like `call`, but a call failing with a retryable error is retried as
+    /// the policy says, see `RetryPolicy::is_retryable`.
+    pub fn call_with_retry<Req, Rsp>(
+        &self,
+        fq_name: impl Into<Cow<'static, str>>,
+        req: &Req,
+        retry: RetryPolicy,
+    ) -> RpcFuture<Result<Rsp>>
+    where
+        Req: labcodec::Message,
+        Rsp: labcodec::Message + 'static,
+    {
+        self.call_with_policy(fq_name, req, CallPolicy::default().retry_policy(retry))
+    }
+
+    /// Calls a server-streaming method, whose chunks are decoded as the
+    /// returned stream is iterated, see `ChunkSink`.
+    pub fn call_stream<Req, Rsp>(
+        &self,
+        fq_name: impl Into<Cow<'static, str>>,
+        req: &Req,
+        policy: CallPolicy,
+    ) -> RpcFuture<Result<ChunkStream<Rsp>>>
     where
         Req: labcodec::Message,
         Rsp: labcodec::Message + 'static,
@@ -59,27 +281,143 @@ impl Client {
             return Box::pin(future::err(Error::Encode(e)));
         }
 
+        Box::pin(
+            self.call_raw_with_policy(fq_name.into(), buf, policy)
+                .map(|res| res.map(ChunkStream::new)),
+        )
+    }
+
+    fn call_raw_with_policy(
+        &self,
+        fq_name: Cow<'static, str>,
+        req: Vec<u8>,
+        policy: CallPolicy,
+    ) -> RpcFuture<Result<Vec<u8>>> {
+        let client = self.clone();
+        Box::pin(async move {
+            let mut retries = policy.retries;
+            let mut attempts = 1;
+            loop {
+                let call = client.call_raw(fq_name.clone(), req.clone());
+                let res = match policy.timeout {
+                    Some(timeout) => futures::select! {
+                        res = call.fuse() => res,
+                        _ = Delay::new(timeout).fuse() => Err(Error::Timeout),
+                    },
+                    None => call.await,
+                };
+                match res {
+                    Err(Error::Timeout) if retries > 0 => retries -= 1,
+                    Err(ref e)
+                        if attempts < policy.retry.max_attempts && RetryPolicy::is_retryable(e) =>
+                    {
+                        attempts += 1;
+                        Delay::new(policy.retry.backoff).await;
+                    }
+                    res => return res,
+                }
+            }
+        })
+    }
+
+    /// Sends an encoded request and returns the encoded reply, no message
+    /// encoding or decoding is involved.
+    pub fn call_raw(
+        &self,
+        fq_name: impl Into<Cow<'static, str>>,
+        req: Vec<u8>,
+    ) -> RpcFuture<Result<Vec<u8>>> {
         let (tx, rx) = oneshot::channel();
+        // Sends requests and waits responses.
+        if let Err(e) = self.send(fq_name.into(), req, Some(tx)) {
+            let res = Err(e);
+            self.stats.record(&res);
+            return Box::pin(future::ready(res));
+        }
+
+        let stats = self.stats.clone();
+        Box::pin(rx.map(move |res| {
+            let res = match res {
+                Ok(resp) => resp,
+                Err(e) => Err(Error::Recv(e)),
+            };
+            stats.record(&res);
+            res
+        }))
+    }
+
+    /// Sends a request to a oneway method, which replies nothing. Returns
+    /// once the request is sent, it is not known whether the server
+    /// receives it or how it is handled.
+    pub fn notify<Req: labcodec::Message>(
+        &self,
+        fq_name: impl Into<Cow<'static, str>>,
+        req: &Req,
+    ) -> Result<()> {
+        let mut buf = vec![];
+        labcodec::encode(req, &mut buf).map_err(Error::Encode)?;
+        self.send(fq_name.into(), buf, None)
+    }
+
+    fn send(
+        &self,
+        fq_name: Cow<'static, str>,
+        req: Vec<u8>,
+        resp: Option<oneshot::Sender<Result<Vec<u8>>>>,
+    ) -> Result<()> {
+        self.stats.calls_sent.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_sent
+            .fetch_add(req.len() as u64, Ordering::Relaxed);
         let rpc = Rpc {
             client_name: self.name.clone(),
+            rpc_id: RPC_ID_ALLOC.fetch_add(1, Ordering::Relaxed),
             fq_name,
-            req: Some(buf),
-            resp: Some(tx),
+            req: Some(req),
+            resp,
             hooks: self.hooks.clone(),
         };
+        self.sender.unbounded_send(rpc).map_err(|_| Error::Stopped)
+    }
 
-        // Sends requests and waits responses.
-        if self.sender.unbounded_send(rpc).is_err() {
-            return Box::pin(future::err(Error::Stopped));
+    /// Checks whether the connected server is reachable, by calling its
+    /// `__labrpc.health` through the network like any other RPC. Fails with
+    /// `Error::Timeout` if there is no reply within `timeout`.
+    ///
+    /// A server replying is alive, though the service on it may still not
+    /// be able to serve, e.g. a raft peer that is not the leader.
+    pub fn health_check(&self, timeout: Duration) -> RpcFuture<Result<HealthReply>> {
+        let call = self.call("__labrpc.health", &HealthArgs {});
+        Box::pin(async move {
+            futures::select! {
+                res = call.fuse() => res,
+                _ = Delay::new(timeout).fuse() => Err(Error::Timeout),
+            }
+        })
+    }
+
+    /// The calls made through the client and its clones so far.
+    pub fn connection_stats(&self) -> ConnectionStats {
+        let stats = &self.stats;
+        ConnectionStats {
+            calls_sent: stats.calls_sent.load(Ordering::Relaxed),
+            calls_succeeded: stats.calls_succeeded.load(Ordering::Relaxed),
+            calls_timed_out: stats.calls_timed_out.load(Ordering::Relaxed),
+            calls_failed: stats.calls_failed.load(Ordering::Relaxed),
+            bytes_sent: stats.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: stats.bytes_received.load(Ordering::Relaxed),
         }
+    }
 
-        Box::pin(rx.then(|res| async move {
-            match res {
-                Ok(Ok(resp)) => labcodec::decode(&resp).map_err(Error::Decode),
-                Ok(Err(e)) => Err(e),
-                Err(e) => Err(Error::Recv(e)),
-            }
-        }))
+    /// Resets the counters of `connection_stats` to zero.
+    pub fn reset_stats(&self) {
+        let stats = &self.stats;
+        stats.calls_sent.store(0, Ordering::Relaxed);
+        stats.calls_succeeded.store(0, Ordering::Relaxed);
+        stats.calls_timed_out.store(0, Ordering::Relaxed);
+        stats.calls_failed.store(0, Ordering::Relaxed);
+        stats.bytes_sent.store(0, Ordering::Relaxed);
+        stats.bytes_received.store(0, Ordering::Relaxed);
     }
 
     pub fn set_hooks(&self, hooks: Arc<dyn RpcHooks>) {
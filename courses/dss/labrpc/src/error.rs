@@ -12,6 +12,21 @@ pub enum Error {
     Recv(Canceled),
     Timeout,
     Stopped,
+    Overloaded,
+    /// The server has been shut down.
+    Shutdown,
+    /// The server has been deleted from the network or replaced while the
+    /// RPC is in flight.
+    ServerKilled,
+    MessageTooLarge,
+    HandlerPanic(String),
+    /// An application level failure returned by the handler, e.g. a wrong
+    /// leader. It is passed to the client as it is, and the reply is not
+    /// decoded. The meaning of `code` is up to the service.
+    App {
+        code: u32,
+        msg: String,
+    },
     Other(String),
 }
 
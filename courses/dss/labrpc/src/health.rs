@@ -0,0 +1,58 @@
+//! The health check service registered on every server, it tells whether a
+//! server is reachable through the network.
+
+use std::sync::atomic::Ordering;
+use std::sync::Weak;
+use std::time::Instant;
+
+use prost::Message;
+
+use crate::error::{Error, Result};
+use crate::server::ServerCore;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HealthArgs {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HealthReply {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(uint64, tag = "2")]
+    pub id: u64,
+    /// How long the server has been built, in milliseconds.
+    #[prost(uint64, tag = "3")]
+    pub uptime_ms: u64,
+    /// The number of dispatches of the server, including this one.
+    #[prost(uint64, tag = "4")]
+    pub count: u64,
+}
+
+service! {
+    /// The health check service.
+    #[allow(dead_code)]
+    service __labrpc {
+        /// Replies as long as the server is reachable.
+        rpc health(HealthArgs) returns (HealthReply);
+    }
+}
+pub use __labrpc::{add_service, Service};
+
+#[derive(Clone)]
+pub(crate) struct Health {
+    // the server serving it, weak to avoid a reference cycle.
+    pub(crate) server: Weak<ServerCore>,
+    pub(crate) started: Instant,
+}
+
+#[async_trait::async_trait]
+impl Service for Health {
+    async fn health(&self, _: HealthArgs) -> Result<HealthReply> {
+        let core = self.server.upgrade().ok_or(Error::Stopped)?;
+        Ok(HealthReply {
+            name: core.name.clone(),
+            id: core.id as u64,
+            uptime_ms: self.started.elapsed().as_millis() as u64,
+            count: core.count.load(Ordering::Relaxed) as u64,
+        })
+    }
+}
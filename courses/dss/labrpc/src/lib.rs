@@ -4,23 +4,76 @@ mod client;
 mod error;
 #[macro_use]
 mod macros;
+mod health;
 mod network;
+mod reflection;
 mod server;
+mod stream;
 
-pub use self::client::{Client, Rpc, RpcHooks};
+pub use self::client::{CallPolicy, Client, ConnectionStats, RetryPolicy, Rpc, RpcHooks};
 pub use self::error::{Error, Result};
-pub use self::network::Network;
-pub use self::server::{Handler, HandlerFactory, RpcFuture, Server, ServerBuilder};
+pub use self::health::{HealthArgs, HealthReply};
+pub use self::network::{
+    CountMark, Network, NetworkConfig, NetworkSettings, RpcInterceptor, RpcObserver, RpcRate,
+    RttModel, SlowHandlerHook, StabilityProbe, TestScope,
+};
+pub use self::reflection::{ListMethodsArgs, ListMethodsReply, ReflectionClient};
+pub use self::server::{
+    DefaultHandler, Handler, HandlerFactory, MethodHandler, MethodLatency, Middleware, RpcContext,
+    RpcFuture, Server, ServerBuilder, UNKNOWN_METHOD,
+};
+pub use self::stream::{ChunkSink, ChunkStream};
+
+// The crates the expansion of `service!` uses, so that the crates invoking
+// it need not depend on them under these names, and the checks it makes at
+// compile time.
+#[doc(hidden)]
+pub mod __private {
+    pub use async_trait::async_trait;
+    pub use futures;
+    pub use paste::paste;
+
+    /// How many times `name` is in `names`.
+    pub const fn count_name(names: &[&str], name: &str) -> usize {
+        let mut count = 0;
+        let mut i = 0;
+        while i < names.len() {
+            if str_eq(names[i], name) {
+                count += 1;
+            }
+            i += 1;
+        }
+        count
+    }
+
+    pub const fn str_eq(a: &str, b: &str) -> bool {
+        let (a, b) = (a.as_bytes(), b.as_bytes());
+        if a.len() != b.len() {
+            return false;
+        }
+        let mut i = 0;
+        while i < a.len() {
+            if a[i] != b[i] {
+                return false;
+            }
+            i += 1;
+        }
+        true
+    }
+}
 
 #[cfg(test)]
 pub mod tests {
-    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::sync::{mpsc, Arc, Mutex, Once};
     use std::thread;
     use std::time::{Duration, Instant};
 
     use futures::channel::oneshot::Canceled;
     use futures::executor::{block_on, ThreadPool};
+    use futures::future::{self, FutureExt};
+    use futures::select;
     use futures::stream::StreamExt;
     use futures_timer::Delay;
     use prost_derive::Message;
@@ -34,12 +87,20 @@ pub mod tests {
             rpc handler2(JunkArgs) returns (JunkReply);
             rpc handler3(JunkArgs) returns (JunkReply);
             rpc handler4(JunkArgs) returns (JunkReply);
+            /// Waits `x` milliseconds before replying.
+            rpc handler5(JunkArgs) returns (JunkReply);
+            /// Always panics.
+            rpc handler6(JunkArgs) returns (JunkReply);
+            /// Blocks the thread `x` milliseconds before replying.
+            rpc handler7(JunkArgs) returns (JunkReply);
+            /// Replies the name of the caller.
+            rpc handler8(JunkArgs) returns (JunkReply) with ctx;
         }
     }
     use junk::{add_service, Client as JunkClient, Service as Junk};
 
     // Hand-written protobuf messages.
-    #[derive(Clone, PartialEq, Message)]
+    #[derive(Clone, PartialEq, Message, serde::Serialize, serde::Deserialize)]
     pub struct JunkArgs {
         #[prost(int64, tag = "1")]
         pub x: i64,
@@ -54,15 +115,15 @@ pub mod tests {
     struct JunkInner {
         log2: Vec<i64>,
     }
-    #[derive(Clone)]
     struct JunkService {
-        inner: Arc<Mutex<JunkInner>>,
+        inner: Mutex<JunkInner>,
     }
     impl JunkService {
-        fn new() -> JunkService {
-            JunkService {
-                inner: Arc::default(),
-            }
+        // shared with the tests inspecting its state.
+        fn new() -> Arc<JunkService> {
+            Arc::new(JunkService {
+                inner: Mutex::default(),
+            })
         }
     }
     #[async_trait::async_trait]
@@ -84,201 +145,1320 @@ pub mod tests {
                 x: "pointer".to_owned(),
             })
         }
+        async fn handler5(&self, args: JunkArgs) -> Result<JunkReply> {
+            Delay::new(Duration::from_millis(args.x as u64)).await;
+            Ok(JunkReply {
+                x: format!("handler5-{}", args.x),
+            })
+        }
+        async fn handler6(&self, args: JunkArgs) -> Result<JunkReply> {
+            panic!("handler6-{}", args.x);
+        }
+        async fn handler7(&self, args: JunkArgs) -> Result<JunkReply> {
+            thread::sleep(Duration::from_millis(args.x as u64));
+            Ok(JunkReply {
+                x: format!("handler7-{}", args.x),
+            })
+        }
+        async fn handler8(&self, ctx: RpcContext, _: JunkArgs) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: ctx.end_name().to_owned(),
+            })
+        }
     }
 
-    fn init_logger() {
-        static LOGGER_INIT: Once = Once::new();
-        LOGGER_INIT.call_once(env_logger::init);
+    service! {
+        /// A service added to a running server.
+        service echoer {
+            rpc echo(JunkArgs) returns (JunkReply);
+        }
     }
 
-    #[test]
-    fn test_service_dispatch() {
-        init_logger();
-
-        let mut builder = ServerBuilder::new("test".to_owned());
-        let junk = JunkService::new();
-        add_service(junk.clone(), &mut builder).unwrap();
-        let prev_len = builder.services.len();
-        add_service(junk, &mut builder).unwrap_err();
-        assert_eq!(builder.services.len(), prev_len);
-        let server = builder.build();
+    #[derive(Clone)]
+    struct EchoService;
 
-        let buf = block_on(async { server.dispatch("junk.handler4", &[]).await.unwrap() });
-        let rsp = labcodec::decode(&buf).unwrap();
-        assert_eq!(
-            JunkReply {
-                x: "pointer".to_owned(),
-            },
-            rsp,
-        );
+    #[async_trait::async_trait]
+    impl echoer::Service for EchoService {
+        async fn echo(&self, args: JunkArgs) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: format!("echo-{}", args.x),
+            })
+        }
+    }
 
-        block_on(async {
-            server
-                .dispatch("junk.handler4", b"bad message")
-                .await
-                .unwrap_err();
+    service! {
+        /// A service failing with application errors.
+        service kv {
+            /// Gets the value of key `x`, which exists if it is positive.
+            rpc get(JunkArgs) returns (JunkReply);
+        }
+    }
 
-            server.dispatch("badjunk.handler4", &[]).await.unwrap_err();
+    #[derive(Clone)]
+    struct KvService;
 
-            server.dispatch("junk.badhandler", &[]).await.unwrap_err();
-        });
+    service! {
+        /// A service without arguments or replies.
+        service pinger {
+            rpc ping() returns (JunkReply);
+            /// Counts the calls.
+            rpc fire(JunkArgs) returns ();
+            rpc nop() returns ();
+        }
     }
 
-    #[test]
-    fn test_network_client_rpc() {
-        init_logger();
+    /// All the items generated for a documented service are documented.
+    #[deny(missing_docs)]
+    pub mod documented {
+        use super::*;
 
-        let mut builder = ServerBuilder::new("test".to_owned());
-        let junk = JunkService::new();
-        add_service(junk, &mut builder).unwrap();
-        let server = builder.build();
+        service! {
+            /// A documented service.
+            service documented {
+                /// Echoes the argument.
+                rpc echo(JunkArgs) returns (JunkReply);
+            }
+        }
+    }
 
-        let (net, incoming) = Network::create();
-        net.add_server(server);
+    /// A service deep in a module tree, which imports nothing.
+    pub mod deep {
+        pub mod deeper {
+            pub mod deepest {
+                crate::service! {
+                    /// A service of messages named by their paths.
+                    service nested {
+                        rpc echo(crate::tests::JunkArgs) returns (crate::tests::JunkReply);
+                        rpc chunks(crate::tests::JunkArgs) returns stream crate::tests::JunkReply;
+                    }
+                }
 
-        let client = JunkClient::new(net.create_client("test_client".to_owned()));
-        let (tx, rx) = mpsc::channel();
-        let cli = client.clone();
-        client.spawn(async move {
-            let reply = cli.handler4(&JunkArgs { x: 777 }).await;
-            tx.send(reply).unwrap();
-        });
-        let (mut rpc, incoming) = block_on(async {
-            match incoming.into_future().await {
-                (Some(rpc), s) => (rpc, s),
-                _ => panic!("unexpected error"),
+                #[derive(Clone)]
+                pub struct NestedService;
+
+                #[async_trait::async_trait]
+                impl nested::Service for NestedService {
+                    async fn echo(
+                        &self,
+                        args: crate::tests::JunkArgs,
+                    ) -> crate::Result<crate::tests::JunkReply> {
+                        Ok(crate::tests::JunkReply {
+                            x: format!("nested-{}", args.x),
+                        })
+                    }
+
+                    async fn chunks(
+                        &self,
+                        args: crate::tests::JunkArgs,
+                        sink: crate::ChunkSink<crate::tests::JunkReply>,
+                    ) -> crate::Result<()> {
+                        for i in 0..args.x {
+                            sink.send(&crate::tests::JunkReply { x: i.to_string() })?;
+                        }
+                        Ok(())
+                    }
+                }
             }
-        });
-        let reply = JunkReply {
-            x: "boom!!!".to_owned(),
-        };
-        let mut buf = vec![];
-        labcodec::encode(&reply, &mut buf).unwrap();
-        let resp = rpc.take_resp_sender().unwrap();
-        resp.send(Ok(buf)).unwrap();
-        assert_eq!(rpc.client_name, "test_client");
-        assert_eq!(rpc.fq_name, "junk.handler4");
-        assert!(!rpc.req.as_ref().unwrap().is_empty());
-        assert_eq!(rx.recv().unwrap(), Ok(reply));
+        }
+    }
 
-        let (tx, rx) = mpsc::channel();
-        let cli = client.clone();
-        client.spawn(async move {
-            let reply = cli.handler4(&JunkArgs { x: 777 }).await;
-            tx.send(reply).unwrap();
-        });
-        let (rpc, incoming) = block_on(async {
-            match incoming.into_future().await {
-                (Some(rpc), s) => (rpc, s),
-                _ => panic!("unexpected error"),
-            }
-        });
-        drop(rpc.resp);
-        assert_eq!(rx.recv().unwrap(), Err(Error::Recv(Canceled)));
+    service! {
+        /// Services defined together.
+        service upper_case {
+            rpc upper(JunkArgs) returns (JunkReply);
+        }
+        service lower_case {
+            rpc lower(JunkArgs) returns (JunkReply);
+        }
+    }
 
-        drop(incoming);
-        assert_eq!(
-            block_on(async { client.handler4(&JunkArgs::default()).await }),
-            Err(Error::Stopped)
-        );
+    #[derive(Clone)]
+    struct CaseService;
+
+    #[async_trait::async_trait]
+    impl upper_case::Service for CaseService {
+        async fn upper(&self, args: JunkArgs) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: format!("UPPER-{}", args.x),
+            })
+        }
     }
 
-    fn junk_suit() -> (Network, Server, JunkService) {
-        let net = Network::new();
-        let server_name = "test_server".to_owned();
-        let mut builder = ServerBuilder::new(server_name);
-        let junk_server = JunkService::new();
-        add_service(junk_server.clone(), &mut builder).unwrap();
-        let server = builder.build();
-        net.add_server(server.clone());
-        (net, server, junk_server)
+    #[async_trait::async_trait]
+    impl lower_case::Service for CaseService {
+        async fn lower(&self, args: JunkArgs) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: format!("lower-{}", args.x),
+            })
+        }
     }
 
-    #[test]
-    fn test_basic() {
-        init_logger();
+    service! {
+        /// A service whose methods need not all be implemented.
+        service partial draft {
+            rpc done(JunkArgs) returns (JunkReply);
+            rpc todo(JunkArgs) returns (JunkReply);
+        }
+    }
 
-        let (net, _, _) = junk_suit();
+    #[derive(Clone)]
+    struct DraftService;
 
-        let client = JunkClient::new(net.create_client("test_client".to_owned()));
-        net.connect("test_client", "test_server");
-        net.enable("test_client", true);
+    #[async_trait::async_trait]
+    impl draft::Service for DraftService {
+        async fn done(&self, args: JunkArgs) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: format!("done-{}", args.x),
+            })
+        }
+    }
 
-        let rsp = block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
-        assert_eq!(
-            JunkReply {
-                x: "pointer".to_owned(),
-            },
-            rsp,
-        );
+    /// Messages used by their paths rather than imported.
+    pub mod msgs {
+        pub use super::{JunkArgs, JunkReply};
     }
 
-    // does net.Enable(endname, false) really disconnect a client?
-    #[test]
-    fn test_disconnect() {
-        init_logger();
+    /// A module importing none of the messages.
+    pub mod paths {
+        service! {
+            /// A service of path-qualified and generic messages.
+            service paths {
+                rpc qualified(crate::tests::msgs::JunkArgs) returns (crate::tests::msgs::JunkReply);
+                rpc boxed(Box<crate::tests::msgs::JunkArgs>) returns (Box<crate::tests::msgs::JunkReply>);
+            }
+        }
+    }
 
-        let (net, _, _) = junk_suit();
+    #[derive(Clone)]
+    struct PathService;
 
-        let client = JunkClient::new(net.create_client("test_client".to_owned()));
-        net.connect("test_client", "test_server");
+    #[async_trait::async_trait]
+    impl paths::paths::Service for PathService {
+        async fn qualified(&self, args: msgs::JunkArgs) -> Result<msgs::JunkReply> {
+            Ok(msgs::JunkReply {
+                x: format!("qualified-{}", args.x),
+            })
+        }
 
-        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap_err() });
+        async fn boxed(&self, args: Box<msgs::JunkArgs>) -> Result<Box<msgs::JunkReply>> {
+            Ok(Box::new(msgs::JunkReply {
+                x: format!("boxed-{}", args.x),
+            }))
+        }
+    }
 
-        net.enable("test_client", true);
-        let rsp = block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+    service! {
+        /// A service whose calls time out and are retried.
+        service policy {
+            /// Waits `x` milliseconds before replying.
+            #[timeout_ms = 100]
+            rpc slow(JunkArgs) returns (JunkReply);
+            /// Only replies in time to the third call.
+            #[timeout_ms = 100]
+            #[retries = 2]
+            rpc flaky(JunkArgs) returns (JunkReply);
+        }
+    }
 
-        assert_eq!(
-            JunkReply {
-                x: "pointer".to_owned(),
-            },
-            rsp,
-        );
+    #[derive(Clone, Default)]
+    struct PolicyService {
+        flaky_calls: Arc<AtomicUsize>,
     }
 
-    // test net.GetCount()
-    #[test]
-    fn test_count() {
-        init_logger();
+    #[async_trait::async_trait]
+    impl policy::Service for PolicyService {
+        async fn slow(&self, args: JunkArgs) -> Result<JunkReply> {
+            Delay::new(Duration::from_millis(args.x as u64)).await;
+            Ok(JunkReply {
+                x: format!("slow-{}", args.x),
+            })
+        }
 
-        let (net, _, _) = junk_suit();
+        async fn flaky(&self, _: JunkArgs) -> Result<JunkReply> {
+            let n = self.flaky_calls.fetch_add(1, Ordering::SeqCst) + 1;
+            if n < 3 {
+                Delay::new(Duration::from_millis(500)).await;
+            }
+            Ok(JunkReply {
+                x: format!("flaky-{}", n),
+            })
+        }
+    }
 
-        let client = JunkClient::new(net.create_client("test_client".to_owned()));
-        net.connect("test_client", "test_server");
-        net.enable("test_client", true);
+    service! {
+        /// A service streaming its replies.
+        #[derive(Clone)]
+        service snapshot {
+            rpc size(JunkArgs) returns (JunkReply);
+            /// Streams `x` chunks, fails if `x` is negative.
+            rpc fetch(JunkArgs) returns stream JunkReply;
+            /// Streams the name of the caller.
+            rpc whoami() returns stream (JunkReply) with ctx;
+        }
+    }
 
-        for i in 0..=16 {
-            let reply = block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
-            assert_eq!(reply.x, format!("handler2-{}", i));
+    // A message which is not `Clone`.
+    #[derive(PartialEq, Message)]
+    pub struct Unique {
+        #[prost(int64, tag = "1")]
+        pub x: i64,
+    }
+
+    service! {
+        /// A service of messages which are not `Clone`.
+        service unique {
+            rpc take(Unique) returns (Unique);
         }
+    }
 
-        assert_eq!(net.count("test_server"), 17);
+    #[derive(Clone, Default)]
+    struct SnapshotService {
+        sent: Arc<AtomicUsize>,
     }
 
-    // test RPCs from concurrent Clients
-    #[test]
-    fn test_concurrent_many() {
-        init_logger();
+    #[async_trait::async_trait]
+    impl snapshot::Service for SnapshotService {
+        async fn size(&self, args: JunkArgs) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: format!("size-{}", args.x),
+            })
+        }
 
-        let (net, server, _) = junk_suit();
-        let server_name = server.name();
+        async fn fetch(&self, args: JunkArgs, sink: ChunkSink<JunkReply>) -> Result<()> {
+            if args.x < 0 {
+                sink.send(&JunkReply::default())?;
+                return Err(Error::Other(format!("no snapshot {}", args.x)));
+            }
+            for i in 0..args.x {
+                sink.send(&JunkReply {
+                    x: format!("chunk-{}", i),
+                })?;
+                self.sent.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        }
 
-        let pool = ThreadPool::new().unwrap();
-        let (tx, rx) = mpsc::channel::<usize>();
+        async fn whoami(&self, ctx: RpcContext, sink: ChunkSink<JunkReply>) -> Result<()> {
+            sink.send(&JunkReply {
+                x: ctx.end_name().to_owned(),
+            })
+        }
+    }
 
-        let nclients = 20usize;
-        let nrpcs = 10usize;
-        for i in 0..nclients {
-            let net = net.clone();
-            let sender = tx.clone();
-            let server_name = server_name.to_string();
+    service! {
+        /// A service notified of the commits.
+        service commits {
+            /// Records `x` once the gate, if any, opens.
+            rpc oneway notify(JunkArgs);
+            /// The number of commits recorded.
+            rpc count() returns (JunkReply);
+        }
+    }
 
-            pool.spawn_ok(async move {
-                let mut n = 0;
-                let client_name = format!("client-{}", i);
-                let client = JunkClient::new(net.create_client(client_name.clone()));
-                net.enable(&client_name, true);
-                net.connect(&client_name, &server_name);
+    #[derive(Clone, Default)]
+    struct CommitService {
+        received: Arc<Mutex<Vec<i64>>>,
+        gate: Arc<Mutex<Option<futures::channel::oneshot::Receiver<()>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl commits::Service for CommitService {
+        async fn notify(&self, args: JunkArgs) {
+            let gate = self.gate.lock().unwrap().take();
+            if let Some(gate) = gate {
+                gate.await.unwrap();
+            }
+            self.received.lock().unwrap().push(args.x);
+        }
+
+        async fn count(&self) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: self.received.lock().unwrap().len().to_string(),
+            })
+        }
+    }
+
+    service! {
+        /// A service keeping its state in itself.
+        service tally {
+            rpc add(JunkArgs) returns ();
+            rpc total() returns (JunkReply);
+        }
+    }
+
+    // Neither `Clone` nor holding its state behind an `Arc`.
+    #[derive(Default)]
+    struct TallyService {
+        total: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl tally::Service for TallyService {
+        async fn add(&self, args: JunkArgs) -> Result<()> {
+            self.total.fetch_add(args.x as usize, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn total(&self) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: self.total.load(Ordering::SeqCst).to_string(),
+            })
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct PingService {
+        fired: Arc<AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl pinger::Service for PingService {
+        async fn ping(&self) -> Result<JunkReply> {
+            Ok(JunkReply {
+                x: "pong".to_owned(),
+            })
+        }
+
+        async fn fire(&self, args: JunkArgs) -> Result<()> {
+            self.fired.fetch_add(args.x as usize, Ordering::SeqCst);
+            Ok(())
+        }
+
+        async fn nop(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    const NOT_FOUND: u32 = 404;
+
+    #[async_trait::async_trait]
+    impl kv::Service for KvService {
+        async fn get(&self, args: JunkArgs) -> Result<JunkReply> {
+            if args.x <= 0 {
+                return Err(Error::App {
+                    code: NOT_FOUND,
+                    msg: format!("no key {}", args.x),
+                });
+            }
+            Ok(JunkReply {
+                x: format!("value-{}", args.x),
+            })
+        }
+    }
+
+    // Another service named junk, which collides with the one above.
+    #[allow(dead_code)]
+    mod other {
+        use super::*;
+
+        service! {
+            service junk {
+                rpc handler2(JunkArgs) returns (JunkReply);
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct OtherJunkService;
+
+        #[async_trait::async_trait]
+        impl junk::Service for OtherJunkService {
+            async fn handler2(&self, args: JunkArgs) -> Result<JunkReply> {
+                Ok(JunkReply {
+                    x: format!("other-handler2-{}", args.x),
+                })
+            }
+        }
+    }
+
+    fn init_logger() {
+        static LOGGER_INIT: Once = Once::new();
+        LOGGER_INIT.call_once(env_logger::init);
+    }
+
+    #[test]
+    fn test_service_dispatch() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        let junk = JunkService::new();
+        add_service(junk.clone(), &mut builder).unwrap();
+        let prev_names = builder.method_names();
+        add_service(junk, &mut builder).unwrap_err();
+        assert_eq!(builder.method_names(), prev_names);
+        let server = builder.build();
+        let ctx = RpcContext::new("test_client".to_owned(), 0);
+
+        let buf = block_on(async { server.dispatch(&ctx, "junk.handler4", &[]).await.unwrap() });
+        let rsp = labcodec::decode(&buf).unwrap();
+        assert_eq!(
+            JunkReply {
+                x: "pointer".to_owned(),
+            },
+            rsp,
+        );
+
+        block_on(async {
+            server
+                .dispatch(&ctx, "junk.handler4", b"bad message")
+                .await
+                .unwrap_err();
+
+            server
+                .dispatch(&ctx, "badjunk.handler4", &[])
+                .await
+                .unwrap_err();
+
+            server
+                .dispatch(&ctx, "junk.badhandler", &[])
+                .await
+                .unwrap_err();
+        });
+    }
+
+    #[test]
+    fn test_reflection() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let reply: ListMethodsReply = block_on(async {
+            client
+                .call("__reflection.list_methods", &ListMethodsArgs {})
+                .await
+                .unwrap()
+        });
+        assert!(reply.methods.contains(&"junk.handler2".to_owned()));
+        assert!(reply.methods.contains(&"junk.handler8".to_owned()));
+        let reply = block_on(async {
+            ReflectionClient::new(client)
+                .list_methods(&ListMethodsArgs {})
+                .await
+                .unwrap()
+        });
+        assert_eq!(
+            reply.methods[..2],
+            ["__labrpc.health", "__reflection.list_methods"]
+        );
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        builder.disable_reflection();
+        let server = builder.build();
+        assert!(!server.has_method("__reflection.list_methods"));
+    }
+
+    #[test]
+    fn test_health_check() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let timeout = Duration::from_millis(500);
+        let reply = block_on(client.health_check(timeout)).unwrap();
+        assert_eq!(reply.name, "test_server");
+        assert_eq!(reply.id, server.core.id as u64);
+        assert_eq!(reply.count, 1);
+        let reply = block_on(client.health_check(timeout)).unwrap();
+        assert_eq!(reply.count, 2);
+
+        // unreachable.
+        net.enable("test_client", false);
+        let res = block_on(client.health_check(timeout));
+        assert_eq!(res, Err(Error::Timeout));
+        net.enable("test_client", true);
+        net.delete_server("test_server");
+        let res = block_on(client.health_check(timeout));
+        assert_eq!(res, Err(Error::Timeout));
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        builder.disable_health_check();
+        let server = builder.build();
+        assert!(!server.has_method("__labrpc.health"));
+    }
+
+    #[test]
+    fn test_rpc_context() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        for name in &["client1", "client2"] {
+            let client = JunkClient::new(net.create_client(name.to_string()));
+            net.connect(name, "test_server");
+            net.enable(name, true);
+            let reply = block_on(async { client.handler8(&JunkArgs::default()).await.unwrap() });
+            assert_eq!(&reply.x, name);
+        }
+    }
+
+    #[test]
+    fn test_method_names() {
+        init_logger();
+
+        let (_, server, _) = junk_suit();
+        let names = vec![
+            "__labrpc.health",
+            "__reflection.list_methods",
+            "junk.handler2",
+            "junk.handler3",
+            "junk.handler4",
+            "junk.handler5",
+            "junk.handler6",
+            "junk.handler7",
+            "junk.handler8",
+        ];
+        assert_eq!(server.method_names(), names);
+        assert_eq!(junk::all_methods(), &names[2..]);
+        assert_eq!(junk::method_names::HANDLER2, "junk.handler2");
+        assert!(server.has_method(junk::method_names::HANDLER2));
+        assert!(!server.has_method("junk.Handler2"));
+        assert!(!server.has_method("junk"));
+        assert!(format!("{:?}", server).contains("junk.handler7"));
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        assert!(builder.method_names().is_empty());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        assert_eq!(builder.method_names(), names[2..]);
+        echoer::add_service(EchoService, &mut builder).unwrap();
+        assert!(builder.method_names().contains(&"echoer.echo".to_owned()));
+
+        let ctx = RpcContext::new("test_client".to_owned(), 0);
+        let res = block_on(async { server.dispatch(&ctx, "junk.Handler2", &[]).await });
+        match res {
+            Err(Error::Unimplemented(msg)) => {
+                assert!(msg.contains("junk.Handler2"), "{}", msg);
+                assert!(msg.contains(&format!("{:?}", names)), "{}", msg);
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_replace_service() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        // a different service sharing the name and method names.
+        other::junk::add_service(other::OtherJunkService, &mut builder).unwrap_err();
+        assert!(other::junk::replace_service(
+            other::OtherJunkService,
+            &mut builder
+        ));
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test");
+        net.enable("test_client", true);
+
+        let rsp = block_on(async {
+            other::junk::Client::new(client.clone())
+                .handler2(&JunkArgs { x: 1 })
+                .await
+                .unwrap()
+        });
+        assert_eq!(
+            JunkReply {
+                x: "other-handler2-1".to_owned(),
+            },
+            rsp,
+        );
+        let res = block_on(async { JunkClient::new(client).handler4(&JunkArgs::default()).await });
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        assert!(!other::junk::replace_service(
+            other::OtherJunkService,
+            &mut builder
+        ));
+        assert_eq!(builder.remove_service("junk."), 1);
+        assert_eq!(builder.remove_service("junk."), 0);
+        add_service(JunkService::new(), &mut builder).unwrap();
+
+        // removes the services sharing a prefix.
+        for name in &["shard1", "shard2"] {
+            builder
+                .add_service(*name, echoer::new_factory(EchoService))
+                .unwrap();
+        }
+        assert_eq!(builder.remove_service("shard"), 2);
+        assert_eq!(builder.method_names().len(), 7);
+    }
+
+    #[test]
+    fn test_replace_handler() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        // a different service sharing a method name.
+        other::junk::add_service(other::OtherJunkService, &mut builder).unwrap_err();
+        builder
+            .replace_handler(
+                "junk.handler2",
+                Arc::new(|_, req| {
+                    let args: JunkArgs = labcodec::decode(req).unwrap();
+                    let reply = JunkReply {
+                        x: format!("replaced-{}", args.x),
+                    };
+                    let mut buf = vec![];
+                    labcodec::encode(&reply, &mut buf).unwrap();
+                    Box::pin(future::ok(buf))
+                }),
+            )
+            .unwrap();
+        let res = builder.replace_handler("junk.nohandler", Arc::new(|_, _| unreachable!()));
+        assert!(matches!(res, Err(Error::Unimplemented(_))));
+        assert!(builder.remove_handler("junk.handler3"));
+        assert!(!builder.remove_handler("junk.handler3"));
+        assert!(!builder.method_names().contains(&"junk.handler3".to_owned()));
+
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test");
+        net.enable("test_client", true);
+        let reply = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "replaced-1");
+        let res = block_on(client.handler3(&JunkArgs::default()));
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+        let reply = block_on(client.handler4(&JunkArgs::default())).unwrap();
+        assert_eq!(reply.x, "pointer");
+    }
+
+    #[test]
+    fn test_add_service_live() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // keep the server busy while adding the service.
+        let stop = Arc::new(AtomicBool::new(false));
+        let junk_client = JunkClient::new(client.clone());
+        let stop1 = stop.clone();
+        let traffic = thread::spawn(move || {
+            while !stop1.load(Ordering::Relaxed) {
+                block_on(async { junk_client.handler4(&JunkArgs::default()).await.unwrap() });
+            }
+        });
+        let echo_client = echoer::Client::new(client);
+        let res = block_on(async { echo_client.echo(&JunkArgs { x: 1 }).await });
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+
+        echoer::add_service_live(EchoService, &server).unwrap();
+        echoer::add_service_live(EchoService, &server).unwrap_err();
+        let reply = block_on(async { echo_client.echo(&JunkArgs { x: 2 }).await.unwrap() });
+        assert_eq!(reply.x, "echo-2");
+        assert!(server.has_method("echoer.echo"));
+        assert_eq!(server.method_count("echoer.echo"), 1);
+
+        stop.store(true, Ordering::Relaxed);
+        traffic.join().unwrap();
+    }
+
+    #[test]
+    fn test_connection_stats() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let raw = net.create_client("test_client".to_owned());
+        let client = JunkClient::new(raw.clone());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for x in 1..4 {
+            block_on(async { client.handler2(&JunkArgs { x }).await.unwrap() });
+        }
+        block_on(async { client.handler6(&JunkArgs { x: 1 }).await.unwrap_err() });
+        net.enable("test_client", false);
+        let res = block_on(async { client.handler2(&JunkArgs { x: 1 }).await });
+        assert_eq!(res, Err(Error::Timeout));
+
+        // the clones share the stats.
+        let stats = raw.connection_stats();
+        assert_eq!(
+            stats,
+            ConnectionStats {
+                calls_sent: 5,
+                calls_succeeded: 3,
+                calls_timed_out: 1,
+                calls_failed: 1,
+                // a JunkArgs takes 2 bytes.
+                bytes_sent: 5 * 2,
+                // a JunkReply like "handler2-1" takes 12 bytes.
+                bytes_received: 3 * 12,
+            }
+        );
+        let other = net.create_client("test_client2".to_owned());
+        assert_eq!(other.connection_stats(), ConnectionStats::default());
+
+        raw.clone().reset_stats();
+        assert_eq!(raw.connection_stats(), ConnectionStats::default());
+    }
+
+    #[test]
+    fn test_replace_service_live() {
+        init_logger();
+
+        #[derive(Clone)]
+        struct NewEchoService;
+
+        #[async_trait::async_trait]
+        impl echoer::Service for NewEchoService {
+            async fn echo(&self, args: JunkArgs) -> Result<JunkReply> {
+                Ok(JunkReply {
+                    x: format!("new-echo-{}", args.x),
+                })
+            }
+        }
+
+        let (net, server, _) = junk_suit();
+        let client = echoer::Client::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        echoer::add_service_live(EchoService, &server).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop1 = stop.clone();
+        let traffic = thread::spawn(move || {
+            let mut replies = vec![];
+            while !stop1.load(Ordering::Relaxed) {
+                let reply = block_on(async { client.echo(&JunkArgs { x: 1 }).await.unwrap() });
+                replies.push(reply.x);
+            }
+            replies
+        });
+        thread::sleep(Duration::from_millis(50));
+        let id = server.core.id;
+        assert!(echoer::replace_service_live(NewEchoService, &server));
+        thread::sleep(Duration::from_millis(50));
+        stop.store(true, Ordering::Relaxed);
+        let replies = traffic.join().unwrap();
+
+        // the server is the same one.
+        assert_eq!(server.core.id, id);
+        let swapped = replies.iter().position(|x| x == "new-echo-1").unwrap();
+        assert!(swapped > 0);
+        assert!(replies[..swapped].iter().all(|x| x == "echo-1"));
+        assert!(replies[swapped..].iter().all(|x| x == "new-echo-1"));
+    }
+
+    #[test]
+    fn test_runtime_service_name() {
+        init_logger();
+
+        let net = Network::new();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        for shard in 0..3 {
+            let factory = echoer::new_factory(EchoService);
+            builder
+                .add_service(format!("shard{}", shard), factory)
+                .unwrap();
+        }
+        builder
+            .add_service("shard1".to_owned(), echoer::new_factory(EchoService))
+            .unwrap_err();
+        let server = builder.build();
+        net.add_server(server.clone());
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let shard = 2;
+        let reply: JunkReply = block_on(async {
+            client
+                .call(format!("shard{}.echo", shard), &JunkArgs { x: 7 })
+                .await
+                .unwrap()
+        });
+        assert_eq!(reply.x, "echo-7");
+        assert_eq!(server.method_count("shard2.echo"), 1);
+        assert!(server.has_method("shard0.echo"));
+        assert!(!server.has_method("echoer.echo"));
+        assert!(server.method_names().contains(&"shard1.echo".to_owned()));
+
+        let res = block_on(async {
+            client
+                .call::<_, JunkReply>("shard3.echo", &JunkArgs::default())
+                .await
+        });
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+    }
+
+    #[test]
+    fn test_remove_service_live() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let raw = net.create_client("test_client".to_owned());
+        let client = JunkClient::new(raw.clone());
+        let echo_client = echoer::Client::new(raw);
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        echoer::add_service_live(EchoService, &server).unwrap();
+
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(server.remove_service_live("junk."), 7);
+        assert_eq!(server.remove_service_live("junk."), 0);
+        assert!(!server.has_method("junk.handler4"));
+        assert_eq!(
+            server.method_names(),
+            vec![
+                "__labrpc.health",
+                "__reflection.list_methods",
+                "echoer.echo"
+            ]
+        );
+        let res = block_on(async { client.handler4(&JunkArgs::default()).await });
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+        // the other service is not affected.
+        let reply = block_on(async { echo_client.echo(&JunkArgs { x: 1 }).await.unwrap() });
+        assert_eq!(reply.x, "echo-1");
+    }
+
+    #[test]
+    fn test_server_shutdown() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let (tx, rx) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            tx.send(cli.handler5(&JunkArgs { x: 500 }).await).unwrap();
+        });
+        while server.in_flight() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        server.shutdown();
+        assert!(server.is_shutdown());
+        let start = Instant::now();
+        let res = block_on(async { client.handler4(&JunkArgs::default()).await });
+        assert_eq!(res, Err(Error::Shutdown));
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert!(!server.wait_idle(Duration::from_millis(10)));
+
+        // the slow one completes normally, waking all the waiters.
+        let waiters: Vec<_> = (0..3)
+            .map(|_| {
+                let server = server.clone();
+                thread::spawn(move || server.wait_idle(Duration::from_secs(2)))
+            })
+            .collect();
+        assert!(server.wait_idle(Duration::from_secs(2)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        for waiter in waiters {
+            assert!(waiter.join().unwrap());
+        }
+        let reply = rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap();
+        assert_eq!(reply.x, "handler5-500");
+    }
+
+    #[test]
+    fn test_network_client_rpc() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test".to_owned());
+        let junk = JunkService::new();
+        add_service(junk, &mut builder).unwrap();
+        let server = builder.build();
+
+        let (net, incoming) = Network::create();
+        net.add_server(server);
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        let (tx, rx) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            let reply = cli.handler4(&JunkArgs { x: 777 }).await;
+            tx.send(reply).unwrap();
+        });
+        let (mut rpc, incoming) = block_on(async {
+            match incoming.into_future().await {
+                (Some(rpc), s) => (rpc, s),
+                _ => panic!("unexpected error"),
+            }
+        });
+        let reply = JunkReply {
+            x: "boom!!!".to_owned(),
+        };
+        let mut buf = vec![];
+        labcodec::encode(&reply, &mut buf).unwrap();
+        let resp = rpc.take_resp_sender().unwrap();
+        resp.send(Ok(buf)).unwrap();
+        assert_eq!(rpc.client_name, "test_client");
+        assert_eq!(rpc.fq_name, "junk.handler4");
+        assert!(!rpc.req.as_ref().unwrap().is_empty());
+        assert_eq!(rx.recv().unwrap(), Ok(reply));
+
+        let (tx, rx) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            let reply = cli.handler4(&JunkArgs { x: 777 }).await;
+            tx.send(reply).unwrap();
+        });
+        let (rpc, incoming) = block_on(async {
+            match incoming.into_future().await {
+                (Some(rpc), s) => (rpc, s),
+                _ => panic!("unexpected error"),
+            }
+        });
+        drop(rpc.resp);
+        assert_eq!(rx.recv().unwrap(), Err(Error::Recv(Canceled)));
+
+        drop(incoming);
+        assert_eq!(
+            block_on(async { client.handler4(&JunkArgs::default()).await }),
+            Err(Error::Stopped)
+        );
+    }
+
+    fn junk_suit() -> (Network, Server, Arc<JunkService>) {
+        let net = Network::new();
+        let server_name = "test_server".to_owned();
+        let mut builder = ServerBuilder::new(server_name);
+        let junk_server = JunkService::new();
+        add_service(junk_server.clone(), &mut builder).unwrap();
+        let server = builder.build();
+        net.add_server(server.clone());
+        (net, server, junk_server)
+    }
+
+    #[test]
+    fn test_basic() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let rsp = block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(
+            JunkReply {
+                x: "pointer".to_owned(),
+            },
+            rsp,
+        );
+    }
+
+    #[test]
+    fn test_call_dynamic() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let mut req = vec![];
+        labcodec::encode(&JunkArgs { x: 7 }, &mut req).unwrap();
+        let rsp = block_on(async { client.call_dynamic("junk.handler2", &req).await.unwrap() });
+        let reply: JunkReply = labcodec::decode(&rsp).unwrap();
+        assert_eq!(reply.x, "handler2-7");
+
+        // malformed payloads are rejected by the server.
+        let res = block_on(async { client.call_dynamic("junk.handler2", b"bad message").await });
+        match res {
+            Err(Error::Decode(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let res = block_on(async { client.call_dynamic("junk.badhandler", &req).await });
+        match res {
+            Err(Error::Unimplemented(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_connect_bidirectional() {
+        init_logger();
+
+        let net = Network::new();
+        for name in &["a", "b"] {
+            let mut builder = ServerBuilder::new(name.to_string());
+            add_service(JunkService::new(), &mut builder).unwrap();
+            net.add_server(builder.build());
+        }
+
+        let (ab, ba) = net.connect_bidirectional("a", "b");
+        let (ab, ba) = (JunkClient::new(ab), JunkClient::new(ba));
+        block_on(async { ab.handler4(&JunkArgs::default()).await.unwrap() });
+        block_on(async { ba.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(net.count("a"), 1);
+        assert_eq!(net.count("b"), 1);
+
+        net.enable_bidirectional("a", "b", false);
+        block_on(async { ab.handler4(&JunkArgs::default()).await.unwrap_err() });
+        block_on(async { ba.handler4(&JunkArgs::default()).await.unwrap_err() });
+
+        net.enable_bidirectional("b", "a", true);
+        block_on(async { ab.handler4(&JunkArgs::default()).await.unwrap() });
+        block_on(async { ba.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(net.count("a"), 2);
+        assert_eq!(net.count("b"), 2);
+    }
+
+    #[test]
+    fn test_connection_queries() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = net.create_client("test_client".to_owned());
+        assert_eq!(net.get_connection(client.name()), None);
+        assert!(!net.is_connected(client.name()));
+        assert!(!net.is_enabled(client.name()));
+
+        net.connect(client.name(), server.name());
+        assert_eq!(
+            net.get_connection(client.name()).as_deref(),
+            Some(server.name())
+        );
+        assert!(net.is_connected(client.name()));
+        assert!(!net.is_enabled(client.name()));
+
+        net.enable(client.name(), true);
+        assert!(net.is_enabled(client.name()));
+        net.enable(client.name(), false);
+        assert!(!net.is_enabled(client.name()));
+
+        let (ab, _) = net.connect_bidirectional("a", "b");
+        assert_eq!(net.get_connection(ab.name()).as_deref(), Some("b"));
+        assert!(net.is_enabled(ab.name()));
+
+        // no such client.
+        assert_eq!(net.get_connection("nobody"), None);
+        assert!(!net.is_connected("nobody"));
+        assert!(!net.is_enabled("nobody"));
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for i in 0..3 {
+            assert_eq!(
+                block_on(async { client.handler6(&JunkArgs { x: i }).await }),
+                Err(Error::HandlerPanic(format!("handler6-{}", i)))
+            );
+            // the server is still usable.
+            let reply = block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            assert_eq!(reply.x, format!("handler2-{}", i));
+        }
+    }
+
+    // does net.Enable(endname, false) really disconnect a client?
+    #[test]
+    fn test_disconnect() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap_err() });
+
+        net.enable("test_client", true);
+        let rsp = block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+
+        assert_eq!(
+            JunkReply {
+                x: "pointer".to_owned(),
+            },
+            rsp,
+        );
+    }
+
+    // test net.GetCount()
+    #[test]
+    fn test_count() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for i in 0..=16 {
+            let reply = block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            assert_eq!(reply.x, format!("handler2-{}", i));
+        }
+
+        assert_eq!(net.count("test_server"), 17);
+    }
+
+    #[test]
+    fn test_reset_counts() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let call = |n| {
+            for i in 0..n {
+                block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            }
+        };
+
+        call(3);
+        assert_eq!(net.count("test_server"), 3);
+        assert_eq!(net.total_count(), 3);
+
+        net.reset_counts();
+        assert_eq!(net.count("test_server"), 0);
+        assert_eq!(net.total_count(), 0);
+
+        call(5);
+        let mark = net.count_mark();
+        assert_eq!(net.count_since(&mark), 0);
+        call(2);
+        assert_eq!(net.count_since(&mark), 2);
+        assert_eq!(net.total_count(), 7);
+
+        server.reset_count();
+        call(4);
+        assert_eq!(server.count(), 4);
+        assert_eq!(net.total_count(), 11);
+        assert_eq!(net.count_since(&mark), 6);
+    }
+
+    #[test]
+    fn test_method_count() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for i in 0..3 {
+            block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+        }
+        for _ in 0..2 {
+            block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        }
+        block_on(async {
+            client
+                .call_dynamic("junk.nohandler", &[])
+                .await
+                .unwrap_err()
+        });
+
+        assert_eq!(server.method_count("junk.handler2"), 3);
+        assert_eq!(net.method_count("test_server", "junk.handler4"), 2);
+        assert_eq!(server.method_count("junk.handler3"), 0);
+        assert_eq!(server.method_count("junk.nohandler"), 0);
+        assert_eq!(server.method_count(UNKNOWN_METHOD), 1);
+        let counts = server.method_counts();
+        assert_eq!(
+            counts,
+            vec![
+                (UNKNOWN_METHOD, 1),
+                ("__labrpc.health", 0),
+                ("__reflection.list_methods", 0),
+                ("junk.handler2", 3),
+                ("junk.handler3", 0),
+                ("junk.handler4", 2),
+                ("junk.handler5", 0),
+                ("junk.handler6", 0),
+                ("junk.handler7", 0),
+                ("junk.handler8", 0),
+            ]
+            .into_iter()
+            .map(|(name, n)| (name.to_owned(), n))
+            .collect::<Vec<_>>()
+        );
+        let sum: usize = counts.iter().map(|(_, n)| n).sum();
+        assert_eq!(sum, server.count());
+
+        net.reset_counts();
+        assert!(server.method_counts().iter().all(|(_, n)| *n == 0));
+    }
+
+    #[test]
+    fn test_seed() {
+        use rand::Rng;
+
+        let samples = |net: &Network| -> Vec<u64> {
+            let rng = &mut net.rng().rng;
+            (0..10).map(|_| rng.gen()).collect()
+        };
+
+        let net1 = Network::new();
+        let net2 = Network::new();
+        assert_eq!(net1.seed(), None);
+        net1.set_seed(42);
+        net2.set_seed(42);
+        assert_eq!(net1.seed(), Some(42));
+        assert_eq!(samples(&net1), samples(&net2));
+
+        net2.set_seed(43);
+        assert_ne!(samples(&net1), samples(&net2));
+    }
+
+    #[test]
+    fn test_json_codec() {
+        let args = JunkArgs { x: -42 };
+        let mut buf = vec![];
+        labcodec::json_encode(&args, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf.clone()).unwrap(), r#"{"x":-42}"#);
+        let decoded: JunkArgs = labcodec::json_decode(&mut &buf[..]).unwrap();
+        assert_eq!(decoded, args);
+
+        let err = labcodec::json_decode::<JunkArgs, _>(&mut &b"{"[..]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    // test RPCs from concurrent Clients
+    #[test]
+    fn test_concurrent_many() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let server_name = server.name();
+
+        let pool = ThreadPool::new().unwrap();
+        let (tx, rx) = mpsc::channel::<usize>();
+
+        let nclients = 20usize;
+        let nrpcs = 10usize;
+        for i in 0..nclients {
+            let net = net.clone();
+            let sender = tx.clone();
+            let server_name = server_name.to_string();
+
+            pool.spawn_ok(async move {
+                let mut n = 0;
+                let client_name = format!("client-{}", i);
+                let client = JunkClient::new(net.create_client(client_name.clone()));
+                net.enable(&client_name, true);
+                net.connect(&client_name, &server_name);
 
                 for j in 0..nrpcs {
                     let x = (i * 100 + j) as i64;
@@ -287,219 +1467,2089 @@ pub mod tests {
                     n += 1;
                 }
 
-                sender.send(n).unwrap();
-            });
+                sender.send(n).unwrap();
+            });
+        }
+
+        let mut total = 0;
+        for _ in 0..nclients {
+            total += rx.recv().unwrap();
+        }
+        assert_eq!(total, nrpcs * nclients);
+        let n = net.count(server_name);
+        assert_eq!(n, total);
+    }
+
+    // handlers waiting on timers must not starve the network pool.
+    #[test]
+    fn test_concurrent_async_handlers() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", server.name());
+        net.enable("test_client", true);
+
+        let nrpcs = 100;
+        let t0 = Instant::now();
+        let calls = (0..nrpcs).map(|_| client.handler5(&JunkArgs { x: 200 }));
+        let replies = block_on(future::join_all(calls));
+        let dur = t0.elapsed();
+        for reply in replies {
+            assert_eq!(reply.unwrap().x, "handler5-200");
+        }
+        assert_eq!(server.count(), nrpcs);
+        assert!(
+            dur < Duration::from_secs(5),
+            "{} concurrent RPCs took {:?}",
+            nrpcs,
+            dur
+        );
+    }
+
+    #[test]
+    fn test_unreliable() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let server_name = server.name();
+        net.set_reliable(false);
+
+        let pool = ThreadPool::new().unwrap();
+        let (tx, rx) = mpsc::channel::<usize>();
+        let nclients = 300;
+        for i in 0..nclients {
+            let sender = tx.clone();
+            let mut n = 0;
+            let server_name = server_name.to_owned();
+            let net = net.clone();
+
+            pool.spawn_ok(async move {
+                let client_name = format!("client-{}", i);
+                let client = JunkClient::new(net.create_client(client_name.clone()));
+                net.enable(&client_name, true);
+                net.connect(&client_name, &server_name);
+
+                let x = i * 100;
+                if let Ok(reply) = client.handler2(&JunkArgs { x }).await {
+                    assert_eq!(reply.x, format!("handler2-{}", x));
+                    n += 1;
+                }
+                sender.send(n).unwrap();
+            });
+        }
+        let mut total = 0;
+        for _ in 0..nclients {
+            total += rx.recv().unwrap();
+        }
+        assert!(
+            !(total == nclients as usize || total == 0),
+            "all RPCs succeeded despite unreliable total {}, nclients {}",
+            total,
+            nclients
+        );
+    }
+
+    #[test]
+    fn test_chaos_mode() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let server_name = server.name();
+        net.set_max_reorder_delay(300);
+        net.chaos_mode(1.0, 42);
+        assert_eq!(net.seed(), Some(42));
+
+        let calls = |nclients: usize| -> usize {
+            let pool = ThreadPool::new().unwrap();
+            let (tx, rx) = mpsc::channel::<usize>();
+            for i in 0..nclients {
+                let sender = tx.clone();
+                let server_name = server_name.to_owned();
+                let net = net.clone();
+                pool.spawn_ok(async move {
+                    let client_name = format!("client-{}", i);
+                    let client = JunkClient::new(net.create_client(client_name.clone()));
+                    net.enable(&client_name, true);
+                    net.connect(&client_name, &server_name);
+                    let ok = client.handler4(&JunkArgs::default()).await.is_ok();
+                    sender.send(ok as usize).unwrap();
+                });
+            }
+            (0..nclients).map(|_| rx.recv().unwrap()).sum()
+        };
+        // half of the faults drop the request or the reply.
+        let ok = calls(200);
+        assert!(ok > 50 && ok < 150, "{} of 200 RPCs succeeded", ok);
+
+        net.chaos_mode(0.0, 42);
+        assert_eq!(calls(50), 50);
+    }
+
+    #[test]
+    fn test_settings() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let saved = net.settings();
+        assert_eq!(saved, NetworkSettings::default());
+        net.apply_settings(&NetworkSettings {
+            reliable: false,
+            long_delays: true,
+            long_reordering: true,
+            min_reorder_delay: 0,
+            max_reorder_delay: 10,
+            max_message_size: 0,
+        });
+        assert_ne!(net.settings(), saved);
+
+        net.apply_settings(&saved);
+        assert_eq!(net.settings(), saved);
+        // the network is reliable again, all RPCs must succeed.
+        for i in 0..100 {
+            let reply = block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            assert_eq!(reply.x, format!("handler2-{}", i));
+        }
+    }
+
+    #[test]
+    fn test_count_by_client() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        // off by default.
+        let client = JunkClient::new(net.create_client("client0".to_owned()));
+        net.connect("client0", "test_server");
+        net.enable("client0", true);
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert!(server.count_by_client().is_empty());
+
+        let mut builder = ServerBuilder::new("counted".to_owned());
+        builder.enable_client_counts();
+        add_service(JunkService::new(), &mut builder).unwrap();
+        let server = builder.build();
+        net.add_server(server.clone());
+        for (i, name) in ["client1", "client2", "client3"].iter().enumerate() {
+            let client = JunkClient::new(net.create_client(name.to_string()));
+            net.connect(name, "counted");
+            net.enable(name, true);
+            for _ in 0..(i + 1) * 5 {
+                block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+            }
+        }
+        let counts = vec![
+            ("client1".to_owned(), 5),
+            ("client2".to_owned(), 10),
+            ("client3".to_owned(), 15),
+        ];
+        assert_eq!(server.count_by_client(), counts);
+        assert_eq!(server.count(), 30);
+
+        server.reset_count();
+        assert!(server.count_by_client().is_empty());
+    }
+
+    #[test]
+    fn test_empty_messages() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let svc = PingService::default();
+        pinger::add_service_live(svc.clone(), &server).unwrap();
+        let raw = net.create_client("test_client".to_owned());
+        let client = pinger::Client::new(raw.clone());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let reply = block_on(client.ping()).unwrap();
+        assert_eq!(reply.x, "pong");
+        block_on(client.fire(&JunkArgs { x: 2 })).unwrap();
+        block_on(client.nop()).unwrap();
+        assert_eq!(svc.fired.load(Ordering::SeqCst), 2);
+        // zero bytes are sent and replied, any message is accepted in place
+        // of an empty one.
+        assert_eq!(block_on(raw.call_raw("pinger.nop", vec![])), Ok(vec![]));
+        let mut req = vec![];
+        labcodec::encode(&JunkArgs { x: 1 }, &mut req).unwrap();
+        assert_eq!(block_on(raw.call_raw("pinger.nop", req)), Ok(vec![]));
+
+        net.set_reliable(false);
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..100 {
+            let (client, tx) = (client.clone(), tx.clone());
+            client.clone().spawn(async move {
+                tx.send(client.ping().await).unwrap();
+            });
+        }
+        drop(tx);
+        let mut ok = 0;
+        for res in rx {
+            match res {
+                Ok(reply) => {
+                    assert_eq!(reply.x, "pong");
+                    ok += 1;
+                }
+                Err(e) => assert_eq!(e, Error::Timeout),
+            }
+        }
+        assert!(ok > 50, "{} of 100 succeeded", ok);
+    }
+
+    #[test]
+    fn test_mock_client() {
+        init_logger();
+
+        // the code under test, generic over the real and the mock clients.
+        fn fetch_all<C: junk::Caller>(client: &C, keys: &[i64]) -> Result<Vec<String>> {
+            keys.iter()
+                .map(|&x| block_on(client.handler2(&JunkArgs { x })).map(|reply| reply.x))
+                .collect()
+        }
+
+        let mock = junk::MockClient::new();
+        mock.on_handler2(|args| {
+            if args.x < 0 {
+                return Err(Error::Other("negative".to_owned()));
+            }
+            Ok(JunkReply {
+                x: format!("mock-{}", args.x),
+            })
+        });
+        assert_eq!(
+            fetch_all(&mock, &[1, 2]),
+            Ok(vec!["mock-1".to_owned(), "mock-2".to_owned()])
+        );
+        assert_eq!(
+            fetch_all(&mock.clone(), &[3, -1, 4]),
+            Err(Error::Other("negative".to_owned()))
+        );
+        let requests: Vec<JunkArgs> = mock.requests("handler2");
+        let keys: Vec<_> = requests.iter().map(|args| args.x).collect();
+        assert_eq!(keys, vec![1, 2, 3, -1]);
+        // no reply is set.
+        let res = block_on(junk::Caller::handler4(&mock, &JunkArgs::default()));
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+        assert_eq!(mock.calls().len(), 5);
+        assert_eq!(mock.calls()[4].0, "handler4");
+
+        let mock = pinger::MockClient::new();
+        mock.on_nop(|()| Ok(()));
+        block_on(pinger::Caller::nop(&mock)).unwrap();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        assert_eq!(fetch_all(&client, &[1]), Ok(vec!["handler2-1".to_owned()]));
+    }
+
+    #[test]
+    fn test_rpc_observer() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let observed = Arc::new(Mutex::new(vec![]));
+        for i in 0..2 {
+            let observed = observed.clone();
+            net.add_rpc_observer(Arc::new(move |rpc: &Rpc| {
+                let args: JunkArgs = labcodec::decode(rpc.req()).unwrap();
+                observed.lock().unwrap().push((
+                    i,
+                    rpc.client_name().to_owned(),
+                    rpc.fq_name().to_owned(),
+                    args.x,
+                ));
+            }));
+        }
+        block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        // the dropped RPCs are observed too.
+        net.drop_next_n("test_client", 1);
+        block_on(client.handler4(&JunkArgs { x: 2 })).unwrap_err();
+        let rpc = |i, fq_name: &str, x| (i, "test_client".to_owned(), fq_name.to_owned(), x);
+        assert_eq!(
+            *observed.lock().unwrap(),
+            vec![
+                rpc(0, "junk.handler2", 1),
+                rpc(1, "junk.handler2", 1),
+                rpc(0, "junk.handler4", 2),
+                rpc(1, "junk.handler4", 2),
+            ]
+        );
+
+        net.clear_rpc_observers();
+        block_on(client.handler2(&JunkArgs { x: 3 })).unwrap();
+        assert_eq!(observed.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_multiple_services() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_all_services(CaseService, CaseService, &mut builder).unwrap();
+        add_all_services(CaseService, CaseService, &mut builder).unwrap_err();
+        let server = builder.build();
+        assert!(server.has_method("upper_case.upper"));
+        assert!(server.has_method("lower_case.lower"));
+        let net = Network::new();
+        net.add_server(server);
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let args = JunkArgs { x: 1 };
+        let reply = block_on(upper_case::Client::new(client.clone()).upper(&args)).unwrap();
+        assert_eq!(reply.x, "UPPER-1");
+        let reply = block_on(lower_case::Client::new(client).lower(&args)).unwrap();
+        assert_eq!(reply.x, "lower-1");
+    }
+
+    #[test]
+    fn test_path_types() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        paths::paths::add_service(PathService, &mut builder).unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let client = paths::paths::Client::new(client);
+        let args = JunkArgs { x: 1 };
+        let reply = block_on(client.qualified(&args)).unwrap();
+        assert_eq!(reply.x, "qualified-1");
+        let reply = block_on(client.boxed(&Box::new(args))).unwrap();
+        assert_eq!(reply.x, "boxed-1");
+    }
+
+    #[test]
+    fn test_stream() {
+        init_logger();
+
+        let svc = SnapshotService::default();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        snapshot::add_service(svc.clone(), &mut builder).unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = snapshot::Client::connect(&net, "test_client".to_owned(), "test_server");
+        assert_eq!(
+            snapshot::all_methods(),
+            &["snapshot.size", "snapshot.fetch", "snapshot.whoami"]
+        );
+
+        let chunks = block_on(client.fetch(&JunkArgs { x: 10 }))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let want: Vec<_> = (0..10).map(|i| format!("chunk-{}", i)).collect();
+        assert_eq!(
+            chunks.iter().map(|c| &c.x).collect::<Vec<_>>(),
+            want.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(svc.sent.load(Ordering::SeqCst), 10);
+
+        // drops the stream after 3 chunks.
+        let mut stream = block_on(client.fetch(&JunkArgs { x: 10 })).unwrap();
+        for want in want.iter().take(3) {
+            assert_eq!(&stream.next().unwrap().unwrap().x, want);
+        }
+        drop(stream);
+        let reply = block_on(client.size(&JunkArgs { x: 10 })).unwrap();
+        assert_eq!(reply.x, "size-10");
+
+        let mut stream = block_on(client.fetch(&JunkArgs { x: 0 })).unwrap();
+        assert!(stream.next().is_none());
+        let res = block_on(client.fetch(&JunkArgs { x: -1 }));
+        assert_eq!(res.unwrap_err(), Error::Other("no snapshot -1".to_owned()));
+
+        let mut stream = block_on(client.whoami()).unwrap();
+        assert_eq!(stream.next().unwrap().unwrap().x, "test_client");
+        assert!(stream.next().is_none());
+
+        // a truncated stream fails at its end.
+        let sink = ChunkSink::new();
+        sink.send(&JunkReply::default()).unwrap();
+        let mut frames = sink.finish();
+        frames.pop();
+        assert!(sink.send(&JunkReply::default()).is_err());
+        let mut stream = ChunkStream::<JunkReply>::new(frames);
+        assert_eq!(stream.next().unwrap().unwrap(), JunkReply::default());
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+
+        let mock = snapshot::MockClient::new();
+        mock.on_fetch(|args| {
+            Ok((0..args.x)
+                .map(|i| JunkReply { x: i.to_string() })
+                .collect())
+        });
+        let stream = block_on(snapshot::Caller::fetch(&mock, &JunkArgs { x: 3 })).unwrap();
+        assert_eq!(stream.count(), 3);
+    }
+
+    #[test]
+    fn test_oneway() {
+        init_logger();
+
+        let svc = CommitService::default();
+        let (open, gate) = futures::channel::oneshot::channel();
+        *svc.gate.lock().unwrap() = Some(gate);
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        commits::add_service(svc.clone(), &mut builder).unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = commits::Client::connect(&net, "test_client".to_owned(), "test_server");
+
+        // the handler waits for the gate, which opens after the client
+        // has returned.
+        client.notify(&JunkArgs { x: 1 }).unwrap();
+        assert!(svc.received.lock().unwrap().is_empty());
+        open.send(()).unwrap();
+        client.notify(&JunkArgs { x: 2 }).unwrap();
+
+        let start = Instant::now();
+        while svc.received.lock().unwrap().len() < 2 {
+            assert!(start.elapsed() < Duration::from_secs(1));
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut received = svc.received.lock().unwrap().clone();
+        received.sort_unstable();
+        assert_eq!(received, vec![1, 2]);
+        let reply = block_on(client.count()).unwrap();
+        assert_eq!(reply.x, "2");
+        assert_eq!(commits::all_methods(), &["commits.notify", "commits.count"]);
+
+        let mock = commits::MockClient::new();
+        commits::Caller::notify(&mock, &JunkArgs { x: 3 }).unwrap();
+        assert_eq!(mock.requests::<JunkArgs>("notify"), vec![JunkArgs { x: 3 }]);
+    }
+
+    #[test]
+    fn test_request_response_enums() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        snapshot::add_service(SnapshotService::default(), &mut builder).unwrap();
+        pinger::add_service(PingService::default(), &mut builder).unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let end = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let client = snapshot::Client::new(end.clone());
+        let requests = vec![
+            snapshot::Request::Size(JunkArgs { x: 1 }),
+            snapshot::Request::Fetch(JunkArgs { x: 2 }),
+            snapshot::Request::Whoami(()),
+        ];
+        // the enums of a service annotated with `#[derive(Clone)]` are Clone.
+        let retry = requests[1].clone();
+        assert_eq!(retry.fq_name(), snapshot::method_names::FETCH);
+        let mut replies = vec![];
+        for req in requests {
+            let rsp = block_on(client.call_dynamic(req.fq_name(), &req.encode().unwrap()));
+            replies.push(snapshot::Response::decode(req.fq_name(), &rsp.unwrap()).unwrap());
+        }
+        match &replies[..] {
+            [snapshot::Response::Size(size), snapshot::Response::Fetch(chunks), snapshot::Response::Whoami(names)] =>
+            {
+                assert_eq!(size.x, "size-1");
+                assert_eq!(chunks.len(), 2);
+                assert_eq!(names[0].x, "test_client");
+            }
+            replies => panic!("{:?}", replies),
+        }
+
+        let client = pinger::Client::new(end);
+        for req in [
+            pinger::Request::Ping(()),
+            pinger::Request::Fire(JunkArgs { x: 1 }),
+            pinger::Request::Nop(()),
+        ] {
+            let rsp = block_on(client.call_dynamic(req.fq_name(), &req.encode().unwrap())).unwrap();
+            match pinger::Response::decode(req.fq_name(), &rsp).unwrap() {
+                pinger::Response::Ping(reply) => assert_eq!(reply.x, "pong"),
+                pinger::Response::Fire(()) | pinger::Response::Nop(()) => {}
+            }
+        }
+        let res = pinger::Response::decode(snapshot::method_names::SIZE, &[]);
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+
+        // the messages need not be Clone otherwise.
+        let req = unique::Request::Take(Unique { x: 1 });
+        let mut buf = vec![];
+        labcodec::encode(&Unique { x: 1 }, &mut buf).unwrap();
+        assert_eq!(req.encode().unwrap(), buf);
+    }
+
+    #[test]
+    fn test_method_name_checks() {
+        use crate::__private::{count_name, str_eq};
+
+        assert!(str_eq("get", "get"));
+        assert!(!str_eq("get", "put"));
+        assert!(!str_eq("get", "gets"));
+        assert!(str_eq("", ""));
+
+        let names = ["get", "put", "append", "put"];
+        assert_eq!(count_name(&names, "get"), 1);
+        assert_eq!(count_name(&names, "put"), 2);
+        assert_eq!(count_name(&names, "delete"), 0);
+        assert_eq!(count_name(&[], "get"), 0);
+        // evaluated at compile time, as in the expansion of `service!`.
+        const DUPLICATES: usize = count_name(&["get", "get"], "get");
+        assert_eq!(DUPLICATES, 2);
+    }
+
+    #[test]
+    fn test_shared_service() {
+        init_logger();
+
+        let shared: Arc<dyn tally::Service> = Arc::new(TallyService::default());
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        tally::add_service(TallyService::default(), &mut builder).unwrap();
+        tally::add_service_with_prefix(shared.clone(), &mut builder, "shared").unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let end = net.create_client("test_client".to_owned());
+        net.connect(end.name(), "test_server");
+        net.enable(end.name(), true);
+
+        // every call of every method is served by the same instance.
+        for client in &[
+            tally::Client::new(end.clone()),
+            tally::Client::with_prefix(end.clone(), "shared"),
+        ] {
+            let calls = (1..=10).map(|x| client.add(&JunkArgs { x }));
+            for res in block_on(future::join_all(calls)) {
+                res.unwrap();
+            }
+            assert_eq!(block_on(client.total()).unwrap().x, "55");
+        }
+        assert_eq!(block_on(shared.total()).unwrap().x, "55");
+    }
+
+    #[test]
+    fn test_nested_service() {
+        use deep::deeper::deepest::{nested, NestedService};
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        nested::add_service(NestedService, &mut builder).unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = nested::Client::connect(&net, "test_client".to_owned(), "test_server");
+
+        let reply = block_on(client.echo(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "nested-1");
+        let stream = block_on(client.chunks(&JunkArgs { x: 3 })).unwrap();
+        assert_eq!(stream.count(), 3);
+    }
+
+    #[test]
+    fn test_service_prefix() {
+        init_logger();
+
+        let (junk_a, junk_b) = (JunkService::new(), JunkService::new());
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        junk::add_service_with_prefix(junk_a.clone(), &mut builder, "junk_a").unwrap();
+        junk::add_service_with_prefix(junk_b.clone(), &mut builder, "junk_b").unwrap();
+        junk::add_service_with_prefix(JunkService::new(), &mut builder, "junk_b").unwrap_err();
+        let server = builder.build();
+        assert!(server.has_method("junk_a.handler2"));
+        assert!(!server.has_method("junk.handler2"));
+        let net = Network::new();
+        net.add_server(server.clone());
+        let end = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let client_a = JunkClient::with_prefix(end.clone(), "junk_a");
+        let client_b = JunkClient::with_prefix(end.clone(), "junk_b".to_owned());
+        block_on(client_a.handler2(&JunkArgs { x: 1 })).unwrap();
+        block_on(client_b.handler2(&JunkArgs { x: 2 })).unwrap();
+        block_on(client_b.handler2(&JunkArgs { x: 3 })).unwrap();
+        assert_eq!(junk_a.inner.lock().unwrap().log2, vec![1]);
+        assert_eq!(junk_b.inner.lock().unwrap().log2, vec![2, 3]);
+        assert_eq!(server.method_count("junk_b.handler2"), 2);
+        assert!(format!("{:?}", client_b).contains(r#"service: "junk_b""#));
+
+        // the default client calls the service under its own name.
+        let res = block_on(JunkClient::new(end).handler2(&JunkArgs { x: 4 }));
+        match res {
+            Err(Error::Unimplemented(msg)) => assert!(msg.contains("junk.handler2"), "{}", msg),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_call_policy() {
+        init_logger();
+
+        let svc = PolicyService::default();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        policy::add_service(svc.clone(), &mut builder).unwrap();
+        let net = Network::new();
+        net.add_server(builder.build());
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        let client = policy::Client::new(client);
+
+        let reply = block_on(client.slow(&JunkArgs { x: 10 })).unwrap();
+        assert_eq!(reply.x, "slow-10");
+        let start = Instant::now();
+        let res = block_on(client.slow(&JunkArgs { x: 2000 }));
+        assert_eq!(res, Err(Error::Timeout));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // the first two calls time out.
+        let reply = block_on(client.flaky(&JunkArgs::default())).unwrap();
+        assert_eq!(reply.x, "flaky-3");
+        assert_eq!(svc.flaky_calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        net.set_reliable(false);
+        // the calls failing of 200 concurrent ones.
+        let failures = |client: JunkClient| {
+            // keeps the short delays of the lost requests short.
+            net.set_rtt_model(client.end_name(), RttModel::Uniform { min: 0, max: 5 });
+            let calls = (0..200).map(|x| {
+                let client = client.clone();
+                async move { client.handler2(&JunkArgs { x }).await }
+            });
+            let results = block_on(future::join_all(calls));
+            results.iter().filter(|res| res.is_err()).count()
+        };
+
+        let client = JunkClient::connect(&net, "no_retry".to_owned(), server.name());
+        assert!(failures(client) > 0);
+
+        let end = net.create_client("retry".to_owned());
+        net.connect(end.name(), server.name());
+        net.enable(end.name(), true);
+        let client = JunkClient::with_policy(end.clone(), RetryPolicy::attempts(10));
+        assert_eq!(failures(client), 0);
+        assert!(end.connection_stats().calls_sent > 200);
+
+        assert_eq!(RetryPolicy::default().max_attempts, 1);
+        assert!(RetryPolicy::is_retryable(&Error::Timeout));
+        assert!(!RetryPolicy::is_retryable(&Error::Stopped));
+    }
+
+    #[test]
+    fn test_partial_service() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        draft::add_service(DraftService, &mut builder).unwrap();
+        let server = builder.build();
+        assert!(server.has_method("draft.todo"));
+        let net = Network::new();
+        net.add_server(server);
+        let client = draft::Client::connect(&net, "test_client".to_owned(), "test_server");
+        assert_eq!(client.end_name(), "test_client");
+        assert_eq!(
+            format!("{:?}", client),
+            r#"Client { service: "draft", end_name: "test_client" }"#
+        );
+
+        let args = JunkArgs { x: 1 };
+        let reply = block_on(client.done(&args)).unwrap();
+        assert_eq!(reply.x, "done-1");
+        match block_on(client.todo(&args)) {
+            Err(Error::Unimplemented(msg)) => assert!(msg.contains("draft.todo"), "{}", msg),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_remove_handler() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let raw = net.create_client("test_client".to_owned());
+        let client = JunkClient::new(raw.clone());
+        let echo_client = echoer::Client::new(raw);
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        echoer::add_service_live(EchoService, &server).unwrap();
+
+        server.remove_handler("junk.handler2").unwrap();
+        assert!(!server.has_method("junk.handler2"));
+        let res = block_on(async { client.handler2(&JunkArgs { x: 1 }).await });
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+        // the other methods of the service are still served.
+        let reply = block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(reply.x, "pointer");
+        match server.remove_handler("junk.handler2") {
+            Err(Error::Unimplemented(msg)) => assert!(msg.contains("junk.handler2"), "{}", msg),
+            res => panic!("{:?}", res),
+        }
+
+        // the service is gone with its last method.
+        server.remove_handler("echoer.echo").unwrap();
+        let res = block_on(async { echo_client.echo(&JunkArgs { x: 1 }).await });
+        assert!(matches!(res, Err(Error::Unimplemented(_))), "{:?}", res);
+        echoer::add_service_live(EchoService, &server).unwrap();
+        let reply = block_on(async { echo_client.echo(&JunkArgs { x: 1 }).await.unwrap() });
+        assert_eq!(reply.x, "echo-1");
+
+        // replacing the service brings the method back.
+        junk::replace_service_live(JunkService::new(), &server);
+        let reply = block_on(async { client.handler2(&JunkArgs { x: 1 }).await.unwrap() });
+        assert_eq!(reply.x, "handler2-1");
+    }
+
+    #[test]
+    fn test_hot_swap_handler() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let upgraded: Arc<MethodHandler> = Arc::new(|_, req| {
+            let args: JunkArgs = labcodec::decode(req).unwrap();
+            let reply = JunkReply {
+                x: format!("upgraded-{}", args.x),
+            };
+            let mut buf = vec![];
+            labcodec::encode(&reply, &mut buf).unwrap();
+            Box::pin(future::ok(buf))
+        });
+        let original = server
+            .hot_swap_handler("junk.handler2", upgraded.clone())
+            .unwrap();
+        let reply = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "upgraded-1");
+        // the other methods are not affected.
+        let reply = block_on(client.handler4(&JunkArgs::default())).unwrap();
+        assert_eq!(reply.x, "pointer");
+        assert_eq!(server.method_count("junk.handler2"), 1);
+
+        // swaps the original handler back.
+        let swapped = server.hot_swap_handler("junk.handler2", original).unwrap();
+        assert!(Arc::ptr_eq(&swapped, &upgraded));
+        let reply = block_on(client.handler2(&JunkArgs { x: 2 })).unwrap();
+        assert_eq!(reply.x, "handler2-2");
+
+        match server.hot_swap_handler("junk.nohandler", upgraded) {
+            Err(Error::Unimplemented(msg)) => assert!(msg.contains("junk.nohandler"), "{}", msg),
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => panic!("swapped an unknown method"),
+        }
+
+        // replacing the service drops the swapped handler.
+        junk::replace_service_live(JunkService::new(), &server);
+        let reply = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "handler2-1");
+    }
+
+    #[test]
+    fn test_default_handler() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        builder.set_default_handler(Arc::new(|_, fq_name, req| {
+            let mut rsp = fq_name.as_bytes().to_vec();
+            rsp.extend_from_slice(req);
+            Box::pin(future::ok(rsp))
+        }));
+        let net = Network::new();
+        let server = builder.build();
+        net.add_server(server.clone());
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let rsp = block_on(client.call_raw("proxy.get", b"-key".to_vec())).unwrap();
+        assert_eq!(rsp, b"proxy.get-key");
+        let rsp = block_on(client.call_raw("junk.nohandler", vec![])).unwrap();
+        assert_eq!(rsp, b"junk.nohandler");
+        assert_eq!(server.method_count(UNKNOWN_METHOD), 2);
+        // the registered methods are served by their handlers.
+        let reply = block_on(async {
+            JunkClient::new(client)
+                .handler2(&JunkArgs { x: 1 })
+                .await
+                .unwrap()
+        });
+        assert_eq!(reply.x, "handler2-1");
+    }
+
+    #[test]
+    fn test_drop_next_n() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        let other = JunkClient::new(net.create_client("other_client".to_owned()));
+        net.connect("other_client", "test_server");
+        net.enable("other_client", true);
+
+        net.drop_next_n("test_client", 3);
+        // the other client is not affected.
+        block_on(async { other.handler4(&JunkArgs::default()).await.unwrap() });
+        for _ in 0..3 {
+            let res = block_on(async { client.handler4(&JunkArgs::default()).await });
+            assert_eq!(res, Err(Error::Timeout));
+        }
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(server.count(), 2);
+
+        net.drop_next_n("test_client", 3);
+        net.drop_next_n("test_client", 0);
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+    }
+
+    #[test]
+    fn test_create_with_config() {
+        init_logger();
+
+        let net = Network::create_with_config(NetworkConfig {
+            reliable: false,
+            long_reordering: true,
+            max_message_size: 1,
+            seed: Some(42),
+            ..Default::default()
+        });
+        assert_eq!(
+            net.settings(),
+            NetworkSettings {
+                reliable: false,
+                long_reordering: true,
+                max_message_size: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(net.seed(), Some(42));
+        assert_eq!(Network::new().settings(), NetworkSettings::default());
+        assert_eq!(Network::new().seed(), None);
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        net.add_server(builder.build());
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        let res = block_on(async { client.handler2(&JunkArgs { x: 1 }).await });
+        assert!(matches!(res, Err(Error::MessageTooLarge)), "{:?}", res);
+    }
+
+    #[test]
+    fn test_reorder_delay() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        net.set_long_reordering(true);
+        net.set_min_reorder_delay(50);
+        net.set_max_reorder_delay(100);
+
+        let mut reordered = 0;
+        for i in 0..20 {
+            let start = Instant::now();
+            block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            let elapsed = start.elapsed();
+            assert!(elapsed < Duration::from_millis(150), "{:?}", elapsed);
+            if elapsed >= Duration::from_millis(50) {
+                reordered += 1;
+            }
+        }
+        // about 2/3 of the replies are reordered.
+        assert!(reordered > 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "exceeds the max")]
+    fn test_reorder_delay_bounds() {
+        let net = Network::new();
+        net.set_min_reorder_delay(50);
+        net.set_max_reorder_delay(100);
+        net.set_min_reorder_delay(200);
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        init_logger();
+
+        let (net, server, junk_server) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let args = JunkArgs { x: 1 };
+        let mut buf = vec![];
+        labcodec::encode(&args, &mut buf).unwrap();
+
+        // the request is too large.
+        net.set_max_message_size(buf.len() - 1);
+        assert_eq!(
+            block_on(async { client.handler2(&args).await }),
+            Err(Error::MessageTooLarge)
+        );
+        assert_eq!(server.count(), 0);
+
+        // the request fits, but the reply "handler2-1" does not.
+        net.set_max_message_size(buf.len());
+        assert_eq!(
+            block_on(async { client.handler2(&args).await }),
+            Err(Error::MessageTooLarge)
+        );
+        assert_eq!(junk_server.inner.lock().unwrap().log2, vec![1]);
+
+        net.set_max_message_size(usize::MAX);
+        let reply = block_on(async { client.handler2(&args).await.unwrap() });
+        assert_eq!(reply.x, "handler2-1");
+    }
+
+    #[test]
+    fn test_app_error() {
+        init_logger();
+
+        let net = Network::new();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        kv::add_service(KvService, &mut builder).unwrap();
+        net.add_server(builder.build());
+        let client = kv::Client::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let reply = block_on(async { client.get(&JunkArgs { x: 1 }).await.unwrap() });
+        assert_eq!(reply.x, "value-1");
+        let res = block_on(async { client.get(&JunkArgs { x: -1 }).await });
+        assert_eq!(
+            res,
+            Err(Error::App {
+                code: NOT_FOUND,
+                msg: "no key -1".to_owned(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_pending_rpcs() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let _scope = net.test_scope();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        net.assert_no_pending_rpcs();
+        let (tx, rx) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            tx.send(cli.handler5(&JunkArgs { x: 100 }).await).unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(net.pending_rpcs(), 1);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| net.assert_no_pending_rpcs()));
+        let msg = *res.unwrap_err().downcast::<String>().unwrap();
+        assert!(msg.contains("test_client -> junk.handler5"), "{}", msg);
+
+        rx.recv().unwrap().unwrap();
+        assert_eq!(net.pending_rpcs(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "test_client -> junk.handler3")]
+    fn test_scope_leak() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let _scope = net.test_scope();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // handler3 replies after 20s, long after the test.
+        let cli = client.clone();
+        client.spawn(async move {
+            let _ = cli.handler3(&JunkArgs::default()).await;
+        });
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_slow_handler() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        net.set_slow_handler_threshold(Duration::from_millis(50));
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        net.set_slow_handler_hook(Arc::new(move |fq_name, elapsed| {
+            tx.lock()
+                .unwrap()
+                .send((fq_name.to_owned(), elapsed))
+                .unwrap();
+        }));
+
+        block_on(async { client.handler7(&JunkArgs { x: 0 }).await.unwrap() });
+        block_on(async { client.handler5(&JunkArgs { x: 10 }).await.unwrap() });
+        assert_eq!(net.slow_handler_count(), 0);
+
+        // blocks its thread.
+        block_on(async { client.handler7(&JunkArgs { x: 100 }).await.unwrap() });
+        assert_eq!(net.slow_handler_count(), 1);
+        let (fq_name, elapsed) = rx.try_recv().unwrap();
+        assert_eq!(fq_name, "junk.handler7");
+        assert_eq!(elapsed, Duration::from_millis(50));
+        // waits asynchronously, which is slow all the same.
+        block_on(async { client.handler5(&JunkArgs { x: 100 }).await.unwrap() });
+        assert_eq!(net.slow_handler_count(), 2);
+    }
+
+    #[test]
+    fn test_max_response_size() {
+        init_logger();
+
+        let net = Network::new();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        let junk_server = JunkService::new();
+        add_service(junk_server.clone(), &mut builder).unwrap();
+        // "handler2-1" takes 12 bytes.
+        builder.set_max_response_size(12);
+        let server = builder.build();
+        assert_eq!(server.max_response_size(), Some(12));
+        net.add_server(server);
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let reply = block_on(async { client.handler2(&JunkArgs { x: 1 }).await.unwrap() });
+        assert_eq!(reply.x, "handler2-1");
+        assert_eq!(
+            block_on(async { client.handler2(&JunkArgs { x: 10 }).await }),
+            Err(Error::MessageTooLarge)
+        );
+        // the handler has run.
+        assert_eq!(junk_server.inner.lock().unwrap().log2, vec![1, 10]);
+
+        let (_, server, _) = junk_suit();
+        assert_eq!(server.max_response_size(), None);
+    }
+
+    // test concurrent RPCs from a single Client
+    #[test]
+    fn test_concurrent_one() {
+        init_logger();
+
+        let (net, server, junk_server) = junk_suit();
+        let server_name = server.name();
+
+        let pool = ThreadPool::new().unwrap();
+        let (tx, rx) = mpsc::channel::<usize>();
+        let nrpcs = 20;
+        for i in 0..20 {
+            let sender = tx.clone();
+            let client_name = format!("client-{}", i);
+            let client = JunkClient::new(net.create_client(client_name.clone()));
+            net.enable(&client_name, true);
+            net.connect(&client_name, server_name);
+
+            pool.spawn_ok(async move {
+                let mut n = 0;
+                let x = i + 100;
+                let reply = client.handler2(&JunkArgs { x }).await.unwrap();
+                assert_eq!(reply.x, format!("handler2-{}", x));
+                n += 1;
+                sender.send(n).unwrap()
+            });
+        }
+
+        let mut total = 0;
+        for _ in 0..nrpcs {
+            total += rx.recv().unwrap();
+        }
+        assert_eq!(
+            total, nrpcs,
+            "wrong number of RPCs completed, got {}, expected {}",
+            total, nrpcs
+        );
+
+        assert_eq!(
+            junk_server.inner.lock().unwrap().log2.len(),
+            nrpcs,
+            "wrong number of RPCs delivered"
+        );
+
+        let n = net.count(server.name());
+        assert_eq!(n, total, "wrong count() {}, expected {}", n, total);
+    }
+
+    // regression: an RPC that's delayed during Enabled=false
+    // should not delay subsequent RPCs (e.g. after Enabled=true).
+    #[test]
+    fn test_regression1() {
+        init_logger();
+
+        let (net, server, junk_server) = junk_suit();
+        let server_name = server.name();
+
+        let client_name = "client";
+        let client = JunkClient::new(net.create_client(client_name.to_owned()));
+        net.connect(client_name, server_name);
+
+        // start some RPCs while the Client is disabled.
+        // they'll be delayed.
+        net.enable(client_name, false);
+
+        let pool = ThreadPool::new().unwrap();
+        let (tx, rx) = mpsc::channel::<bool>();
+        let nrpcs = 20;
+        for i in 0..20 {
+            let sender = tx.clone();
+            let cli = client.clone();
+            pool.spawn_ok(async move {
+                let x = i + 100;
+                // this call ought to return false.
+                let _ = cli.handler2(&JunkArgs { x }).await;
+                sender.send(true).unwrap();
+            });
+        }
+
+        // wait until all the RPCs have been processed by the network.
+        let t0 = Instant::now();
+        while net.total_count() < nrpcs {
+            assert!(t0.elapsed() < Duration::from_secs(5), "RPCs are not sent");
+            thread::yield_now();
+        }
+
+        let t0 = Instant::now();
+        net.enable(client_name, true);
+        let x = 99;
+        let reply = block_on(async { client.handler2(&JunkArgs { x }).await.unwrap() });
+        assert_eq!(reply.x, format!("handler2-{}", x));
+        let dur = t0.elapsed();
+        assert!(
+            dur < Duration::from_millis(30),
+            "RPC took too long ({:?}) after Enable",
+            dur
+        );
+
+        // the delayed RPCs time out as soon as the client is enabled.
+        for _ in 0..nrpcs {
+            rx.recv_timeout(Duration::from_millis(50)).unwrap();
+        }
+
+        let len = junk_server.inner.lock().unwrap().log2.len();
+        assert_eq!(
+            len, 1,
+            "wrong number ({}) of RPCs delivered, expected 1",
+            len
+        );
+
+        let n = net.count(server.name());
+        assert_eq!(n, 1, "wrong count() {}, expected 1", n);
+    }
+
+    #[test]
+    fn test_bandwidth_limit() {
+        init_logger();
+
+        let net = Network::create_with_config(NetworkConfig {
+            reliable: true,
+            // a request of 3 bytes, e.g. `JunkArgs { x: 1000 }`, takes 0.1
+            // second.
+            bandwidth_limit_bps: Some(240),
+            ..Default::default()
+        });
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        net.add_server(builder.build());
+        let clients: Vec<_> = (0..2)
+            .map(|i| {
+                let name = format!("test_client{}", i);
+                let client = JunkClient::new(net.create_client(name.clone()));
+                net.connect(&name, "test_server");
+                net.enable(&name, true);
+                client
+            })
+            .collect();
+
+        // The two clients share the bandwidth, the first 10 RPCs go out
+        // immediately, the rest 10 RPCs have to wait for about a second.
+        let t0 = Instant::now();
+        for i in 0..20 {
+            let args = JunkArgs { x: 1000 + i };
+            block_on(async { clients[i as usize % 2].handler2(&args).await.unwrap() });
+        }
+        let dur = t0.elapsed();
+        assert!(
+            dur >= Duration::from_millis(900) && dur < Duration::from_secs(10),
+            "20 RPCs took {:?} with a bandwidth limit of 240 bps",
+            dur
+        );
+
+        // Removes the limit.
+        net.set_bandwidth_limit(None);
+        let t0 = Instant::now();
+        for i in 0..20 {
+            block_on(async { clients[0].handler2(&JunkArgs { x: i }).await.unwrap() });
+        }
+        let dur = t0.elapsed();
+        assert!(
+            dur < Duration::from_secs(1),
+            "RPCs took {:?} without limit",
+            dur
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        net.set_rate_limit(
+            "test_client",
+            RpcRate {
+                rpcs_per_sec: Some(10),
+                bytes_per_sec: None,
+            },
+        );
+
+        // The first 10 RPCs go out immediately, the rest 40 RPCs have to
+        // wait for about 4 seconds.
+        let t0 = Instant::now();
+        for i in 0..50 {
+            let reply = block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+            assert_eq!(reply.x, format!("handler2-{}", i));
+        }
+        let dur = t0.elapsed();
+        assert!(
+            dur >= Duration::from_millis(3800) && dur < Duration::from_secs(20),
+            "50 RPCs took {:?} with a rate limit of 10/sec",
+            dur
+        );
+
+        // Removes the limit.
+        net.set_rate_limit("test_client", RpcRate::default());
+        let t0 = Instant::now();
+        for i in 0..50 {
+            block_on(async { client.handler2(&JunkArgs { x: i }).await.unwrap() });
+        }
+        let dur = t0.elapsed();
+        assert!(
+            dur < Duration::from_secs(2),
+            "RPCs took {:?} without limit",
+            dur
+        );
+    }
+
+    #[test]
+    fn test_server_queue_depth() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let server_name = server.name();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", server_name);
+        net.enable("test_client", true);
+        net.set_server_queue_depth(server_name, 1);
+
+        // handler3 occupies the only slot for 20 seconds.
+        let cli = client.clone();
+        client.spawn(async move {
+            let _ = cli.handler3(&JunkArgs { x: 99 }).await;
+        });
+        let t0 = Instant::now();
+        while server.in_flight() == 0 {
+            assert!(
+                t0.elapsed() < Duration::from_secs(5),
+                "handler3 is not dispatched"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let t0 = Instant::now();
+        assert_eq!(
+            block_on(async { client.handler4(&JunkArgs::default()).await }),
+            Err(Error::Overloaded)
+        );
+        assert!(t0.elapsed() < Duration::from_secs(1));
+        assert_eq!(server.in_flight(), 1);
+
+        net.set_server_queue_depth(server_name, usize::MAX);
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(server.in_flight(), 1);
+
+        // the depth applies to the server replacing it.
+        net.set_server_queue_depth(server_name, 0);
+        let mut builder = ServerBuilder::new(server_name.to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        net.add_server(builder.build());
+        assert_eq!(
+            block_on(async { client.handler4(&JunkArgs::default()).await }),
+            Err(Error::Overloaded)
+        );
+    }
+
+    #[test]
+    fn test_link_latency() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let fast = JunkClient::new(net.create_client("fast_client".to_owned()));
+        net.connect("fast_client", server.name());
+        net.enable("fast_client", true);
+        let slow = JunkClient::new(net.create_client("slow_client".to_owned()));
+        net.connect("slow_client", server.name());
+        net.enable("slow_client", true);
+
+        let latency = Duration::from_millis(100);
+        net.set_link_latency("slow_client", latency);
+
+        let t0 = Instant::now();
+        block_on(async { fast.handler4(&JunkArgs::default()).await.unwrap() });
+        let fast_dur = t0.elapsed();
+        let t0 = Instant::now();
+        block_on(async { slow.handler4(&JunkArgs::default()).await.unwrap() });
+        let slow_dur = t0.elapsed();
+        assert!(
+            slow_dur >= fast_dur + 2 * latency,
+            "slow link {:?}, fast link {:?}",
+            slow_dur,
+            fast_dur
+        );
+
+        net.set_link_latency("slow_client", Duration::from_secs(0));
+        let t0 = Instant::now();
+        block_on(async { slow.handler4(&JunkArgs::default()).await.unwrap() });
+        assert!(t0.elapsed() < latency);
+    }
+
+    #[test]
+    fn test_rtt_model() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        net.set_seed(7);
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", server.name());
+        net.enable("test_client", true);
+        // the delays of 50 concurrent calls, sorted.
+        let delays = |client: &JunkClient| {
+            let calls = (0..50).map(|_| async move {
+                let t0 = Instant::now();
+                client.handler4(&JunkArgs::default()).await.unwrap();
+                t0.elapsed()
+            });
+            let mut delays = block_on(future::join_all(calls));
+            delays.sort_unstable();
+            delays
+        };
+        let ms = Duration::from_millis;
+
+        net.set_rtt_model("test_client", RttModel::Uniform { min: 100, max: 101 });
+        let uniform = delays(&client);
+        assert!(uniform[0] >= ms(100), "{:?}", uniform);
+
+        net.set_rtt_model(
+            "test_client",
+            RttModel::Normal {
+                mean: 50.0,
+                stddev: 5.0,
+            },
+        );
+        let normal = delays(&client);
+        let mean = normal.iter().sum::<Duration>() / normal.len() as u32;
+        assert!(normal[0] >= ms(30), "{:?}", normal);
+        assert!(mean >= ms(45) && mean < ms(80), "{:?}", normal);
+
+        net.set_rtt_model(
+            "test_client",
+            RttModel::LogNormal {
+                mu: 20f64.ln(),
+                sigma: 0.5,
+            },
+        );
+        let log_normal = delays(&client);
+        let median = log_normal[log_normal.len() / 2];
+        assert!(median >= ms(12) && median < ms(50), "{:?}", log_normal);
+
+        net.clear_rtt_model("test_client");
+        assert!(delays(&client)[0] < ms(10));
+        assert_eq!(RttModel::default(), RttModel::Uniform { min: 0, max: 27 });
+    }
+
+    #[test]
+    #[should_panic(expected = "negative deviation")]
+    fn test_rtt_model_negative_deviation() {
+        Network::new().set_rtt_model(
+            "test_client",
+            RttModel::Normal {
+                mean: 50.0,
+                stddev: -1.0,
+            },
+        );
+    }
+
+    // if an RPC is stuck in a server, and the server
+    // is killed with DeleteServer(), does the RPC
+    // get un-stuck?
+    #[test]
+    fn test_killed() {
+        init_logger();
+
+        let (net, server, _junk_server) = junk_suit();
+        let server_name = server.name();
+
+        let client_name = "client";
+        let client = JunkClient::new(net.create_client(client_name.to_owned()));
+        net.connect(client_name, server_name);
+        net.enable(client_name, true);
+        let (tx, rx) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            let reply = cli.handler3(&JunkArgs { x: 99 }).await;
+            tx.send(reply).unwrap();
+        });
+        // a handler stuck on its thread.
+        let (tx1, rx1) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            let reply = cli.handler7(&JunkArgs { x: 5000 }).await;
+            tx1.send(reply).unwrap();
+        });
+        thread::sleep(Duration::from_secs(1));
+        rx.recv_timeout(Duration::from_millis(100)).unwrap_err();
+        rx1.recv_timeout(Duration::from_millis(100)).unwrap_err();
+
+        let deleted = net.delete_server(server_name).unwrap();
+        assert_eq!(deleted.name(), server_name);
+        let reply = rx.recv_timeout(Duration::from_millis(200)).unwrap();
+        assert_eq!(reply, Err(Error::ServerKilled));
+        let reply = rx1.recv_timeout(Duration::from_millis(200)).unwrap();
+        assert_eq!(reply, Err(Error::ServerKilled));
+    }
+
+    #[test]
+    fn test_server_killed() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        let (tx, rx) = mpsc::channel();
+        let cli = client.clone();
+        client.spawn(async move {
+            tx.send(cli.handler5(&JunkArgs { x: 5000 }).await).unwrap();
+        });
+        while server.in_flight() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let start = Instant::now();
+        net.delete_server("test_server");
+        // not waiting for the next check of the dead server.
+        let res = rx.recv_timeout(Duration::from_millis(50)).unwrap();
+        assert_eq!(res, Err(Error::ServerKilled));
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // the server serves again once it is added back.
+        net.add_server(server);
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+    }
+
+    #[test]
+    fn test_delete_server() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let server_name = server.name();
+
+        assert!(net.delete_server("no_such_server").is_none());
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", server_name);
+        net.enable("test_client", true);
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+
+        let deleted = net.delete_server(server_name).unwrap();
+        assert_eq!(deleted.name(), server_name);
+        assert_eq!(deleted.count(), 1);
+        // it has been deleted already.
+        assert!(net.delete_server(server_name).is_none());
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap_err() });
+    }
+
+    #[test]
+    fn test_readd_server() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let server_name = server.name();
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", server_name);
+        net.enable("test_client", true);
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+
+        let mut builder = ServerBuilder::new(server_name.to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        let new_server = builder.build();
+        let old = net.add_server(new_server.clone()).unwrap();
+        assert_eq!(old.count(), 1);
+        // re-adding a deleted server replaces nothing.
+        net.delete_server(server_name).unwrap();
+        assert!(net.add_server(new_server.clone()).is_none());
+
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(new_server.count(), 1);
+        assert_eq!(server.count(), 1);
+    }
+
+    struct Hooks {
+        drop_req: AtomicBool,
+        drop_resp: AtomicBool,
+    }
+    impl RpcHooks for Hooks {
+        fn before_dispatch(&self, _: &str, _: &[u8]) -> Result<()> {
+            if self.drop_req.load(Ordering::Relaxed) {
+                Err(Error::Other("reqhook".to_owned()))
+            } else {
+                Ok(())
+            }
+        }
+        fn after_dispatch(&self, _: &str, resp: Result<Vec<u8>>) -> Result<Vec<u8>> {
+            if self.drop_resp.load(Ordering::Relaxed) {
+                Err(Error::Other("resphook".to_owned()))
+            } else {
+                resp
+            }
+        }
+    }
+
+    // Rejects every other request, and records the replies.
+    #[derive(Default)]
+    struct Alternate {
+        requests: AtomicUsize,
+        replies: Mutex<Vec<(String, Result<usize>)>>,
+    }
+    impl RpcHooks for Alternate {
+        fn before_dispatch(&self, _: &str, _: &[u8]) -> Result<()> {
+            if self.requests.fetch_add(1, Ordering::Relaxed) % 2 == 1 {
+                Err(Error::Other("rejected".to_owned()))
+            } else {
+                Ok(())
+            }
+        }
+        fn after_dispatch(&self, fq_name: &str, resp: Result<Vec<u8>>) -> Result<Vec<u8>> {
+            let len = resp.as_ref().map(Vec::len).map_err(Clone::clone);
+            self.replies.lock().unwrap().push((fq_name.to_owned(), len));
+            resp
+        }
+    }
+
+    #[test]
+    fn test_server_interceptor() {
+        init_logger();
+
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        let interceptor = Arc::new(Alternate::default());
+        builder.add_interceptor(interceptor.clone());
+        let server = builder.build();
+        let net = Network::new();
+        net.add_server(server.clone());
+
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for i in 0..6 {
+            let res = block_on(async { client.handler4(&JunkArgs::default()).await });
+            if i % 2 == 0 {
+                assert_eq!(res.unwrap().x, "pointer");
+            } else {
+                assert_eq!(res, Err(Error::Other("rejected".to_owned())));
+            }
         }
+        // rejected requests are counted as received.
+        assert_eq!(server.count(), 6);
+        assert_eq!(server.method_count("junk.handler4"), 6);
+        // only the dispatched requests are seen after dispatching.
+        let replies = interceptor.replies.lock().unwrap();
+        assert_eq!(replies.len(), 3);
+        for (fq_name, len) in replies.iter() {
+            assert_eq!(fq_name, "junk.handler4");
+            assert_eq!(*len, Ok(9));
+        }
+    }
 
-        let mut total = 0;
-        for _ in 0..nclients {
-            total += rx.recv().unwrap();
+    // Records the order in which the requests are dispatched.
+    #[derive(Default)]
+    struct DispatchOrder(Mutex<Vec<i64>>);
+    impl RpcHooks for DispatchOrder {
+        fn before_dispatch(&self, _: &str, req: &[u8]) -> Result<()> {
+            let args: JunkArgs = labcodec::decode(req).unwrap();
+            self.0.lock().unwrap().push(args.x);
+            Ok(())
+        }
+        fn after_dispatch(&self, _: &str, resp: Result<Vec<u8>>) -> Result<Vec<u8>> {
+            resp
+        }
+    }
+
+    // Records the maximum number of requests dispatched at the same time.
+    #[derive(Default)]
+    struct Parallelism {
+        current: AtomicUsize,
+        max: AtomicUsize,
+    }
+    impl RpcHooks for Parallelism {
+        fn before_dispatch(&self, _: &str, _: &[u8]) -> Result<()> {
+            let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max.fetch_max(current, Ordering::SeqCst);
+            Ok(())
+        }
+        fn after_dispatch(&self, _: &str, resp: Result<Vec<u8>>) -> Result<Vec<u8>> {
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            resp
+        }
+    }
+
+    // Records the requests and the replies passing through it.
+    struct Log {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+    impl Middleware for Log {
+        fn before(&self, fq_name: &str, _: &[u8]) -> Result<()> {
+            let entry = format!("{} before {}", self.name, fq_name);
+            self.log.lock().unwrap().push(entry);
+            Ok(())
+        }
+        fn after(&self, fq_name: &str, _: &[u8]) -> Result<()> {
+            let entry = format!("{} after {}", self.name, fq_name);
+            self.log.lock().unwrap().push(entry);
+            Ok(())
+        }
+    }
+
+    // Rejects negative arguments and replies longer than 12 bytes.
+    struct Validate;
+    impl Middleware for Validate {
+        fn before(&self, _: &str, req: &[u8]) -> Result<()> {
+            let args: JunkArgs = labcodec::decode(req).map_err(Error::Decode)?;
+            if args.x < 0 {
+                return Err(Error::Other(format!("negative {}", args.x)));
+            }
+            Ok(())
+        }
+        fn after(&self, _: &str, rsp: &[u8]) -> Result<()> {
+            if rsp.len() > 12 {
+                return Err(Error::MessageTooLarge);
+            }
+            Ok(())
         }
-        assert_eq!(total, nrpcs * nclients);
-        let n = net.count(server_name);
-        assert_eq!(n, total);
     }
 
     #[test]
-    fn test_unreliable() {
+    fn test_middleware() {
         init_logger();
 
-        let (net, server, _) = junk_suit();
-        let server_name = server.name();
-        net.set_reliable(false);
+        let log = Arc::new(Mutex::new(vec![]));
+        let junk_server = JunkService::new();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(junk_server.clone(), &mut builder).unwrap();
+        let server = builder.build_with_middleware(vec![
+            Box::new(Log {
+                name: "outer",
+                log: log.clone(),
+            }),
+            Box::new(Validate),
+            Box::new(Log {
+                name: "inner",
+                log: log.clone(),
+            }),
+        ]);
+        let net = Network::new();
+        net.add_server(server);
+        let client = JunkClient::connect(&net, "test_client".to_owned(), "test_server");
 
-        let pool = ThreadPool::new().unwrap();
-        let (tx, rx) = mpsc::channel::<usize>();
-        let nclients = 300;
-        for i in 0..nclients {
-            let sender = tx.clone();
-            let mut n = 0;
-            let server_name = server_name.to_owned();
-            let net = net.clone();
+        let reply = block_on(client.handler2(&JunkArgs { x: 1 })).unwrap();
+        assert_eq!(reply.x, "handler2-1");
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                "outer before junk.handler2",
+                "inner before junk.handler2",
+                "inner after junk.handler2",
+                "outer after junk.handler2",
+            ]
+        );
 
-            pool.spawn_ok(async move {
-                let client_name = format!("client-{}", i);
-                let client = JunkClient::new(net.create_client(client_name.clone()));
-                net.enable(&client_name, true);
-                net.connect(&client_name, &server_name);
+        // rejected before the handler.
+        let res = block_on(client.handler2(&JunkArgs { x: -1 }));
+        assert_eq!(res, Err(Error::Other("negative -1".to_owned())));
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            ["outer before junk.handler2"]
+        );
 
-                let x = i * 100;
-                if let Ok(reply) = client.handler2(&JunkArgs { x }).await {
-                    assert_eq!(reply.x, format!("handler2-{}", x));
-                    n += 1;
+        // rejected after the handler.
+        let res = block_on(client.handler2(&JunkArgs { x: 1000 }));
+        assert_eq!(res, Err(Error::MessageTooLarge));
+        assert_eq!(
+            log.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                "outer before junk.handler2",
+                "inner before junk.handler2",
+                "inner after junk.handler2",
+            ]
+        );
+        assert_eq!(junk_server.inner.lock().unwrap().log2, vec![1, 1000]);
+    }
+
+    #[test]
+    fn test_wait_until_stable() {
+        init_logger();
+
+        let (net, _, _) = junk_suit();
+        let client = net.create_client("test_client".to_owned());
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+        let leader = Arc::new(AtomicUsize::new(0));
+        let probe_leader = leader.clone();
+        net.register_stability_probe(Arc::new(move || {
+            format!("leader {}", probe_leader.load(Ordering::SeqCst))
+        }));
+        let quiesce = Duration::from_millis(200);
+        let timeout = Duration::from_secs(6);
+
+        // the leader changes for 300ms.
+        let start = Instant::now();
+        let changes = thread::spawn(move || {
+            for i in 1..=6 {
+                thread::sleep(Duration::from_millis(50));
+                leader.store(i, Ordering::SeqCst);
+            }
+        });
+        net.wait_until_stable("request_vote", quiesce, timeout)
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300) + quiesce);
+        changes.join().unwrap();
+
+        // elections go on for 300ms.
+        let start = Instant::now();
+        let cli = client.clone();
+        let elections = thread::spawn(move || {
+            let client = cli;
+            for _ in 0..6 {
+                thread::sleep(Duration::from_millis(50));
+                block_on(client.call_raw("junk.request_vote", vec![])).unwrap_err();
+            }
+        });
+        net.wait_until_stable("request_vote", quiesce, timeout)
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300) + quiesce);
+        elections.join().unwrap();
+
+        let start = Instant::now();
+        net.wait_until_stable("request_vote", quiesce, timeout)
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // RPCs to the other methods don't matter.
+        let start = Instant::now();
+        let others = thread::spawn(move || {
+            for _ in 0..6 {
+                thread::sleep(Duration::from_millis(50));
+                block_on(client.call_raw("junk.append_entries", vec![])).unwrap_err();
+            }
+        });
+        net.wait_until_stable("request_vote", quiesce, timeout)
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(300));
+        others.join().unwrap();
+
+        // a cluster that never settles.
+        let changes = AtomicUsize::new(0);
+        net.register_stability_probe(Arc::new(move || {
+            changes.fetch_add(1, Ordering::SeqCst).to_string()
+        }));
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            net.wait_until_stable("request_vote", quiesce, timeout),
+            Err(Error::Timeout)
+        );
+    }
+
+    #[test]
+    fn test_network_intercept() {
+        init_logger();
+
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        // a server replying stale reads of handler2.
+        net.intercept(
+            "test_client",
+            Arc::new(|rpc: &Rpc| {
+                assert_eq!(rpc.client_name(), "test_client");
+                if rpc.fq_name() != junk::method_names::HANDLER2 {
+                    return None;
                 }
-                sender.send(n).unwrap();
-            });
-        }
-        let mut total = 0;
-        for _ in 0..nclients {
-            total += rx.recv().unwrap();
-        }
-        assert!(
-            !(total == nclients as _ || total == 0),
-            "all RPCs succeeded despite unreliable total {}, nclients {}",
-            total,
-            nclients
+                let args: JunkArgs = labcodec::decode(rpc.req()).unwrap();
+                let reply = JunkReply {
+                    x: format!("stale-{}", args.x),
+                };
+                let mut buf = vec![];
+                labcodec::encode(&reply, &mut buf).unwrap();
+                Some(Ok(buf))
+            }),
         );
+        let reply = block_on(async { client.handler2(&JunkArgs { x: 3 }).await.unwrap() });
+        assert_eq!(reply.x, "stale-3");
+        assert_eq!(server.count(), 0);
+        let reply = block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert_eq!(reply.x, "pointer");
+        assert_eq!(server.count(), 1);
+
+        net.clear_intercept("test_client");
+        let reply = block_on(async { client.handler2(&JunkArgs { x: 3 }).await.unwrap() });
+        assert_eq!(reply.x, "handler2-3");
     }
 
-    // test concurrent RPCs from a single Client
     #[test]
-    fn test_concurrent_one() {
+    fn test_method_delay() {
         init_logger();
 
         let (net, server, junk_server) = junk_suit();
-        let server_name = server.name();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
 
-        let pool = ThreadPool::new().unwrap();
-        let (tx, rx) = mpsc::channel::<usize>();
-        let nrpcs = 20;
-        for i in 0..20 {
-            let sender = tx.clone();
-            let client_name = format!("client-{}", i);
-            let client = JunkClient::new(net.create_client(client_name.clone()));
-            net.enable(&client_name, true);
-            net.connect(&client_name, server_name);
+        server.set_method_delay("junk.handler2", Duration::from_millis(200));
+        // the client gives up after 50ms.
+        let res = block_on(async {
+            select! {
+                res = client.handler2(&JunkArgs { x: 1 }).fuse() => res,
+                _ = Delay::new(Duration::from_millis(50)).fuse() => Err(Error::Timeout),
+            }
+        });
+        assert_eq!(res, Err(Error::Timeout));
+        // other methods are not delayed.
+        let start = Instant::now();
+        block_on(async { client.handler4(&JunkArgs::default()).await.unwrap() });
+        assert!(start.elapsed() < Duration::from_millis(100));
+        // the handler still runs, once.
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(junk_server.inner.lock().unwrap().log2, vec![1]);
+
+        server.clear_method_delay("junk.handler2");
+        let start = Instant::now();
+        block_on(async { client.handler2(&JunkArgs { x: 2 }).await.unwrap() });
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
 
-            pool.spawn_ok(async move {
-                let mut n = 0;
-                let x = i + 100;
-                let reply = client.handler2(&JunkArgs { x }).await.unwrap();
-                assert_eq!(reply.x, format!("handler2-{}", x));
-                n += 1;
-                sender.send(n).unwrap()
-            });
-        }
+    #[test]
+    fn test_method_latency() {
+        init_logger();
 
-        let mut total = 0;
-        for _ in 0..nrpcs {
-            total += rx.recv().unwrap();
+        let (net, server, _) = junk_suit();
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        for x in [20, 5, 0] {
+            block_on(async { client.handler7(&JunkArgs { x }).await.unwrap() });
         }
+        let latency = server.method_latency("junk.handler7");
+        assert_eq!(latency.calls, 3);
+        assert_eq!(latency.calls, server.method_count("junk.handler7") as u64);
+        assert!(latency.max >= Duration::from_millis(20), "{:?}", latency);
+        assert!(latency.total >= Duration::from_millis(25), "{:?}", latency);
         assert_eq!(
-            total, nrpcs,
-            "wrong number of RPCs completed, got {}, expected {}",
-            total, nrpcs
+            server.method_latency("junk.handler2"),
+            MethodLatency::default()
         );
 
+        server.reset_count();
         assert_eq!(
-            junk_server.inner.lock().unwrap().log2.len(),
-            nrpcs,
-            "wrong number of RPCs delivered"
+            server.method_latency("junk.handler7"),
+            MethodLatency::default()
         );
 
-        let n = net.count(server.name());
-        assert_eq!(n, total, "wrong count() {}, expected {}", n, total);
+        let mut builder = ServerBuilder::new("test_server2".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        builder.disable_latency_stats();
+        let server = builder.build();
+        let ctx = RpcContext::new("test_client".to_owned(), 0);
+        let mut req = vec![];
+        labcodec::encode(&JunkArgs { x: 1 }, &mut req).unwrap();
+        block_on(async { server.dispatch(&ctx, "junk.handler7", &req).await.unwrap() });
+        assert_eq!(server.method_count("junk.handler7"), 1);
+        assert_eq!(
+            server.method_latency("junk.handler7"),
+            MethodLatency::default()
+        );
     }
 
-    // regression: an RPC that's delayed during Enabled=false
-    // should not delay subsequent RPCs (e.g. after Enabled=true).
     #[test]
-    fn test_regression1() {
+    fn test_max_concurrency() {
         init_logger();
 
-        let (net, server, junk_server) = junk_suit();
-        let server_name = server.name();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        let parallelism = Arc::new(Parallelism::default());
+        builder.add_interceptor(parallelism.clone());
+        builder.set_max_concurrency(2);
+        let server = builder.build();
+        let net = Network::new();
+        net.add_server(server.clone());
 
-        let client_name = "client";
-        let client = JunkClient::new(net.create_client(client_name.to_owned()));
-        net.connect(client_name, server_name);
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
 
-        // start some RPCs while the Client is disabled.
-        // they'll be delayed.
-        net.enable(client_name, false);
+        let n = 50;
+        let replies = block_on(future::join_all(
+            (0..n).map(|_| client.handler5(&JunkArgs { x: 10 })),
+        ));
+        assert!(replies.iter().all(|res| res.is_ok()), "{:?}", replies);
+        assert_eq!(parallelism.max.load(Ordering::SeqCst), 2);
 
-        let pool = ThreadPool::new().unwrap();
-        let (tx, rx) = mpsc::channel::<bool>();
-        let nrpcs = 20;
-        for i in 0..20 {
-            let sender = tx.clone();
+        // requests waiting for their turns fail once the server is killed.
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..3 {
             let cli = client.clone();
-            pool.spawn_ok(async move {
-                let x = i + 100;
-                // this call ought to return false.
-                let _ = cli.handler2(&JunkArgs { x });
-                sender.send(true).unwrap();
+            let tx = tx.clone();
+            client.spawn(async move {
+                tx.send(cli.handler5(&JunkArgs { x: 3000 }).await).unwrap();
             });
         }
-
-        // FIXME: have to sleep 300ms to pass the test
-        //        in my computer (i5-4200U, 4 threads).
-        thread::sleep(Duration::from_millis(100 * 3));
-
-        let t0 = Instant::now();
-        net.enable(client_name, true);
-        let x = 99;
-        let reply = block_on(async { client.handler2(&JunkArgs { x }).await.unwrap() });
-        assert_eq!(reply.x, format!("handler2-{}", x));
-        let dur = t0.elapsed();
-        assert!(
-            dur < Duration::from_millis(30),
-            "RPC took too long ({:?}) after Enable",
-            dur
-        );
-
-        for _ in 0..nrpcs {
-            rx.recv().unwrap();
+        while server.in_flight() < 3 {
+            thread::sleep(Duration::from_millis(1));
         }
+        net.delete_server("test_server");
+        for _ in 0..3 {
+            let res = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+            assert_eq!(res, Err(Error::ServerKilled));
+        }
+    }
 
-        let len = junk_server.inner.lock().unwrap().log2.len();
-        assert_eq!(
-            len, 1,
-            "wrong number ({}) of RPCs delivered, expected 1",
-            len
-        );
+    #[test]
+    fn test_limit_concurrency() {
+        init_logger();
 
-        let n = net.count(server.name());
-        assert_eq!(n, 1, "wrong count() {}, expected 1", n);
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        let parallelism = Arc::new(Parallelism::default());
+        builder.add_interceptor(parallelism.clone());
+        let net = Network::new();
+        net.add_server(builder.build());
+        net.limit_concurrency("test_server", 1);
+        let client = JunkClient::connect(&net, "test_client".to_owned(), "test_server");
+
+        let n = 20;
+        let replies = block_on(future::join_all(
+            (0..n).map(|_| client.handler5(&JunkArgs { x: 10 })),
+        ));
+        assert!(replies.iter().all(|res| res.is_ok()), "{:?}", replies);
+        assert_eq!(parallelism.max.load(Ordering::SeqCst), 1);
+
+        // the requests beyond the queue depth are rejected.
+        net.set_server_queue_depth("test_server", 3);
+        let replies = block_on(future::join_all(
+            (0..n).map(|_| client.handler5(&JunkArgs { x: 50 })),
+        ));
+        let overloaded = replies
+            .iter()
+            .filter(|res| **res == Err(Error::Overloaded))
+            .count();
+        assert!(overloaded > 0, "{:?}", replies);
+        assert_eq!(parallelism.max.load(Ordering::SeqCst), 1);
     }
 
-    // if an RPC is stuck in a server, and the server
-    // is killed with DeleteServer(), does the RPC
-    // get un-stuck?
     #[test]
-    fn test_killed() {
+    fn test_pause_server() {
         init_logger();
 
-        let (net, server, _junk_server) = junk_suit();
-        let server_name = server.name();
+        let mut builder = ServerBuilder::new("test_server".to_owned());
+        add_service(JunkService::new(), &mut builder).unwrap();
+        let order = Arc::new(DispatchOrder::default());
+        builder.add_interceptor(order.clone());
+        let server = builder.build();
+        let net = Network::new();
+        net.add_server(server.clone());
 
-        let client_name = "client";
-        let client = JunkClient::new(net.create_client(client_name.to_owned()));
-        net.connect(client_name, server_name);
-        net.enable(client_name, true);
+        let client = JunkClient::new(net.create_client("test_client".to_owned()));
+        net.connect("test_client", "test_server");
+        net.enable("test_client", true);
+
+        net.pause_server("test_server");
         let (tx, rx) = mpsc::channel();
+        for i in 0..5 {
+            let cli = client.clone();
+            let tx = tx.clone();
+            client.spawn(async move {
+                tx.send(cli.handler2(&JunkArgs { x: i }).await).unwrap();
+            });
+            // let it be parked before the next one.
+            thread::sleep(Duration::from_millis(20));
+        }
+        rx.recv_timeout(Duration::from_millis(100)).unwrap_err();
+        assert_eq!(net.total_count(), 5);
+        assert_eq!(server.count(), 0);
+
+        net.resume_server("test_server");
+        assert_eq!(*order.0.lock().unwrap(), vec![0, 1, 2, 3, 4]);
+        let mut replies: Vec<_> = (0..5)
+            .map(|_| rx.recv_timeout(Duration::from_secs(1)).unwrap().unwrap().x)
+            .collect();
+        replies.sort();
+        let expected: Vec<_> = (0..5).map(|i| format!("handler2-{}", i)).collect();
+        assert_eq!(replies, expected);
+
+        // RPCs parked on a deleted server fail.
+        net.pause_server("test_server");
         let cli = client.clone();
         client.spawn(async move {
-            let reply = cli.handler3(&JunkArgs { x: 99 }).await;
-            tx.send(reply).unwrap();
+            tx.send(cli.handler2(&JunkArgs { x: 5 }).await).unwrap();
         });
-        thread::sleep(Duration::from_secs(1));
-        rx.recv_timeout(Duration::from_millis(100)).unwrap_err();
-
-        net.delete_server(server_name);
-        let reply = rx.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert_eq!(reply, Err(Error::Stopped));
-    }
-
-    struct Hooks {
-        drop_req: AtomicBool,
-        drop_resp: AtomicBool,
-    }
-    impl RpcHooks for Hooks {
-        fn before_dispatch(&self, _: &str, _: &[u8]) -> Result<()> {
-            if self.drop_req.load(Ordering::Relaxed) {
-                Err(Error::Other("reqhook".to_owned()))
-            } else {
-                Ok(())
-            }
-        }
-        fn after_dispatch(&self, _: &str, resp: Result<Vec<u8>>) -> Result<Vec<u8>> {
-            if self.drop_resp.load(Ordering::Relaxed) {
-                Err(Error::Other("resphook".to_owned()))
-            } else {
-                resp
-            }
-        }
+        thread::sleep(Duration::from_millis(20));
+        net.delete_server("test_server");
+        let res = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(res, Err(Error::ServerKilled));
     }
 
     #[test]
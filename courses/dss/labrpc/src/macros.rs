@@ -1,103 +1,1084 @@
+/// Defines a service, its client and the function to register it.
+///
+/// A method declared as `rpc name(Args) returns (Reply) with ctx;` receives
+/// the [`RpcContext`](crate::RpcContext) of the request as the parameter
+/// `ctx` before the arguments.
+///
+/// The arguments and the reply can be left out, e.g. `rpc ping() returns
+/// ();`, the method takes no request and replies `()` then. An empty
+/// message is sent in their place.
+///
+/// The arguments and the reply can be any message type, e.g. a path
+/// `crate::msgs::Args` or a generic `Box<Args>`. The types are resolved in
+/// the module generated for the service, which sees all the names of the
+/// invoking module, but a path starting with `super` has to go one level
+/// further up.
+///
+/// A method declared as `rpc name(Args) returns stream Chunk;` streams its
+/// reply. The service sends the chunks through the
+/// [`ChunkSink`](crate::ChunkSink) passed as the parameter `sink` after the
+/// arguments, and the client gets a [`ChunkStream`](crate::ChunkStream)
+/// yielding them. The chunks are framed into one reply, which is sent when
+/// the method returns. A streaming method taking the context is declared
+/// as `returns stream (Chunk) with ctx;`.
+///
+/// A method declared as `rpc oneway name(Args);` replies nothing. Its client
+/// returns once the request is sent, see
+/// [`Client::notify`](crate::Client::notify).
+///
+/// A registered service is served by one instance, shared by all its
+/// methods and requests, so it need not be `Clone`. An `Arc` of a service,
+/// e.g. `Arc<dyn Service>`, is a service too, which lets the caller keep a
+/// handle to the instance it registers.
+///
+/// Several services can be defined in one invocation, each in its own
+/// module, together with `add_all_services` registering all of them.
+///
+/// A service declared as `service partial name { ... }` does not require
+/// every method to be implemented, the missing ones fail with
+/// `Error::Unimplemented`, or ignore the requests if they are oneway.
+///
+/// Every request and reply of the service can be held in its `Request` and
+/// `Response` enums, whose variants are named after the methods in camel
+/// case, e.g. `Request::RequestVote` for `request_vote`. They are `Debug`,
+/// and `Clone` too if the service is annotated with `#[derive(Clone)]`,
+/// which requires all the messages to be `Clone`.
+///
+/// The client of a method annotated with `#[timeout_ms = 200]` fails a call
+/// with `Error::Timeout` if there is no reply within 200ms, and the one of
+/// a method annotated with `#[retries = 3]` retries a call timing out up to
+/// 3 times, see [`CallPolicy`](crate::CallPolicy). Other attributes are
+/// copied to the generated methods as they are, so an unknown one fails to
+/// compile.
+///
+/// A client created by `Client::with_policy(end, retry)` retries every call
+/// failing with a retryable error as the [`RetryPolicy`](crate::RetryPolicy)
+/// says, after the retries of the method are used up.
+///
+/// A method cannot be declared twice, or named the same as its service:
+///
+/// ```compile_fail
+/// labrpc::service! {
+///     service kv {
+///         rpc get() returns ();
+///         rpc get() returns ();
+///     }
+/// }
+/// ```
+///
+/// ```compile_fail
+/// labrpc::service! {
+///     service get {
+///         rpc get() returns ();
+///     }
+/// }
+/// ```
 #[macro_export]
 macro_rules! service {
     () => {
         compile_error!("empty service is not allowed");
     };
     (
-        $(#[$service_attr:meta])*
+        $(#[$($service_attr:tt)*])*
+        service partial $svc_name:ident { $($body:tt)* }
+    ) => {
+        $crate::service! {
+            @attrs partial $svc_name { $($body)* } [] [] $(#[$($service_attr)*])*
+        }
+    };
+    (
+        $(#[$($service_attr:tt)*])*
+        service $svc_name:ident { $($body:tt)* }
+    ) => {
+        $crate::service! {
+            @attrs strict $svc_name { $($body)* } [] [] $(#[$($service_attr)*])*
+        }
+    };
+    // Separates `#[derive(Clone)]` of the `Request` and `Response` enums
+    // from the attributes of the module of the service, the state is
+    // [attributes] [derives].
+    (
+        @attrs $mode:ident $svc_name:ident { $($body:tt)* } [$($attrs:tt)*] [$($derives:tt)*]
+        #[derive(Clone)] $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @attrs $mode $svc_name { $($body)* } [$($attrs)*] [$($derives)* Clone] $($rest)*
+        }
+    };
+    (
+        @attrs $mode:ident $svc_name:ident { $($body:tt)* } [$($attrs:tt)*] [$($derives:tt)*]
+        #[$attr:meta] $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @attrs $mode $svc_name { $($body)* } [$($attrs)* #[$attr]] [$($derives)*] $($rest)*
+        }
+    };
+    (
+        @attrs $mode:ident $svc_name:ident { $($body:tt)* } [$($attrs:tt)*] [$($derives:tt)*]
+    ) => {
+        $crate::service! {
+            @munch $mode; [[$($attrs)*] [$($derives)*]] $svc_name;
+            [] [] [] [] [] [] [] $($body)*
+        }
+    };
+    // Separates the call policy of each method from its other attributes,
+    // the state is [methods done] [streaming methods done] [oneway methods
+    // done] [names of all the methods] [attributes] [timeout_ms] [retries].
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [] [$($retries:tt)*]
+        #[timeout_ms = $timeout_ms:literal] $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [$($done)*] [$($streams)*] [$($oneways)*] [$($names)*] [$($attrs)*] [$timeout_ms] [$($retries)*] $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [$($timeout_ms:tt)*] []
+        #[retries = $retries:literal] $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [$($done)*] [$($streams)*] [$($oneways)*] [$($names)*] [$($attrs)*] [$($timeout_ms)*] [$retries] $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [$($timeout_ms:tt)*] [$($retries:tt)*]
+        #[$attr:meta] $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [$($done)*] [$($streams)*] [$($oneways)*] [$($names)*] [$($attrs)* #[$attr]] [$($timeout_ms)*] [$($retries)*] $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [$($timeout_ms:tt)*] [$($retries:tt)*]
+        rpc $method_name:ident($($input:ty)?) returns ($($output:ty)?) $(with $ctx:ident)?;
+        $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [
+                $($done)*
+                [$($attrs)*] [$($timeout_ms)*] [$($retries)*]
+                rpc $method_name($($input)?) returns ($($output)?) $(with $ctx)?;
+            ]
+            [$($streams)*] [$($oneways)*] [$($names)* $method_name]
+            [] [] [] $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [$($timeout_ms:tt)*] [$($retries:tt)*]
+        rpc $method_name:ident($($input:ty)?) returns stream ($output:ty) $(with $ctx:ident)?;
+        $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [$($done)*]
+            [
+                $($streams)*
+                [$($attrs)*] [$($timeout_ms)*] [$($retries)*]
+                rpc $method_name($($input)?) returns stream ($output) $(with $ctx)?;
+            ]
+            [$($oneways)*] [$($names)* $method_name]
+            [] [] [] $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [] []
+        rpc oneway $method_name:ident($($input:ty)?) $(with $ctx:ident)?;
+        $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [$($done)*] [$($streams)*]
+            [
+                $($oneways)*
+                [$($attrs)*]
+                rpc $method_name($($input)?) $(with $ctx)?;
+            ]
+            [$($names)* $method_name]
+            [] [] [] $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [$($timeout_ms:tt)*] [$($retries:tt)*]
+        rpc oneway $method_name:ident $($rest:tt)*
+    ) => {
+        compile_error!(concat!(
+            "oneway ", stringify!($method_name), " has no reply to time out or retry"
+        ));
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [$($attrs:tt)*] [$($timeout_ms:tt)*] [$($retries:tt)*]
+        rpc $method_name:ident($($input:ty)?) returns stream $output:ty;
+        $($rest:tt)*
+    ) => {
+        $crate::service! {
+            @munch $mode; [$($service_attrs)*] $svc_name;
+            [$($done)*] [$($streams)*] [$($oneways)*] [$($names)*] [$($attrs)*] [$($timeout_ms)*] [$($retries)*]
+            rpc $method_name($($input)?) returns stream ($output);
+            $($rest)*
+        }
+    };
+    (
+        @munch $mode:ident; [$($service_attrs:tt)*] $svc_name:ident;
+        [$($done:tt)*] [$($streams:tt)*] [$($oneways:tt)*] [$($names:tt)*] [] [] []
+    ) => {
+        $crate::service! {
+            @mode $mode;
+            $($service_attrs)*
+            service $svc_name { $($done)* }
+            streams { $($streams)* }
+            oneways { $($oneways)* }
+            methods [$($names)*]
+        }
+    };
+    (
+        @mode $mode:ident;
+        [$(#[$service_attr:meta])*] [$($derive:ident)*]
         service $svc_name:ident {
             $(
-                $(#[$method_attr:meta])*
-                rpc $method_name:ident($input:ty) returns ($output:ty);
+                [$(#[$method_attr:meta])*] [$($timeout_ms:literal)?] [$($retries:literal)?]
+                rpc $method_name:ident($($input:ty)?) returns ($($output:ty)?) $(with $ctx:ident)?;
             )*
         }
+        streams {
+            $(
+                [$(#[$stream_attr:meta])*] [$($stream_timeout_ms:literal)?] [$($stream_retries:literal)?]
+                rpc $stream_name:ident($($stream_input:ty)?) returns stream ($stream_output:ty) $(with $stream_ctx:ident)?;
+            )*
+        }
+        oneways {
+            $(
+                [$(#[$oneway_attr:meta])*]
+                rpc $oneway_name:ident($($oneway_input:ty)?) $(with $oneway_ctx:ident)?;
+            )*
+        }
+        methods [$($all_names:ident)*]
     ) => {
         $(#[$service_attr])*
         pub mod $svc_name {
             // In order to find input and output.
+            #[allow(unused_imports)]
             use super::*;
             // $( use super::$input; )*
             // $( use super::$output;)*
 
-            extern crate futures as __futures;
+            // A method declared twice would shadow the other one when the
+            // service is registered, and one named after the service has a
+            // confusing full name, e.g. `get.get`.
+            const _: () = {
+                const NAMES: &[&str] = &[$(stringify!($all_names)),*];
+                $(
+                    if $crate::__private::count_name(NAMES, stringify!($all_names)) > 1 {
+                        panic!("{}", concat!(
+                            "rpc `", stringify!($all_names), "` is declared more than once in service `",
+                            stringify!($svc_name), "`"
+                        ));
+                    }
+                    if $crate::__private::str_eq(stringify!($all_names), stringify!($svc_name)) {
+                        panic!("{}", concat!(
+                            "rpc `", stringify!($all_names), "` is named the same as its service"
+                        ));
+                    }
+                )*
+            };
+
+            $crate::__private::paste! {
+                /// The full names of the methods, e.g. `service.method`, named
+                /// after the methods in upper case, e.g. `METHOD`.
+                pub mod method_names {
+                    $(
+                        #[doc = concat!("`", stringify!($svc_name), ".", stringify!($method_name), "`.")]
+                        pub const [<$method_name:upper>]: &str =
+                            concat!(stringify!($svc_name), ".", stringify!($method_name));
+                    )*
+                    $(
+                        #[doc = concat!("`", stringify!($svc_name), ".", stringify!($stream_name), "`.")]
+                        pub const [<$stream_name:upper>]: &str =
+                            concat!(stringify!($svc_name), ".", stringify!($stream_name));
+                    )*
+                    $(
+                        #[doc = concat!("`", stringify!($svc_name), ".", stringify!($oneway_name), "`.")]
+                        pub const [<$oneway_name:upper>]: &str =
+                            concat!(stringify!($svc_name), ".", stringify!($oneway_name));
+                    )*
+                }
+
+                /// The full names of all the methods, in the order they are
+                /// declared.
+                pub fn all_methods() -> &'static [&'static str] {
+                    &[$(method_names::[<$all_names:upper>]),*]
+                }
+
+                /// Any request to the service, e.g. to generate them in a
+                /// fuzzer and send them with `Client::call_dynamic`.
+                #[derive(Debug $(, $derive)*)]
+                pub enum Request {
+                    $(
+                        #[doc = concat!("A request to `", stringify!($svc_name), ".", stringify!($method_name), "`.")]
+                        [<$method_name:camel>]($crate::__or_unit!($($input)?)),
+                    )*
+                    $(
+                        #[doc = concat!("A request to `", stringify!($svc_name), ".", stringify!($stream_name), "`.")]
+                        [<$stream_name:camel>]($crate::__or_unit!($($stream_input)?)),
+                    )*
+                    $(
+                        #[doc = concat!("A request to `", stringify!($svc_name), ".", stringify!($oneway_name), "`.")]
+                        [<$oneway_name:camel>]($crate::__or_unit!($($oneway_input)?)),
+                    )*
+                }
+
+                impl Request {
+                    /// The full name of the method it is sent to.
+                    pub fn fq_name(&self) -> &'static str {
+                        match self {
+                            $(Request::[<$method_name:camel>](_) => method_names::[<$method_name:upper>],)*
+                            $(Request::[<$stream_name:camel>](_) => method_names::[<$stream_name:upper>],)*
+                            $(Request::[<$oneway_name:camel>](_) => method_names::[<$oneway_name:upper>],)*
+                        }
+                    }
+
+                    /// The encoded request.
+                    pub fn encode(&self) -> $crate::Result<Vec<u8>> {
+                        let mut buf = vec![];
+                        match self {
+                            $(Request::[<$method_name:camel>](req) => ::labcodec::encode(req, &mut buf),)*
+                            $(Request::[<$stream_name:camel>](req) => ::labcodec::encode(req, &mut buf),)*
+                            $(Request::[<$oneway_name:camel>](req) => ::labcodec::encode(req, &mut buf),)*
+                        }
+                        .map_err($crate::Error::Encode)?;
+                        Ok(buf)
+                    }
+                }
+
+                /// Any reply of the service, all the chunks of it for a
+                /// streaming method. The oneway methods reply nothing.
+                #[derive(Debug $(, $derive)*)]
+                pub enum Response {
+                    $(
+                        #[doc = concat!("A reply of `", stringify!($svc_name), ".", stringify!($method_name), "`.")]
+                        [<$method_name:camel>]($crate::__or_unit!($($output)?)),
+                    )*
+                    $(
+                        #[doc = concat!("The chunks of `", stringify!($svc_name), ".", stringify!($stream_name), "`.")]
+                        [<$stream_name:camel>](Vec<$stream_output>),
+                    )*
+                }
+
+                impl Response {
+                    /// Decodes the encoded reply of the method `fq_name`, as
+                    /// returned by `Request::fq_name`.
+                    pub fn decode(fq_name: &str, rsp: &[u8]) -> $crate::Result<Response> {
+                        match fq_name {
+                            $(method_names::[<$method_name:upper>] => ::labcodec::decode(rsp)
+                                .map(Response::[<$method_name:camel>])
+                                .map_err($crate::Error::Decode),)*
+                            $(method_names::[<$stream_name:upper>] => $crate::ChunkStream::new(rsp.to_vec())
+                                .collect::<$crate::Result<Vec<_>>>()
+                                .map(Response::[<$stream_name:camel>]),)*
+                            _ => Err($crate::Error::Unimplemented(format!(
+                                "unknown {} in {}",
+                                fq_name,
+                                stringify!($svc_name),
+                            ))),
+                        }
+                    }
+                }
+            }
 
-            #[async_trait::async_trait]
-            pub trait Service: Clone + Send + 'static {
+            $crate::__service_trait! {
+                $mode $svc_name;
                 $(
                     $(#[$method_attr])*
-                    async fn $method_name(&self, req: $input) -> $crate::Result<$output>;
+                    #[doc = ""]
+                    #[doc = concat!("Served as `", stringify!($svc_name), ".", stringify!($method_name), "`.")]
+                    fn $method_name(
+                        $($ctx: $crate::RpcContext,)?
+                        $(req: $input)?
+                    ) -> $crate::Result<$crate::__or_unit!($($output)?)>;
                 )*
+                $(
+                    $(#[$stream_attr])*
+                    #[doc = ""]
+                    #[doc = concat!("Streamed as `", stringify!($svc_name), ".", stringify!($stream_name), "`.")]
+                    fn $stream_name(
+                        $($stream_ctx: $crate::RpcContext,)?
+                        $(req: $stream_input,)?
+                        sink: $crate::ChunkSink<$stream_output>
+                    ) -> $crate::Result<()>;
+                )*
+                oneway {
+                    $(
+                        $(#[$oneway_attr])*
+                        #[doc = ""]
+                        #[doc = concat!("Notified as `", stringify!($svc_name), ".", stringify!($oneway_name), "`, replying nothing.")]
+                        fn $oneway_name(
+                            $($oneway_ctx: $crate::RpcContext,)?
+                            $(req: $oneway_input)?
+                        );
+                    )*
+                }
             }
 
+            /// A shared service, e.g. `Arc<dyn Service>`, or one whose state
+            /// the caller keeps a handle to.
+            #[$crate::__private::async_trait]
+            impl<S: Service + ?Sized> Service for ::std::sync::Arc<S> {
+                $(async fn $method_name(
+                    &self,
+                    $($ctx: $crate::RpcContext,)?
+                    $(req: $input)?
+                ) -> $crate::Result<$crate::__or_unit!($($output)?)> {
+                    (**self).$method_name($($ctx,)? $({ let req: $input = req; req })?).await
+                })*
+                $(async fn $stream_name(
+                    &self,
+                    $($stream_ctx: $crate::RpcContext,)?
+                    $(req: $stream_input,)?
+                    sink: $crate::ChunkSink<$stream_output>
+                ) -> $crate::Result<()> {
+                    (**self).$stream_name(
+                        $($stream_ctx,)?
+                        $({ let req: $stream_input = req; req },)?
+                        sink,
+                    ).await
+                })*
+                $(async fn $oneway_name(
+                    &self,
+                    $($oneway_ctx: $crate::RpcContext,)?
+                    $(req: $oneway_input)?
+                ) {
+                    (**self).$oneway_name($($oneway_ctx,)? $({ let req: $oneway_input = req; req })?).await
+                })*
+            }
+
+            /// The client of the service.
             #[derive(Clone)]
             pub struct Client {
                 client: $crate::Client,
+                // the name the service is registered under
+                prefix: ::std::borrow::Cow<'static, str>,
+                // how every call is retried
+                retry: $crate::RetryPolicy,
+            }
+
+            impl ::std::fmt::Debug for Client {
+                fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                    f.debug_struct("Client")
+                        .field("service", &self.prefix)
+                        .field("end_name", &self.end_name())
+                        .finish()
+                }
             }
             impl Client {
+                /// Calls the service through `client`.
                 pub fn new(client: $crate::Client) -> Client {
-                    Client { client }
+                    Client::with_prefix(client, stringify!($svc_name))
                 }
 
+                /// Calls the service registered under `prefix` through
+                /// `client`, see `add_service_with_prefix`.
+                pub fn with_prefix(
+                    client: $crate::Client,
+                    prefix: impl Into<::std::borrow::Cow<'static, str>>,
+                ) -> Client {
+                    Client {
+                        client,
+                        prefix: prefix.into(),
+                        retry: $crate::RetryPolicy::default(),
+                    }
+                }
+
+                /// Calls the service through `client`, retrying every call
+                /// failing with a retryable error as `retry` says, see
+                /// `RetryPolicy`.
+                pub fn with_policy(client: $crate::Client, retry: $crate::RetryPolicy) -> Client {
+                    Client {
+                        retry,
+                        ..Client::new(client)
+                    }
+                }
+
+                /// Creates an end named `end_name` on `net`, connects it to
+                /// `server_name` and enables it, so the client is ready to
+                /// use.
+                pub fn connect(
+                    net: &$crate::Network,
+                    end_name: String,
+                    server_name: &str,
+                ) -> Client {
+                    let client = net.create_client(end_name);
+                    net.connect(client.name(), server_name);
+                    net.enable(client.name(), true);
+                    Client::new(client)
+                }
+
+                /// The name of the end it calls through.
+                pub fn end_name(&self) -> &str {
+                    self.client.name()
+                }
+
+                /// Runs `f` on the worker pool of the client.
                 pub fn spawn<F>(&self, f: F)
-                where F: __futures::Future<Output = ()> + Send + 'static
+                where F: $crate::__private::futures::Future<Output = ()> + Send + 'static
                 {
                     self.client.worker.spawn_ok(f);
                 }
 
-                $(pub fn $method_name(&self, args: &$input) -> $crate::RpcFuture<$crate::Result<$output>> {
-                    let fq_name = concat!(stringify!($svc_name), ".", stringify!($method_name));
-                    self.client.call(fq_name, args)
+                /// Sends an encoded request to `fq_name` and returns the encoded
+                /// reply, bypassing the protobuf encoding and decoding.
+                pub fn call_dynamic(
+                    &self,
+                    fq_name: impl Into<::std::borrow::Cow<'static, str>>,
+                    req: &[u8],
+                ) -> $crate::RpcFuture<$crate::Result<Vec<u8>>> {
+                    self.client.call_raw(fq_name, req.to_vec())
+                }
+
+                // The fq_name of a method under the prefix.
+                fn prefixed(&self, fq_name: &'static str) -> ::std::borrow::Cow<'static, str> {
+                    if self.prefix == stringify!($svc_name) {
+                        return fq_name.into();
+                    }
+                    let method_name = &fq_name[stringify!($svc_name).len() + 1..];
+                    format!("{}.{}", self.prefix, method_name).into()
+                }
+
+                $(
+                $(#[$method_attr])*
+                #[doc = ""]
+                #[doc = concat!("Calls `", stringify!($svc_name), ".", stringify!($method_name), "`.")]
+                pub fn $method_name(
+                    &self,
+                    $(args: &$input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::__or_unit!($($output)?)>> {
+                    self.client.call_with_policy(
+                        self.prefixed($crate::__private::paste!(method_names::[<$method_name:upper>])),
+                        $crate::__or_unit!(@args $(args: $input)?),
+                        $crate::CallPolicy::default()
+                            $(.timeout_ms($timeout_ms))?
+                            $(.retries($retries))?
+                            .retry_policy(self.retry),
+                    )
+                })*
+
+                $(
+                $(#[$stream_attr])*
+                #[doc = ""]
+                #[doc = concat!("Calls `", stringify!($svc_name), ".", stringify!($stream_name), "`, whose chunks are")]
+                #[doc = "decoded as the stream is iterated."]
+                pub fn $stream_name(
+                    &self,
+                    $(args: &$stream_input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::ChunkStream<$stream_output>>> {
+                    self.client.call_stream(
+                        self.prefixed($crate::__private::paste!(method_names::[<$stream_name:upper>])),
+                        $crate::__or_unit!(@args $(args: $stream_input)?),
+                        $crate::CallPolicy::default()
+                            $(.timeout_ms($stream_timeout_ms))?
+                            $(.retries($stream_retries))?
+                            .retry_policy(self.retry),
+                    )
+                })*
+
+                $(
+                $(#[$oneway_attr])*
+                #[doc = ""]
+                #[doc = concat!("Notifies `", stringify!($svc_name), ".", stringify!($oneway_name), "`, returning once")]
+                #[doc = "the request is sent."]
+                pub fn $oneway_name(&self, $(args: &$oneway_input)?) -> $crate::Result<()> {
+                    self.client.notify(
+                        self.prefixed($crate::__private::paste!(method_names::[<$oneway_name:upper>])),
+                        $crate::__or_unit!(@args $(args: $oneway_input)?),
+                    )
+                })*
+            }
+
+            /// Calls the service, implemented by both `Client` and
+            /// `MockClient`, so that the callers can be tested without a
+            /// network.
+            pub trait Caller: Clone + Send + Sync + 'static {
+                $(
+                $(#[$method_attr])*
+                fn $method_name(
+                    &self,
+                    $(args: &$input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::__or_unit!($($output)?)>>;
+                )*
+                $(
+                $(#[$stream_attr])*
+                fn $stream_name(
+                    &self,
+                    $(args: &$stream_input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::ChunkStream<$stream_output>>>;
+                )*
+                $(
+                $(#[$oneway_attr])*
+                fn $oneway_name(&self, $(args: &$oneway_input)?) -> $crate::Result<()>;
+                )*
+            }
+
+            impl Caller for Client {
+                $(fn $method_name(
+                    &self,
+                    $(args: &$input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::__or_unit!($($output)?)>> {
+                    Client::$method_name(self, $({ let args: &$input = args; args })?)
+                })*
+                $(fn $stream_name(
+                    &self,
+                    $(args: &$stream_input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::ChunkStream<$stream_output>>> {
+                    Client::$stream_name(self, $({ let args: &$stream_input = args; args })?)
+                })*
+                $(fn $oneway_name(&self, $(args: &$oneway_input)?) -> $crate::Result<()> {
+                    Client::$oneway_name(self, $({ let args: &$oneway_input = args; args })?)
+                })*
+            }
+
+            /// A client replying with the closures set by `on_<method>`
+            /// instead of calling a server, it records the calls made through
+            /// it and its clones.
+            #[derive(Clone, Default)]
+            pub struct MockClient {
+                // method name -> reply
+                replies: ::std::sync::Arc<::std::sync::Mutex<::std::collections::HashMap<
+                    &'static str,
+                    ::std::sync::Arc<dyn Fn(&[u8]) -> $crate::Result<Vec<u8>> + Send + Sync>,
+                >>>,
+                // (method name, encoded request)
+                calls: ::std::sync::Arc<::std::sync::Mutex<Vec<(&'static str, Vec<u8>)>>>,
+            }
+
+            impl MockClient {
+                /// Creates a client failing every call with
+                /// `Error::Unimplemented` until the replies are set.
+                pub fn new() -> MockClient {
+                    MockClient::default()
+                }
+
+                $crate::__private::paste! {
+                    $(
+                    #[doc = concat!("Replies the calls to `", stringify!($method_name), "` with `f`, e.g.")]
+                    #[doc = concat!("`mock.on_", stringify!($method_name), "(|args| Ok(Default::default()))`.")]
+                    pub fn [<on_ $method_name>]<F>(&self, f: F)
+                    where
+                        F: Fn($crate::__or_unit!($($input)?)) -> $crate::Result<$crate::__or_unit!($($output)?)>
+                            + Send
+                            + Sync
+                            + 'static,
+                    {
+                        self.reply_with(stringify!($method_name), f)
+                    })*
+
+                    $(
+                    #[doc = concat!("Replies the calls to the streaming `", stringify!($stream_name), "` with the")]
+                    #[doc = "chunks returned by `f`."]
+                    pub fn [<on_ $stream_name>]<F>(&self, f: F)
+                    where
+                        F: Fn($crate::__or_unit!($($stream_input)?)) -> $crate::Result<Vec<$stream_output>>
+                            + Send
+                            + Sync
+                            + 'static,
+                    {
+                        self.stream_with(stringify!($stream_name), f)
+                    })*
+                }
+
+                // Some services have no unary methods.
+                #[allow(dead_code)]
+                fn reply_with<Req, Rsp, F>(&self, method: &'static str, f: F)
+                where
+                    Req: ::labcodec::Message,
+                    Rsp: ::labcodec::Message,
+                    F: Fn(Req) -> $crate::Result<Rsp> + Send + Sync + 'static,
+                {
+                    let reply = move |req: &[u8]| {
+                        let req = ::labcodec::decode(req).map_err($crate::Error::Decode)?;
+                        let rsp = f(req)?;
+                        let mut buf = vec![];
+                        ::labcodec::encode(&rsp, &mut buf).map_err($crate::Error::Encode)?;
+                        Ok(buf)
+                    };
+                    self.replies
+                        .lock()
+                        .unwrap()
+                        .insert(method, ::std::sync::Arc::new(reply));
+                }
+
+                // Some services have no streaming methods.
+                #[allow(dead_code)]
+                fn stream_with<Req, Rsp, F>(&self, method: &'static str, f: F)
+                where
+                    Req: ::labcodec::Message,
+                    Rsp: ::labcodec::Message,
+                    F: Fn(Req) -> $crate::Result<Vec<Rsp>> + Send + Sync + 'static,
+                {
+                    let reply = move |req: &[u8]| {
+                        let req = ::labcodec::decode(req).map_err($crate::Error::Decode)?;
+                        let sink = $crate::ChunkSink::new();
+                        for chunk in f(req)? {
+                            sink.send(&chunk)?;
+                        }
+                        Ok(sink.finish())
+                    };
+                    self.replies
+                        .lock()
+                        .unwrap()
+                        .insert(method, ::std::sync::Arc::new(reply));
+                }
+
+                /// The method names and the encoded requests of the calls
+                /// made so far, in order.
+                pub fn calls(&self) -> Vec<(&'static str, Vec<u8>)> {
+                    self.calls.lock().unwrap().clone()
+                }
+
+                /// The requests of the calls to `method` made so far, in order.
+                pub fn requests<Req: ::labcodec::Message>(&self, method: &str) -> Vec<Req> {
+                    self.calls
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|(name, _)| *name == method)
+                        .map(|(_, req)| ::labcodec::decode(req).unwrap())
+                        .collect()
+                }
+
+                fn call<Req, Rsp>(
+                    &self,
+                    method: &'static str,
+                    req: &Req,
+                ) -> $crate::RpcFuture<$crate::Result<Rsp>>
+                where
+                    Req: ::labcodec::Message,
+                    Rsp: ::labcodec::Message + 'static,
+                {
+                    let res = self
+                        .call_raw(method, req)
+                        .and_then(|rsp| ::labcodec::decode(&rsp).map_err($crate::Error::Decode));
+                    Box::pin($crate::__private::futures::future::ready(res))
+                }
+
+                // Some services have no streaming methods.
+                #[allow(dead_code)]
+                fn call_stream<Req, Rsp>(
+                    &self,
+                    method: &'static str,
+                    req: &Req,
+                ) -> $crate::RpcFuture<$crate::Result<$crate::ChunkStream<Rsp>>>
+                where
+                    Req: ::labcodec::Message,
+                    Rsp: ::labcodec::Message + 'static,
+                {
+                    let res = self.call_raw(method, req).map($crate::ChunkStream::new);
+                    Box::pin($crate::__private::futures::future::ready(res))
+                }
+
+                // Some services have no oneway methods.
+                #[allow(dead_code)]
+                fn notify<Req: ::labcodec::Message>(
+                    &self,
+                    method: &'static str,
+                    req: &Req,
+                ) -> $crate::Result<()> {
+                    let mut buf = vec![];
+                    ::labcodec::encode(req, &mut buf).map_err($crate::Error::Encode)?;
+                    self.calls.lock().unwrap().push((method, buf));
+                    Ok(())
+                }
+
+                fn call_raw<Req: ::labcodec::Message>(
+                    &self,
+                    method: &'static str,
+                    req: &Req,
+                ) -> $crate::Result<Vec<u8>> {
+                    let mut buf = vec![];
+                    ::labcodec::encode(req, &mut buf).map_err($crate::Error::Encode)?;
+                    self.calls.lock().unwrap().push((method, buf.clone()));
+                    let reply = self.replies.lock().unwrap().get(method).cloned();
+                    match reply {
+                        Some(reply) => reply(&buf),
+                        None => Err($crate::Error::Unimplemented(format!(
+                            "no reply of {} in {}",
+                            method,
+                            stringify!($svc_name),
+                        ))),
+                    }
+                }
+            }
+
+            impl Caller for MockClient {
+                $(fn $method_name(
+                    &self,
+                    $(args: &$input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::__or_unit!($($output)?)>> {
+                    self.call(stringify!($method_name), $crate::__or_unit!(@args $(args: $input)?))
+                })*
+                $(fn $stream_name(
+                    &self,
+                    $(args: &$stream_input)?
+                ) -> $crate::RpcFuture<$crate::Result<$crate::ChunkStream<$stream_output>>> {
+                    self.call_stream(
+                        stringify!($stream_name),
+                        $crate::__or_unit!(@args $(args: $stream_input)?),
+                    )
+                })*
+                $(fn $oneway_name(&self, $(args: &$oneway_input)?) -> $crate::Result<()> {
+                    self.notify(
+                        stringify!($oneway_name),
+                        $crate::__or_unit!(@args $(args: $oneway_input)?),
+                    )
                 })*
             }
 
+            /// Registers the service, fails if a service of the same name has
+            /// already registered.
             pub fn add_service<T: Service>(svc: T, builder: &mut $crate::ServerBuilder) -> $crate::Result<()> {
-                use ::std::sync::Mutex;
+                builder.add_service(stringify!($svc_name), new_factory(svc))
+            }
+
+            /// Registers the service under `prefix` instead of its name, e.g.
+            /// to serve two instances of it on one server. The methods are
+            /// called as `prefix.method` then, see `Client::with_prefix`.
+            pub fn add_service_with_prefix<T: Service>(
+                svc: T,
+                builder: &mut $crate::ServerBuilder,
+                prefix: &str,
+            ) -> $crate::Result<()> {
+                builder.add_service(prefix.to_owned(), new_factory(svc))
+            }
+
+            /// Registers the service on a running server, fails if a service
+            /// of the same name has already registered.
+            pub fn add_service_live<T: Service>(svc: T, server: &$crate::Server) -> $crate::Result<()> {
+                server.add_service(stringify!($svc_name), new_factory(svc))
+            }
+
+            /// Registers the service, replacing the registered one of the same
+            /// name if any. Returns whether a service was replaced.
+            pub fn replace_service<T: Service>(svc: T, builder: &mut $crate::ServerBuilder) -> bool {
+                builder.replace_service(stringify!($svc_name), new_factory(svc))
+            }
+
+            /// Registers the service on a running server, replacing the
+            /// registered one of the same name if any. Returns whether a
+            /// service was replaced.
+            pub fn replace_service_live<T: Service>(svc: T, server: &$crate::Server) -> bool {
+                server.replace_service(stringify!($svc_name), new_factory(svc))
+            }
+
+            /// Creates the handlers of the service, which can be registered
+            /// under a name computed at runtime with
+            /// [`ServerBuilder::add_service`]($crate::ServerBuilder::add_service).
+            pub fn new_factory<T: Service>(svc: T) -> Box<dyn $crate::HandlerFactory> {
+                use ::std::sync::Arc;
+                // every request is served by the same instance.
                 struct Factory<S> {
-                    svc: Mutex<S>,
+                    svc: Arc<S>,
                 }
                 impl<S: Service> $crate::HandlerFactory for Factory<S> {
-                    fn handler(&self, name: &'static str) -> Box<$crate::Handler> {
-                        let s = self.svc.lock().unwrap().clone();
-                        Box::new(move |req| {
-                            match name {
-                                $(stringify!($method_name) => {
-                                    let request = match labcodec::decode(req) {
-                                        Ok(req) => req,
-                                        Err(e) => return Box::pin(__futures::future::err(
-                                            $crate::Error::Decode(e)
-                                        )),
-                                    };
-                                    Box::pin(async move {
-                                        let f = s.$method_name(request);
-                                        let resp = f.await;
-                                        match resp {
-                                            Ok(resp) => {
-                                                let mut rsp = vec![];
-                                                labcodec::encode(&resp, &mut rsp).map_err($crate::Error::Encode)?;
-                                                Ok(rsp)
-                                            }
-                                            Err(e) => Err(e),
+                    fn handler(&self, name: &str) -> Box<$crate::Handler> {
+                        let s = self.svc.clone();
+                        match name {
+                            $(stringify!($method_name) => Box::new(move |ctx, req| {
+                                // not every method takes the context.
+                                let _ = ctx;
+                                let request: $crate::__or_unit!($($input)?) = match ::labcodec::decode(req) {
+                                    Ok(req) => req,
+                                    Err(e) => return Box::pin($crate::__private::futures::future::err(
+                                        $crate::Error::Decode(e)
+                                    )),
+                                };
+                                // not every method takes the request.
+                                let _ = &request;
+                                $(let $ctx = ctx.clone();)?
+                                Box::pin(async move {
+                                    let f = s.$method_name(
+                                        $($ctx,)?
+                                        $({ let request: $input = request; request })?
+                                    );
+                                    let resp = f.await;
+                                    match resp {
+                                        Ok(resp) => {
+                                            let mut rsp = vec![];
+                                            ::labcodec::encode(&resp, &mut rsp).map_err($crate::Error::Encode)?;
+                                            Ok(rsp)
                                         }
-                                    })
-                                })*
-                                other => {
-                                    Box::pin(__futures::future::err(
-                                        $crate::Error::Unimplemented(
-                                            format!("unknown {} in {}", other, stringify!($svc_name))
-                                        )
-                                    ))
-                                }
+                                        Err(e) => Err(e),
+                                    }
+                                })
+                            }),)*
+                            $(stringify!($stream_name) => Box::new(move |ctx, req| {
+                                let _ = ctx;
+                                let request: $crate::__or_unit!($($stream_input)?) = match ::labcodec::decode(req) {
+                                    Ok(req) => req,
+                                    Err(e) => return Box::pin($crate::__private::futures::future::err(
+                                        $crate::Error::Decode(e)
+                                    )),
+                                };
+                                let _ = &request;
+                                $(let $stream_ctx = ctx.clone();)?
+                                Box::pin(async move {
+                                    // the chunks are framed into one reply
+                                    // once the method returns.
+                                    let sink = $crate::ChunkSink::<$stream_output>::new();
+                                    s.$stream_name(
+                                        $($stream_ctx,)?
+                                        $({ let request: $stream_input = request; request },)?
+                                        sink.clone(),
+                                    ).await?;
+                                    Ok(sink.finish())
+                                })
+                            }),)*
+                            $(stringify!($oneway_name) => Box::new(move |ctx, req| {
+                                let _ = ctx;
+                                let request: $crate::__or_unit!($($oneway_input)?) = match ::labcodec::decode(req) {
+                                    Ok(req) => req,
+                                    Err(e) => return Box::pin($crate::__private::futures::future::err(
+                                        $crate::Error::Decode(e)
+                                    )),
+                                };
+                                let _ = &request;
+                                $(let $oneway_ctx = ctx.clone();)?
+                                Box::pin(async move {
+                                    s.$oneway_name(
+                                        $($oneway_ctx,)?
+                                        $({ let request: $oneway_input = request; request })?
+                                    ).await;
+                                    // nothing is replied, the empty reply
+                                    // allocates nothing.
+                                    Ok(Vec::new())
+                                })
+                            }),)*
+                            other => {
+                                let msg = format!("unknown {} in {}", other, stringify!($svc_name));
+                                Box::new(move |_, _| {
+                                    Box::pin($crate::__private::futures::future::err($crate::Error::Unimplemented(msg)))
+                                })
                             }
-                        })
+                        }
                     }
-                }
 
-                let fact = Factory {
-                    svc: Mutex::new(svc),
-                };
+                    fn method_names(&self) -> &'static [&'static str] {
+                        &[$(stringify!($all_names)),*]
+                    }
+                }
 
-                builder.add_service(stringify!($svc_name), Box::new(fact))
+                Box::new(Factory { svc: Arc::new(svc) })
             }
         }
     };
+    (
+        $(#[$($first_attr:tt)*])*
+        service $first:ident { $($first_body:tt)* }
+        $(
+            $(#[$($service_attr:tt)*])*
+            service $svc_name:ident { $($body:tt)* }
+        )+
+    ) => {
+        $crate::service! {
+            $(#[$($first_attr)*])*
+            service $first { $($first_body)* }
+        }
+        $(
+            $crate::service! {
+                $(#[$($service_attr)*])*
+                service $svc_name { $($body)* }
+            }
+        )+
+
+        /// Registers all the services defined together, fails if any of them
+        /// has already registered.
+        pub fn add_all_services(
+            $first: impl $first::Service,
+            $($svc_name: impl $svc_name::Service,)+
+            builder: &mut $crate::ServerBuilder,
+        ) -> $crate::Result<()> {
+            $first::add_service($first, builder)?;
+            $($svc_name::add_service($svc_name, builder)?;)+
+            Ok(())
+        }
+    };
+}
+
+/// The `Service` trait of a service, whose methods are all required if the
+/// service is strict, or fail with `Error::Unimplemented` by default if it
+/// is partial.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __service_trait {
+    (
+        strict $svc_name:ident;
+        $($(#[$method_attr:meta])* fn $method_name:ident($($params:tt)*) -> $output:ty;)*
+        oneway {
+            $($(#[$oneway_attr:meta])* fn $oneway_name:ident($($oneway_params:tt)*);)*
+        }
+    ) => {
+        /// The service, implemented by the server.
+        #[$crate::__private::async_trait]
+        pub trait Service: Send + Sync + 'static {
+            $($(#[$method_attr])* async fn $method_name(&self, $($params)*) -> $output;)*
+            $($(#[$oneway_attr])* async fn $oneway_name(&self, $($oneway_params)*);)*
+        }
+    };
+    (
+        partial $svc_name:ident;
+        $($(#[$method_attr:meta])* fn $method_name:ident($($params:tt)*) -> $output:ty;)*
+        oneway {
+            $($(#[$oneway_attr:meta])* fn $oneway_name:ident($($oneway_params:tt)*);)*
+        }
+    ) => {
+        /// The service, implemented by the server. The methods not
+        /// implemented fail with `Error::Unimplemented`, and the oneway
+        /// ones ignore the requests.
+        #[$crate::__private::async_trait]
+        pub trait Service: Send + Sync + 'static {
+            $(
+                $(#[$method_attr])*
+                #[allow(unused_variables)]
+                async fn $method_name(&self, $($params)*) -> $output {
+                    Err($crate::Error::Unimplemented(format!(
+                        "{}.{} is not implemented",
+                        stringify!($svc_name),
+                        stringify!($method_name),
+                    )))
+                }
+            )*
+            $(
+                $(#[$oneway_attr])*
+                #[allow(unused_variables)]
+                async fn $oneway_name(&self, $($oneway_params)*) {}
+            )*
+        }
+    };
+}
+
+/// The type given, or `()` if there is none. With `@args`, the arguments of
+/// a client method, or an empty message if it takes none.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __or_unit {
+    () => {
+        ()
+    };
+    ($ty:ty) => {
+        $ty
+    };
+    (@args) => {
+        &()
+    };
+    (@args $args:ident: $ty:ty) => {
+        $args
+    };
 }
@@ -1,30 +1,275 @@
 use std::collections::HashMap;
 use std::future::Future;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
-use futures::executor::ThreadPool;
+use futures::channel::oneshot;
+use futures::executor::{block_on, ThreadPool};
 use futures::future::FutureExt;
 use futures::select;
 use futures::stream::StreamExt;
 use futures_timer::Delay;
-use log::{debug, error};
-use rand::{thread_rng, Rng};
+use log::{debug, error, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, LogNormal, Normal};
 
 use crate::client::{Client, Rpc};
 use crate::error::{Error, Result};
-use crate::server::Server;
+use crate::server::{RpcContext, Semaphore, Server};
+
+// How often `wait_until_stable` checks the cluster.
+const STABILITY_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 #[derive(Debug)]
 struct EndInfo {
     enabled: bool,
     reliable: bool,
+    long_delays: bool,
     long_reordering: bool,
+    min_reorder_delay: u64,
+    max_reorder_delay: u64,
+    max_message_size: usize,
+    // probability of a random fault, see `Network::chaos_mode`
+    chaos: Option<f64>,
+    // one-way latency of the link
+    latency: Duration,
+    // the delay of the requests, see `Network::set_rtt_model`
+    rtt_model: Option<RttModel>,
     server: Option<Server>,
 }
 
+// A fault injected into an RPC in chaos mode.
+#[derive(Debug)]
+enum ChaosFault {
+    // drop the request after a short while
+    DropRequest(Duration),
+    // drop the reply
+    DropReply,
+    // delay the request
+    Delay(Duration),
+    // delay the reply by the given milliseconds
+    Reorder(u64),
+}
+
+impl ChaosFault {
+    // Picks a fault with the given probability.
+    fn pick(
+        rng: &mut StdRng,
+        probability: f64,
+        min_reorder_delay: u64,
+        max_reorder_delay: u64,
+    ) -> Option<ChaosFault> {
+        if !rng.gen_bool(probability) {
+            return None;
+        }
+        let fault = match rng.gen_range(0, 4) {
+            0 => ChaosFault::DropRequest(Duration::from_millis(rng.gen_range(0, 27))),
+            1 => ChaosFault::DropReply,
+            2 => ChaosFault::Delay(Duration::from_millis(rng.gen_range(0, 27))),
+            _ => ChaosFault::Reorder(rng.gen_range(min_reorder_delay, max_reorder_delay + 1)),
+        };
+        Some(fault)
+    }
+}
+
+/// A snapshot of the fault injection knobs of a network.
+///
+/// It can be used to save the current settings and restore them later.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NetworkSettings {
+    pub reliable: bool,
+    // pause a long time on send on disabled connection
+    pub long_delays: bool,
+    // sometimes delay replies a long time
+    pub long_reordering: bool,
+    // bounds of the delay of long reordering in milliseconds, [min, max)
+    pub min_reorder_delay: u64,
+    pub max_reorder_delay: u64,
+    // requests and replies larger than it are rejected
+    pub max_message_size: usize,
+}
+
+/// A snapshot of the total RPC count, see `Network::count_since`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CountMark {
+    total: usize,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> NetworkSettings {
+        NetworkSettings {
+            reliable: true,
+            long_delays: false,
+            long_reordering: false,
+            min_reorder_delay: 200,
+            max_reorder_delay: 2000,
+            max_message_size: usize::MAX,
+        }
+    }
+}
+
+/// The configuration of a new network, see `Network::create_with_config`.
+///
+/// The default is a reliable network without limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NetworkConfig {
+    pub reliable: bool,
+    pub long_delays: bool,
+    pub long_reordering: bool,
+    // requests and replies larger than it are rejected, 0 means no limit
+    pub max_message_size: usize,
+    // the bits per second all the requests on the network share
+    pub bandwidth_limit_bps: Option<u64>,
+    // seeds the random delays and drops, see `Network::set_seed`
+    pub seed: Option<u64>,
+}
+
+/// The rate limit of a client end.
+///
+/// RPCs exceeding the limit are delayed until the link can carry them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RpcRate {
+    pub rpcs_per_sec: Option<u32>,
+    pub bytes_per_sec: Option<u64>,
+}
+
+/// The distribution of the delay of the RPCs sent by a client end, in
+/// milliseconds, see `Network::set_rtt_model`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RttModel {
+    /// Uniform in `min..max`.
+    Uniform {
+        min: u64,
+        max: u64,
+    },
+    Normal {
+        mean: f64,
+        stddev: f64,
+    },
+    /// The delay is `exp(x)` where `x` is normal of `mu` and `sigma`,
+    /// which has a long tail like a real network.
+    LogNormal {
+        mu: f64,
+        sigma: f64,
+    },
+}
+
+impl Default for RttModel {
+    /// The short delays of an unreliable network.
+    fn default() -> RttModel {
+        RttModel::Uniform { min: 0, max: 27 }
+    }
+}
+
+impl RttModel {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        let ms = match *self {
+            RttModel::Uniform { min, max } if min < max => rng.gen_range(min, max) as f64,
+            RttModel::Uniform { min, .. } => min as f64,
+            RttModel::Normal { mean, stddev } => Normal::new(mean, stddev).unwrap().sample(rng),
+            RttModel::LogNormal { mu, sigma } => LogNormal::new(mu, sigma).unwrap().sample(rng),
+        };
+        // a delay cannot be negative, and is at most a day.
+        if ms.is_nan() {
+            return Duration::from_millis(0);
+        }
+        Duration::from_secs_f64(ms.clamp(0.0, 86_400_000.0) / 1000.0)
+    }
+}
+
+/// A token bucket which holds at most one second worth of tokens.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `n` tokens out of the bucket, returns how long the caller has to
+    /// wait until these tokens are actually available.
+    ///
+    /// Tokens are taken even if the bucket runs short, so that callers are
+    /// served in the order they arrive.
+    fn reserve(&mut self, n: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= n;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    rpcs: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(rate: RpcRate) -> RateLimiter {
+        RateLimiter {
+            rpcs: rate.rpcs_per_sec.map(|r| TokenBucket::new(r.into())),
+            bytes: rate.bytes_per_sec.map(|r| TokenBucket::new(r as f64)),
+        }
+    }
+
+    fn reserve(&mut self, bytes: usize) -> Duration {
+        let rpcs_wait = self.rpcs.as_mut().map(|b| b.reserve(1.0));
+        let bytes_wait = self.bytes.as_mut().map(|b| b.reserve(bytes as f64));
+        rpcs_wait
+            .into_iter()
+            .chain(bytes_wait)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Replies an RPC in place of the server, see `Network::intercept`.
+pub type RpcInterceptor = dyn Fn(&Rpc) -> Option<Result<Vec<u8>>> + Send + Sync;
+
+/// Observes every RPC the network receives, see
+/// `Network::add_rpc_observer`.
+pub type RpcObserver = dyn Fn(&Rpc) + Send + Sync;
+
+/// Describes the state of a cluster on the network, e.g. the term and the
+/// role of each raft peer, see `Network::register_stability_probe`.
+pub type StabilityProbe = dyn Fn() -> String + Send + Sync;
+
+/// Called with the fq_name and the elapsed time of a handler running too
+/// long, see `Network::set_slow_handler_hook`.
+pub type SlowHandlerHook = dyn Fn(&str, Duration) + Send + Sync;
+
+// Reports the handlers running longer than the threshold.
+struct SlowHandlerWatch {
+    threshold: Duration,
+    hook: Option<Arc<SlowHandlerHook>>,
+}
+
+// The source of the randomness of the network.
+pub(crate) struct NetworkRng {
+    // the seed, if it is set by the tests
+    seed: Option<u64>,
+    pub(crate) rng: StdRng,
+}
+
 struct Endpoints {
     // by client name
     enabled: HashMap<String, bool>,
@@ -32,19 +277,50 @@ struct Endpoints {
     servers: HashMap<String, Option<Server>>,
     // client_name -> server_name
     connections: HashMap<String, Option<String>>,
+    // client_name -> rate limiter
+    rate_limits: HashMap<String, RateLimiter>,
+    // client_name -> one-way latency
+    latencies: HashMap<String, Duration>,
+    // client_name -> the distribution of the delay of its requests
+    rtt_models: HashMap<String, RttModel>,
+    // client_name -> the number of RPCs sent by the client to drop
+    drops: HashMap<String, usize>,
+    // server_name -> the max number of requests dispatched at the same time
+    queue_depths: HashMap<String, usize>,
+    // server_name -> permits to dispatch to the server
+    concurrency_limits: HashMap<String, Arc<Semaphore>>,
+    // client_name -> interceptor of the RPCs sent by the client
+    interceptors: HashMap<String, Arc<RpcInterceptor>>,
+    // client_name -> timeouts of RPCs sent while the client is disabled,
+    // dropping the senders completes the timeouts at once
+    pending_timeouts: HashMap<String, Vec<oneshot::Sender<()>>>,
+    // server_name -> RPCs parked while the server is paused, in order of
+    // arrival, each is resumed with a sender to drop once it is dispatched
+    paused: HashMap<String, Vec<oneshot::Sender<oneshot::Sender<()>>>>,
 }
 
 struct NetworkCore {
-    reliable: AtomicBool,
-    // pause a long time on send on disabled connection
-    long_delays: AtomicBool,
-    // sometimes delay replies a long time
-    long_reordering: AtomicBool,
+    settings: Mutex<NetworkSettings>,
+    // probability of a random fault, see `Network::chaos_mode`
+    chaos: Mutex<Option<f64>>,
+    rng: Mutex<NetworkRng>,
+    // in bits, shared by all the requests on the network
+    bandwidth: Mutex<Option<TokenBucket>>,
     endpoints: Mutex<Endpoints>,
     count: AtomicUsize,
+    // rpc_id -> (client name, fq_name) of the RPCs not replied yet
+    pending: Mutex<HashMap<u64, (String, String)>>,
+    observers: Mutex<Vec<Arc<RpcObserver>>>,
+    stability_probes: Mutex<Vec<Arc<StabilityProbe>>>,
+    // method name, without the service name -> the number of RPCs received
+    method_counts: Mutex<HashMap<String, u64>>,
+    slow_handler_watch: Mutex<SlowHandlerWatch>,
+    slow_handlers: AtomicUsize,
     sender: UnboundedSender<Rpc>,
     poller: ThreadPool,
     worker: ThreadPool,
+    // runs RPC handlers, so that a stuck handler does not jam the poller.
+    handler: ThreadPool,
 }
 
 #[derive(Clone)]
@@ -54,7 +330,39 @@ pub struct Network {
 
 impl Network {
     pub fn new() -> Network {
+        Network::create_with_config(NetworkConfig {
+            reliable: true,
+            ..Default::default()
+        })
+    }
+
+    /// Creates a network with all the settings applied before it starts,
+    /// instead of a series of `set_*` calls.
+    ///
+    /// ```ignore
+    /// let net = Network::create_with_config(NetworkConfig {
+    ///     reliable: false,
+    ///     long_reordering: true,
+    ///     seed: Some(42),
+    ///     ..Default::default()
+    /// });
+    /// ```
+    pub fn create_with_config(config: NetworkConfig) -> Network {
         let (net, incoming) = Network::create();
+        net.apply_settings(&NetworkSettings {
+            reliable: config.reliable,
+            long_delays: config.long_delays,
+            long_reordering: config.long_reordering,
+            max_message_size: match config.max_message_size {
+                0 => usize::MAX,
+                size => size,
+            },
+            ..Default::default()
+        });
+        net.set_bandwidth_limit(config.bandwidth_limit_bps);
+        if let Some(seed) = config.seed {
+            net.set_seed(seed);
+        }
         net.start(incoming);
         net
     }
@@ -63,17 +371,43 @@ impl Network {
         let (sender, incoming) = unbounded();
         let net = Network {
             core: Arc::new(NetworkCore {
-                reliable: AtomicBool::new(true),
-                long_delays: AtomicBool::new(false),
-                long_reordering: AtomicBool::new(false),
+                settings: Mutex::default(),
+                chaos: Mutex::default(),
+                rng: Mutex::new(NetworkRng {
+                    seed: None,
+                    rng: StdRng::from_entropy(),
+                }),
+                bandwidth: Mutex::default(),
                 endpoints: Mutex::new(Endpoints {
                     enabled: HashMap::new(),
                     servers: HashMap::new(),
                     connections: HashMap::new(),
+                    rate_limits: HashMap::new(),
+                    latencies: HashMap::new(),
+                    rtt_models: HashMap::new(),
+                    drops: HashMap::new(),
+                    queue_depths: HashMap::new(),
+                    concurrency_limits: HashMap::new(),
+                    interceptors: HashMap::new(),
+                    pending_timeouts: HashMap::new(),
+                    paused: HashMap::new(),
                 }),
                 count: AtomicUsize::new(0),
+                pending: Mutex::default(),
+                observers: Mutex::default(),
+                stability_probes: Mutex::default(),
+                method_counts: Mutex::default(),
+                slow_handler_watch: Mutex::new(SlowHandlerWatch {
+                    threshold: Duration::from_secs(1),
+                    hook: None,
+                }),
+                slow_handlers: AtomicUsize::new(0),
                 poller: ThreadPool::builder().pool_size(2).create().unwrap(),
                 worker: ThreadPool::new().unwrap(),
+                handler: ThreadPool::builder()
+                    .name_prefix("labrpc-handler-")
+                    .create()
+                    .unwrap(),
                 sender,
             }),
         };
@@ -85,27 +419,101 @@ impl Network {
         let network = self.clone();
         self.core.poller.spawn_ok(async move {
             while let Some(mut rpc) = incoming.next().await {
-                let resp = rpc.take_resp_sender().unwrap();
+                // None for a oneway RPC.
+                let resp = rpc.take_resp_sender();
                 let net = network.clone();
+                let rpc_id = rpc.rpc_id;
+                network
+                    .core
+                    .pending
+                    .lock()
+                    .unwrap()
+                    .insert(rpc_id, (rpc.client_name.clone(), rpc.fq_name.to_string()));
                 network.core.poller.spawn_ok(async move {
                     let res = net.process_rpc(rpc).await;
-                    if let Err(e) = resp.send(res) {
-                        error!("fail to send resp: {:?}", e);
+                    net.core.pending.lock().unwrap().remove(&rpc_id);
+                    match resp {
+                        Some(resp) => {
+                            if let Err(e) = resp.send(res) {
+                                error!("fail to send resp: {:?}", e);
+                            }
+                        }
+                        None => debug!("oneway rpc {} done: {:?}", rpc_id, res.map(|_| ())),
                     }
                 })
             }
         });
     }
 
-    pub fn add_server(&self, server: Server) {
+    /// The number of RPCs the network has received but not replied yet.
+    pub fn pending_rpcs(&self) -> usize {
+        self.core.pending.lock().unwrap().len()
+    }
+
+    /// Panics if there are RPCs not replied yet, listing their client names
+    /// and methods.
+    pub fn assert_no_pending_rpcs(&self) {
+        let mut rpcs: Vec<_> = self
+            .core
+            .pending
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(client_name, fq_name)| format!("{} -> {}", client_name, fq_name))
+            .collect();
+        if rpcs.is_empty() {
+            return;
+        }
+        rpcs.sort_unstable();
+        panic!("{} pending RPCs: {:?}", rpcs.len(), rpcs);
+    }
+
+    /// Returns a guard which calls `assert_no_pending_rpcs` on drop, so a
+    /// test holding it fails if it leaks RPCs.
+    pub fn test_scope(&self) -> TestScope {
+        TestScope {
+            network: self.clone(),
+        }
+    }
+
+    /// Adds a server to the network, returns the live server it replaces.
+    pub fn add_server(&self, server: Server) -> Option<Server> {
         let mut eps = self.core.endpoints.lock().unwrap();
-        eps.servers.insert(server.core.name.clone(), Some(server));
+        if let Some(&depth) = eps.queue_depths.get(&server.core.name) {
+            server.core.max_queue_depth.store(depth, Ordering::Release);
+        }
+        let old = eps
+            .servers
+            .insert(server.core.name.clone(), Some(server))
+            .flatten();
+        if let Some(ref old) = old {
+            old.kill();
+        }
+        old
     }
 
-    pub fn delete_server(&self, name: &str) {
+    /// Kills a server, returns the removed server or `None` if there is no
+    /// live server with the name.
+    ///
+    /// The name is kept in the network, so RPCs sent to the killed server
+    /// still fail as if the server is dead.
+    pub fn delete_server(&self, name: &str) -> Option<Server> {
         let mut eps = self.core.endpoints.lock().unwrap();
-        if let Some(s) = eps.servers.get_mut(name) {
-            *s = None;
+        // the parked RPCs fail with `Error::ServerKilled`.
+        eps.paused.remove(name);
+        match eps.servers.get_mut(name) {
+            Some(s) => {
+                let server = s.take();
+                if let Some(ref server) = server {
+                    // the RPCs in flight fail with `Error::ServerKilled`.
+                    server.kill();
+                }
+                server
+            }
+            None => {
+                debug!("delete unknown server {}", name);
+                None
+            }
         }
     }
 
@@ -119,6 +527,7 @@ impl Network {
             sender,
             worker: self.core.worker.clone(),
             hooks: Arc::new(Mutex::new(None)),
+            stats: Arc::default(),
         }
     }
 
@@ -130,6 +539,39 @@ impl Network {
             .insert(client_name.to_owned(), Some(server_name.to_owned()));
     }
 
+    /// Creates a pair of Clients between two servers, `a->b` sends to `b` and
+    /// `b->a` sends to `a`. Both of them are connected and enabled.
+    ///
+    /// Returns the Clients of `a->b` and `b->a`.
+    pub fn connect_bidirectional(&self, a: &str, b: &str) -> (Client, Client) {
+        let (ab, ba) = bidirectional_names(a, b);
+        let client_ab = self.create_client(ab.clone());
+        let client_ba = self.create_client(ba.clone());
+        self.connect(&ab, b);
+        self.connect(&ba, a);
+        self.enable_bidirectional(a, b, true);
+        (client_ab, client_ba)
+    }
+
+    /// Enable/disable both Clients created by `connect_bidirectional` at
+    /// once.
+    pub fn enable_bidirectional(&self, a: &str, b: &str, enabled: bool) {
+        let (ab, ba) = bidirectional_names(a, b);
+        debug!(
+            "client {} and {} are {}",
+            ab,
+            ba,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        let mut eps = self.core.endpoints.lock().unwrap();
+        for name in [ab, ba].iter() {
+            eps.enabled.insert(name.clone(), enabled);
+            if enabled {
+                eps.pending_timeouts.remove(name);
+            }
+        }
+    }
+
     /// Enable/disable a Client.
     pub fn enable(&self, client_name: &str, enabled: bool) {
         debug!(
@@ -139,18 +581,228 @@ impl Network {
         );
         let mut eps = self.core.endpoints.lock().unwrap();
         eps.enabled.insert(client_name.to_owned(), enabled);
+        if enabled {
+            // RPCs sent while disabled time out right now, so that they
+            // do not compete with the subsequent RPCs.
+            eps.pending_timeouts.remove(client_name);
+        }
+    }
+
+    /// The name of the server a Client is connected to, `None` if it is not
+    /// connected or there is no such Client.
+    pub fn get_connection(&self, client_name: &str) -> Option<String> {
+        let eps = self.core.endpoints.lock().unwrap();
+        eps.connections.get(client_name).and_then(|o| o.clone())
+    }
+
+    /// Whether a Client is enabled, a Client is disabled until `enable` is
+    /// called on it.
+    pub fn is_enabled(&self, client_name: &str) -> bool {
+        let eps = self.core.endpoints.lock().unwrap();
+        eps.enabled.get(client_name).cloned().unwrap_or(false)
+    }
+
+    /// Whether a Client is connected to a server. Its RPCs reach the server
+    /// only if it is enabled as well, and the server is on the network.
+    pub fn is_connected(&self, client_name: &str) -> bool {
+        self.get_connection(client_name).is_some()
+    }
+
+    /// Limits the rate of RPCs sent by a Client, `RpcRate::default()`
+    /// removes the limit.
+    pub fn set_rate_limit(&self, client_name: &str, rate: RpcRate) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if rate == RpcRate::default() {
+            eps.rate_limits.remove(client_name);
+        } else {
+            eps.rate_limits
+                .insert(client_name.to_owned(), RateLimiter::new(rate));
+        }
+    }
+
+    /// Limits the bits per second of the requests sent by all the Clients
+    /// together, `None` removes the limit.
+    pub fn set_bandwidth_limit(&self, bps: Option<u64>) {
+        *self.core.bandwidth.lock().unwrap() = bps.map(|bps| TokenBucket::new(bps as f64));
+    }
+
+    /// Sets a fixed one-way latency on the link of a Client, it applies to
+    /// both requests and replies on top of other delays.
+    pub fn set_link_latency(&self, client_name: &str, latency: Duration) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if latency == Duration::from_secs(0) {
+            eps.latencies.remove(client_name);
+        } else {
+            eps.latencies.insert(client_name.to_owned(), latency);
+        }
+    }
+
+    /// Delays each request sent by a Client by a sample of `model` before
+    /// it is dispatched, instead of the short delay of an unreliable
+    /// network, even if the network is reliable.
+    pub fn set_rtt_model(&self, client_name: &str, model: RttModel) {
+        match model {
+            RttModel::Normal { stddev: x, .. } | RttModel::LogNormal { sigma: x, .. } => {
+                assert!(x >= 0.0, "negative deviation {} of {:?}", x, model)
+            }
+            RttModel::Uniform { .. } => {}
+        }
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.rtt_models.insert(client_name.to_owned(), model);
+    }
+
+    /// Removes the model set by `set_rtt_model`.
+    pub fn clear_rtt_model(&self, client_name: &str) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.rtt_models.remove(client_name);
+    }
+
+    /// Drops the next `n` RPCs sent by a Client regardless of the methods,
+    /// they fail with `Error::Timeout` without reaching the server. It
+    /// simulates a brief outage of the link, `n = 0` ends it.
+    pub fn drop_next_n(&self, client_name: &str, n: usize) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if n == 0 {
+            eps.drops.remove(client_name);
+        } else {
+            eps.drops.insert(client_name.to_owned(), n);
+        }
+    }
+
+    // Whether to drop the RPC sent by the client, see `drop_next_n`.
+    fn take_drop(&self, client_name: &str) -> bool {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        match eps.drops.get_mut(client_name) {
+            Some(n) => {
+                *n -= 1;
+                if *n == 0 {
+                    eps.drops.remove(client_name);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets the max number of requests a server dispatches at the same time,
+    /// requests beyond it are rejected with `Error::Overloaded`. It applies
+    /// to the servers added under the name later too.
+    pub fn set_server_queue_depth(&self, server_name: &str, depth: usize) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.queue_depths.insert(server_name.to_owned(), depth);
+        if let Some(Some(server)) = eps.servers.get(server_name) {
+            server.core.max_queue_depth.store(depth, Ordering::Release);
+        }
+    }
+
+    /// Dispatches at most `max` requests to the server at the same time, the
+    /// rest wait for their turns in the queue of the server, whose depth is
+    /// bounded by `set_server_queue_depth`. It applies to the servers added
+    /// under the name later too, e.g. to simulate a single-threaded server
+    /// with `max` of 1.
+    ///
+    /// The requests already waiting keep waiting for the previous limit.
+    pub fn limit_concurrency(&self, server_name: &str, max: usize) {
+        assert!(max > 0, "max concurrency must be positive");
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.concurrency_limits
+            .insert(server_name.to_owned(), Arc::new(Semaphore::new(max)));
+    }
+
+    fn concurrency_limit(&self, server_name: &str) -> Option<Arc<Semaphore>> {
+        let eps = self.core.endpoints.lock().unwrap();
+        eps.concurrency_limits.get(server_name).cloned()
     }
 
     pub fn set_reliable(&self, yes: bool) {
-        self.core.reliable.store(yes, Ordering::Release);
+        self.core.settings.lock().unwrap().reliable = yes;
     }
 
     pub fn set_long_reordering(&self, yes: bool) {
-        self.core.long_reordering.store(yes, Ordering::Release);
+        self.core.settings.lock().unwrap().long_reordering = yes;
+    }
+
+    /// Bounds the delay of long reordering from below, 200ms by default.
+    pub fn set_min_reorder_delay(&self, ms: u64) {
+        let mut settings = self.core.settings.lock().unwrap();
+        assert!(
+            ms <= settings.max_reorder_delay,
+            "min reorder delay {}ms exceeds the max {}ms",
+            ms,
+            settings.max_reorder_delay
+        );
+        settings.min_reorder_delay = ms;
+    }
+
+    /// Bounds the delay of long reordering from above, 2000ms by default.
+    /// It does not change how often replies are reordered.
+    pub fn set_max_reorder_delay(&self, ms: u64) {
+        let mut settings = self.core.settings.lock().unwrap();
+        assert!(
+            ms >= settings.min_reorder_delay,
+            "max reorder delay {}ms is below the min {}ms",
+            ms,
+            settings.min_reorder_delay
+        );
+        settings.max_reorder_delay = ms;
     }
 
     pub fn set_long_delays(&self, yes: bool) {
-        self.core.long_delays.store(yes, Ordering::Release);
+        self.core.settings.lock().unwrap().long_delays = yes;
+    }
+
+    /// Rejects requests and replies whose payload exceeds `bytes` with
+    /// `Error::MessageTooLarge`.
+    pub fn set_max_message_size(&self, bytes: usize) {
+        self.core.settings.lock().unwrap().max_message_size = bytes;
+    }
+
+    /// Returns a snapshot of the current settings.
+    pub fn settings(&self) -> NetworkSettings {
+        *self.core.settings.lock().unwrap()
+    }
+
+    /// Replaces all the settings at once.
+    pub fn apply_settings(&self, settings: &NetworkSettings) {
+        *self.core.settings.lock().unwrap() = *settings;
+    }
+
+    /// Makes the random delays and drops of the network reproducible by
+    /// seeding its random number generator.
+    ///
+    /// Note that the scheduling of threads is still nondeterministic.
+    pub fn set_seed(&self, seed: u64) {
+        *self.core.rng.lock().unwrap() = NetworkRng {
+            seed: Some(seed),
+            rng: StdRng::seed_from_u64(seed),
+        };
+    }
+
+    /// Injects a random fault into each RPC with `probability`, one of
+    /// dropping the request, dropping the reply, delaying the request or
+    /// delaying the reply as long reordering does. The network is seeded
+    /// with `seed`, see `set_seed`.
+    ///
+    /// The fine-grained settings override it: it does nothing while the
+    /// network is unreliable or reorders replies. A probability of 0 turns
+    /// it off.
+    pub fn chaos_mode(&self, probability: f64, seed: u64) {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "chaos probability {} is not in [0, 1]",
+            probability
+        );
+        self.set_seed(seed);
+        *self.core.chaos.lock().unwrap() = Some(probability).filter(|&p| p > 0.0);
+    }
+
+    /// Returns the seed set by `set_seed`.
+    pub fn seed(&self) -> Option<u64> {
+        self.core.rng.lock().unwrap().seed
+    }
+
+    pub(crate) fn rng(&self) -> MutexGuard<'_, NetworkRng> {
+        self.core.rng.lock().unwrap()
     }
 
     pub fn count(&self, server_name: &str) -> usize {
@@ -158,100 +810,431 @@ impl Network {
         eps.servers[server_name].as_ref().unwrap().count()
     }
 
+    pub fn method_count(&self, server_name: &str, fq_name: &str) -> usize {
+        let eps = self.core.endpoints.lock().unwrap();
+        eps.servers[server_name]
+            .as_ref()
+            .unwrap()
+            .method_count(fq_name)
+    }
+
     pub fn total_count(&self) -> usize {
         self.core.count.load(Ordering::Relaxed)
     }
 
+    /// Zeroes the total count and the count of every server.
+    pub fn reset_counts(&self) {
+        let eps = self.core.endpoints.lock().unwrap();
+        self.core.count.store(0, Ordering::Relaxed);
+        for server in eps.servers.values().flatten() {
+            server.reset_count();
+        }
+    }
+
+    /// Marks the current total count, so that RPCs sent afterward can be
+    /// counted without resetting the counters.
+    pub fn count_mark(&self) -> CountMark {
+        CountMark {
+            total: self.total_count(),
+        }
+    }
+
+    /// The number of RPCs sent since the mark was taken.
+    pub fn count_since(&self, mark: &CountMark) -> usize {
+        self.total_count().saturating_sub(mark.total)
+    }
+
     fn end_info(&self, client_name: &str) -> EndInfo {
         let eps = self.core.endpoints.lock().unwrap();
         let mut server = None;
         if let Some(Some(server_name)) = eps.connections.get(client_name) {
             server = eps.servers[server_name].clone();
         }
+        let settings = self.settings();
         EndInfo {
             enabled: eps.enabled[client_name],
-            reliable: self.core.reliable.load(Ordering::Acquire),
-            long_reordering: self.core.long_reordering.load(Ordering::Acquire),
+            reliable: settings.reliable,
+            long_delays: settings.long_delays,
+            long_reordering: settings.long_reordering,
+            min_reorder_delay: settings.min_reorder_delay,
+            max_reorder_delay: settings.max_reorder_delay,
+            max_message_size: settings.max_message_size,
+            chaos: *self.core.chaos.lock().unwrap(),
+            latency: eps.latencies.get(client_name).cloned().unwrap_or_default(),
+            rtt_model: eps.rtt_models.get(client_name).cloned(),
             server,
         }
     }
 
-    fn is_server_dead(&self, client_name: &str, server_name: &str, server_id: usize) -> bool {
+    fn rate_limit_delay(&self, client_name: &str, bytes: usize) -> Option<Duration> {
+        let link = {
+            let mut eps = self.core.endpoints.lock().unwrap();
+            eps.rate_limits
+                .get_mut(client_name)
+                .map(|limiter| limiter.reserve(bytes))
+        };
+        let network = {
+            let mut bandwidth = self.core.bandwidth.lock().unwrap();
+            bandwidth.as_mut().map(|b| b.reserve(bytes as f64 * 8.0))
+        };
+        link.into_iter().chain(network).max()
+    }
+
+    /// Intercepts the RPCs sent by a Client that reach the server. The
+    /// interceptor replies an RPC by returning `Some`, the server is not
+    /// involved then. Otherwise the RPC goes on as usual.
+    pub fn intercept(&self, client_name: &str, interceptor: Arc<RpcInterceptor>) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.interceptors.insert(client_name.to_owned(), interceptor);
+    }
+
+    /// Calls `observer` with every RPC the network receives, before any
+    /// fault is injected, in the order the observers are added. It must not
+    /// block, e.g. it records the RPCs to a buffer of the test.
+    pub fn add_rpc_observer(&self, observer: Arc<RpcObserver>) {
+        self.core.observers.lock().unwrap().push(observer);
+    }
+
+    /// Removes the observers added by `add_rpc_observer`.
+    pub fn clear_rpc_observers(&self) {
+        self.core.observers.lock().unwrap().clear();
+    }
+
+    /// Adds `probe` describing the state of a cluster to the ones checked by
+    /// `wait_until_stable`.
+    pub fn register_stability_probe(&self, probe: Arc<StabilityProbe>) {
+        self.core.stability_probes.lock().unwrap().push(probe);
+    }
+
+    /// Blocks until the cluster on the network has been stable for
+    /// `quiesce_duration`, i.e. no RPC to `method` of any service has been
+    /// sent and the probes registered by `register_stability_probe` describe
+    /// the same state all the while, e.g. no `request_vote` after a leader
+    /// has been elected. Fails with `Error::Timeout` if it is not stable
+    /// within `timeout`.
+    pub fn wait_until_stable(
+        &self,
+        method: &str,
+        quiesce_duration: Duration,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut stable_since = Instant::now();
+        let mut last = self.stability_state(method);
+        loop {
+            let now = Instant::now();
+            if now.duration_since(stable_since) >= quiesce_duration {
+                return Ok(());
+            }
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+            thread::sleep(STABILITY_POLL_INTERVAL);
+            let state = self.stability_state(method);
+            if state != last {
+                last = state;
+                stable_since = Instant::now();
+            }
+        }
+    }
+
+    // The RPCs to the method received so far and the descriptions of the
+    // probes.
+    fn stability_state(&self, method: &str) -> (u64, Vec<String>) {
+        let probes = self.core.stability_probes.lock().unwrap().clone();
+        let count = self
+            .core
+            .method_counts
+            .lock()
+            .unwrap()
+            .get(method)
+            .copied()
+            .unwrap_or(0);
+        (count, probes.iter().map(|probe| probe()).collect())
+    }
+
+    /// Removes the interceptor set by `intercept`.
+    pub fn clear_intercept(&self, client_name: &str) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.interceptors.remove(client_name);
+    }
+
+    fn intercepted(&self, rpc: &Rpc) -> Option<Result<Vec<u8>>> {
+        let interceptor = {
+            let eps = self.core.endpoints.lock().unwrap();
+            eps.interceptors.get(&rpc.client_name).cloned()
+        };
+        interceptor.and_then(|interceptor| interceptor(rpc))
+    }
+
+    /// Freezes a server as if its process stopped running, the RPCs sent to
+    /// it are parked until `resume_server`.
+    pub fn pause_server(&self, server_name: &str) {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.paused.entry(server_name.to_owned()).or_default();
+    }
+
+    /// Unfreezes a paused server. It blocks until the parked RPCs have been
+    /// dispatched, one by one in the order they arrived.
+    pub fn resume_server(&self, server_name: &str) {
+        let parked = {
+            let mut eps = self.core.endpoints.lock().unwrap();
+            eps.paused.remove(server_name).unwrap_or_default()
+        };
+        for resume in parked {
+            let (tx, rx) = oneshot::channel();
+            if resume.send(tx).is_ok() {
+                // ignore the error, the RPC might end without dispatching.
+                let _ = block_on(rx);
+            }
+        }
+    }
+
+    /// Parks an RPC if the server is paused, the returned receiver completes
+    /// when the server is resumed.
+    fn park(&self, server_name: &str) -> Option<oneshot::Receiver<oneshot::Sender<()>>> {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        eps.paused.get_mut(server_name).map(|parked| {
+            let (tx, rx) = oneshot::channel();
+            parked.push(tx);
+            rx
+        })
+    }
+
+    /// Registers a timeout of an RPC sent by a disabled Client, the returned
+    /// receiver completes when the Client is enabled again.
+    fn pending_timeout(&self, client_name: &str) -> Option<oneshot::Receiver<()>> {
+        let mut eps = self.core.endpoints.lock().unwrap();
+        if eps.enabled[client_name] {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        let pending = eps
+            .pending_timeouts
+            .entry(client_name.to_owned())
+            .or_default();
+        pending.retain(|tx| !tx.is_canceled());
+        pending.push(tx);
+        Some(rx)
+    }
+
+    /// Checks if the server can not reply to the client any more, returns
+    /// `Error::ServerKilled` if the server has been deleted or replaced, or
+    /// `Error::Stopped` if the client has been disabled.
+    fn server_dead_error(
+        &self,
+        client_name: &str,
+        server_name: &str,
+        server_id: usize,
+    ) -> Option<Error> {
         let eps = self.core.endpoints.lock().unwrap();
-        !eps.enabled[client_name]
-            || eps.servers.get(server_name).map_or(true, |o| {
-                o.as_ref().map(|s| s.core.id != server_id).unwrap_or(true)
-            })
+        let killed = eps
+            .servers
+            .get(server_name)
+            .is_none_or(|o| o.as_ref().map(|s| s.core.id != server_id).unwrap_or(true));
+        if killed {
+            Some(Error::ServerKilled)
+        } else if !eps.enabled[client_name] {
+            Some(Error::Stopped)
+        } else {
+            None
+        }
     }
 
     async fn process_rpc(&self, rpc: Rpc) -> Result<Vec<u8>> {
-        self.core.count.fetch_add(1, Ordering::Relaxed);
+        let observers = self.core.observers.lock().unwrap().clone();
+        for observer in &observers {
+            observer(&rpc);
+        }
+        if let Some((_, method)) = rpc.fq_name.split_once('.') {
+            *self
+                .core
+                .method_counts
+                .lock()
+                .unwrap()
+                .entry(method.to_owned())
+                .or_insert(0) += 1;
+        }
         let network = self.clone();
         let end_info = self.end_info(&rpc.client_name);
+        self.core.count.fetch_add(1, Ordering::Relaxed);
         debug!("{:?} process with {:?}", rpc, end_info);
         let EndInfo {
             enabled,
             reliable,
+            long_delays,
             long_reordering,
+            min_reorder_delay,
+            max_reorder_delay,
+            max_message_size,
+            chaos,
+            latency,
+            rtt_model,
             server,
         } = end_info;
 
         match (enabled, server) {
             (true, Some(server)) => {
-                let short_delay = if !reliable {
-                    // short delay
-                    let ms = thread_rng().gen::<u64>() % 27;
-                    Some(ms)
-                } else {
-                    None
+                if self.take_drop(&rpc.client_name) {
+                    debug!("{:?} dropped", rpc);
+                    return Err(Error::Timeout);
+                }
+                let bytes = rpc.req.as_ref().map_or(0, Vec::len);
+                if bytes > max_message_size {
+                    debug!("{:?} request too large {} bytes", rpc, bytes);
+                    return Err(Error::MessageTooLarge);
+                }
+                if let Some(delay) = self.rate_limit_delay(&rpc.client_name, bytes) {
+                    // the link is busy, wait for the tokens.
+                    debug!("{:?} rate limited {:?}", rpc, delay);
+                    Delay::new(delay).await;
+                }
+
+                let (short_delay, drop_request, drop_reply, long_reordering, chaos) = {
+                    let rng = &mut self.rng().rng;
+                    let chaos = chaos
+                        .filter(|_| reliable && !long_reordering)
+                        .and_then(|p| {
+                            ChaosFault::pick(rng, p, min_reorder_delay, max_reorder_delay)
+                        });
+                    let short_delay = match rtt_model {
+                        Some(model) => Some(model.sample(rng)),
+                        None if !reliable => Some(RttModel::default().sample(rng)),
+                        None => None,
+                    };
+                    let drop_request = !reliable && (rng.gen::<u64>() % 1000) < 100;
+                    let drop_reply = !reliable && rng.gen::<u64>() % 1000 < 100;
+                    let long_reordering = if long_reordering && rng.gen_range(0, 900) < 600i32 {
+                        // delay the response for a while
+                        let span = max_reorder_delay - min_reorder_delay;
+                        if span == 0 {
+                            Some(min_reorder_delay)
+                        } else {
+                            let upper_bound: u64 = 1 + rng.gen_range(0, span);
+                            Some(min_reorder_delay + rng.gen_range(0, upper_bound))
+                        }
+                    } else {
+                        None
+                    };
+                    (
+                        short_delay,
+                        drop_request,
+                        drop_reply,
+                        long_reordering,
+                        chaos,
+                    )
+                };
+                let (drop_reply, long_reordering) = match chaos {
+                    Some(ChaosFault::DropRequest(delay)) => {
+                        debug!("{:?} chaos drops the request", rpc);
+                        Delay::new(delay).await;
+                        return Err(Error::Timeout);
+                    }
+                    Some(ChaosFault::DropReply) => (true, long_reordering),
+                    Some(ChaosFault::Delay(delay)) => {
+                        debug!("{:?} chaos delays the request {:?}", rpc, delay);
+                        Delay::new(delay).await;
+                        (drop_reply, long_reordering)
+                    }
+                    Some(ChaosFault::Reorder(ms)) => (drop_reply, Some(ms)),
+                    None => (drop_reply, long_reordering),
                 };
 
-                if !reliable && (thread_rng().gen::<u64>() % 1000) < 100 {
+                if drop_request {
                     // drop the request, return as if timeout
-                    Delay::new(Duration::from_secs(short_delay.unwrap())).await;
+                    Delay::new(short_delay.unwrap()).await;
                     return Err(Error::Timeout);
                 }
 
-                let drop_reply = !reliable && thread_rng().gen::<u64>() % 1000 < 100;
-                let long_reordering = if long_reordering && thread_rng().gen_range(0, 900) < 600i32
-                {
-                    // delay the response for a while
-                    let upper_bound: u64 = 1 + thread_rng().gen_range(0, 2000);
-                    Some(200 + thread_rng().gen_range(0, upper_bound))
-                } else {
-                    None
-                };
+                if latency > Duration::from_secs(0) {
+                    Delay::new(latency).await;
+                }
 
                 // Dispatch
-                process_rpc(
-                    short_delay,
-                    drop_reply,
-                    long_reordering,
-                    rpc,
-                    network,
-                    server,
-                )
-                .await
+                let res = match self.intercepted(&rpc) {
+                    Some(res) => {
+                        debug!("{:?} intercepted", rpc);
+                        res
+                    }
+                    None => {
+                        // fail promptly if the server is killed meanwhile.
+                        let killed = server.killed();
+                        let process = process_rpc(
+                            short_delay,
+                            drop_reply,
+                            long_reordering,
+                            max_message_size,
+                            rpc,
+                            network,
+                            server,
+                        );
+                        select! {
+                            res = process.fuse() => res,
+                            _ = killed.fuse() => Err(Error::ServerKilled),
+                        }
+                    }
+                };
+
+                if latency > Duration::from_secs(0) {
+                    Delay::new(latency).await;
+                }
+                res
             }
             _ => {
                 // simulate no reply and eventual timeout.
-                let ms = if self.core.long_delays.load(Ordering::Acquire) {
+                let ms = if long_delays {
                     // let Raft tests check that leader doesn't send
                     // RPCs synchronously.
-                    thread_rng().gen::<u64>() % 7000
+                    self.rng().rng.gen::<u64>() % 7000
                 } else {
                     // many kv tests require the client to try each
                     // server in fairly rapid succession.
-                    thread_rng().gen::<u64>() % 100
+                    self.rng().rng.gen::<u64>() % 100
                 };
 
                 debug!("{:?} delay {}ms then timeout", rpc, ms);
-                Delay::new(Duration::from_millis(ms)).await;
+                if let Some(enabled) = self.pending_timeout(&rpc.client_name) {
+                    select! {
+                        _ = Delay::new(Duration::from_millis(ms)).fuse() => (),
+                        _ = enabled.fuse() => debug!("{:?} timeout early", rpc),
+                    }
+                }
                 Err(Error::Timeout)
             }
         }
     }
 
+    /// Reports the handlers running longer than `threshold`, 1s by default.
+    /// A warning is logged for each of them and `slow_handler_count` counts
+    /// them.
+    pub fn set_slow_handler_threshold(&self, threshold: Duration) {
+        self.core.slow_handler_watch.lock().unwrap().threshold = threshold;
+    }
+
+    /// Calls `hook` for each handler running longer than the threshold, once
+    /// the threshold is reached, e.g. to fail a test fast.
+    pub fn set_slow_handler_hook(&self, hook: Arc<SlowHandlerHook>) {
+        self.core.slow_handler_watch.lock().unwrap().hook = Some(hook);
+    }
+
+    /// The number of handlers that have run longer than the threshold.
+    pub fn slow_handler_count(&self) -> usize {
+        self.core.slow_handlers.load(Ordering::Relaxed)
+    }
+
+    fn slow_handler_threshold(&self) -> Duration {
+        self.core.slow_handler_watch.lock().unwrap().threshold
+    }
+
+    fn report_slow_handler(&self, fq_name: &str, elapsed: Duration) {
+        warn!("handler of {} has run for {:?}", fq_name, elapsed);
+        self.core.slow_handlers.fetch_add(1, Ordering::Relaxed);
+        let hook = self.core.slow_handler_watch.lock().unwrap().hook.clone();
+        if let Some(hook) = hook {
+            hook(fq_name, elapsed);
+        }
+    }
+
     /// Spawns a future to run on this net framework.
     pub fn spawn<F>(&self, f: F)
     where
@@ -270,27 +1253,53 @@ impl Network {
 }
 
 async fn process_rpc(
-    mut delay: Option<u64>,
+    mut delay: Option<Duration>,
     drop_reply: bool,
     long_reordering: Option<u64>,
+    max_message_size: usize,
     mut rpc: Rpc,
     network: Network,
     server: Server,
 ) -> Result<Vec<u8>> {
     // Dispatch ===============================================================
     if let Some(delay) = delay {
-        Delay::new(Duration::from_secs(delay)).await;
+        Delay::new(delay).await;
     }
     // We has finished the delay, take it out to prevent polling
     // twice.
     delay.take();
 
-    let fq_name = rpc.fq_name;
+    let fq_name = rpc.fq_name.clone();
     let req = rpc.req.take().unwrap();
     if let Some(hooks) = rpc.hooks.lock().unwrap().as_ref() {
-        hooks.before_dispatch(fq_name, &req)?;
+        hooks.before_dispatch(&fq_name, &req)?;
     }
 
+    // Paused =================================================================
+    let dispatched = match network.park(&server.core.name) {
+        Some(resumed) => {
+            debug!("{:?} parked", rpc);
+            match resumed.await {
+                Ok(dispatched) => Some(dispatched),
+                // the server has been deleted.
+                Err(_) => return Err(Error::ServerKilled),
+            }
+        }
+        None => None,
+    };
+
+    // Queued =================================================================
+    let queued = match server.enqueue() {
+        Some(queued) => queued,
+        None => {
+            // the server is overloaded, reject the request after a short while.
+            let ms = network.rng().rng.gen::<u64>() % 27;
+            debug!("{:?} server overloaded, reject in {}ms", rpc, ms);
+            Delay::new(Duration::from_millis(ms)).await;
+            return Err(Error::Overloaded);
+        }
+    };
+
     // Execute the request (call the RPC handler) in a separate thread so that
     // we can periodically check if the server has been killed and the RPC
     // should get a failure reply.
@@ -299,19 +1308,49 @@ async fn process_rpc(
     // this is needed to avoid situation in which a client gets a positive reply
     // to an Append, but the server persisted the update into the old Persister.
     // config.go is careful to call DeleteServer() before superseding the Persister.
+    let ctx = RpcContext::new(rpc.client_name.clone(), rpc.rpc_id);
+    let handle = async {
+        // wait for a turn if the concurrency of the server is limited, by
+        // the network or by the server itself.
+        let limit = match network.concurrency_limit(&server.core.name) {
+            Some(limit) => Some(limit.acquire().await),
+            None => None,
+        };
+        let permit = server.acquire().await;
+        let dispatch = server.dispatch(&ctx, &fq_name, &req);
+        drop(dispatched);
+        let (tx, rx) = oneshot::channel();
+        network.core.handler.spawn_ok(async move {
+            let res = dispatch.await;
+            drop(permit);
+            drop(limit);
+            // the receiver is gone if the server has been killed.
+            let _ = tx.send(res);
+        });
+        let mut rx = rx.fuse();
+        let threshold = network.slow_handler_threshold();
+        select! {
+            res = rx => return res.unwrap_or(Err(Error::Stopped)),
+            _ = Delay::new(threshold).fuse() => {
+                network.report_slow_handler(&fq_name, threshold);
+            }
+        }
+        rx.await.unwrap_or(Err(Error::Stopped))
+    };
     let resp = select! {
-        res = server.dispatch(fq_name, &req).fuse() => res,
-        _ = server_dead(
+        res = handle.fuse() => res,
+        e = server_dead(
             Duration::from_millis(100),
             network.clone(),
             &rpc.client_name,
             &server.core.name,
             server.core.id,
-        ).fuse() => Err(Error::Stopped),
+        ).fuse() => Err(e),
     };
+    drop(queued);
 
     let resp = if let Some(hooks) = rpc.hooks.lock().unwrap().as_ref() {
-        hooks.after_dispatch(fq_name, resp)?
+        hooks.after_dispatch(&fq_name, resp)?
     } else {
         resp?
     };
@@ -320,13 +1359,17 @@ async fn process_rpc(
     let client_name = &rpc.client_name;
     let server_name = &server.core.name;
     let server_id = server.core.id;
-    if network.is_server_dead(client_name, server_name, server_id) {
-        return Err(Error::Stopped);
+    if let Some(e) = network.server_dead_error(client_name, server_name, server_id) {
+        return Err(e);
     }
     if drop_reply {
         // drop the reply, return as if timeout.
         return Err(Error::Timeout);
     }
+    if resp.len() > max_message_size {
+        debug!("{:?} reply too large {} bytes", rpc, resp.len());
+        return Err(Error::MessageTooLarge);
+    }
 
     // Reordering =============================================================
     if let Some(reordering) = long_reordering {
@@ -338,21 +1381,40 @@ async fn process_rpc(
     }
 }
 
+/// A guard checking there is no pending RPC on drop, see
+/// `Network::test_scope`.
+pub struct TestScope {
+    network: Network,
+}
+
+impl Drop for TestScope {
+    fn drop(&mut self) {
+        // do not panic again while unwinding.
+        if !thread::panicking() {
+            self.network.assert_no_pending_rpcs();
+        }
+    }
+}
+
+fn bidirectional_names(a: &str, b: &str) -> (String, String) {
+    (format!("{}->{}", a, b), format!("{}->{}", b, a))
+}
+
 /// Checks if the specified server killed.
 ///
-/// It will return when the server is killed.
+/// It will return the error to reply when the server is killed.
 async fn server_dead(
     interval: Duration,
     net: Network,
     client_name: &str,
     server_name: &str,
     server_id: usize,
-) {
+) -> Error {
     loop {
         Delay::new(interval).await;
-        if net.is_server_dead(&client_name, &server_name, server_id) {
+        if let Some(e) = net.server_dead_error(client_name, server_name, server_id) {
             debug!("{:?} is dead", server_name);
-            return;
+            return e;
         }
     }
 }
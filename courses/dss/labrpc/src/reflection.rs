@@ -0,0 +1,44 @@
+//! The reflection service registered on every server, it tells what methods
+//! a server serves.
+
+use std::sync::Weak;
+
+use prost::Message;
+
+use crate::error::{Error, Result};
+use crate::server::{Server, ServerCore};
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListMethodsArgs {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListMethodsReply {
+    /// The sorted fully qualified names, e.g. `junk.handler2`.
+    #[prost(string, repeated, tag = "1")]
+    pub methods: Vec<String>,
+}
+
+service! {
+    /// The reflection service.
+    #[allow(dead_code)]
+    service __reflection {
+        /// Lists the methods registered on the server, including itself.
+        rpc list_methods(ListMethodsArgs) returns (ListMethodsReply);
+    }
+}
+pub use __reflection::{add_service, Client as ReflectionClient, Service};
+
+#[derive(Clone)]
+pub(crate) struct Reflection {
+    // the server serving it, weak to avoid a reference cycle.
+    pub(crate) server: Weak<ServerCore>,
+}
+
+#[async_trait::async_trait]
+impl Service for Reflection {
+    async fn list_methods(&self, _: ListMethodsArgs) -> Result<ListMethodsReply> {
+        let core = self.server.upgrade().ok_or(Error::Stopped)?;
+        let methods = Server { core }.method_names();
+        Ok(ListMethodsReply { methods })
+    }
+}
@@ -1,62 +1,450 @@
-use std::collections::hash_map::{Entry, HashMap};
+use std::any::Any;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use futures::future::{self, BoxFuture};
+use futures::channel::oneshot;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures_timer::Delay;
+use log::debug;
 
+use crate::client::RpcHooks;
 use crate::error::{Error, Result};
+use crate::health::{self, Health};
+use crate::reflection::{self, Reflection};
 
 static ID_ALLOC: AtomicUsize = AtomicUsize::new(0);
 
 pub type RpcFuture<T> = BoxFuture<'static, T>;
 
-pub type Handler = dyn FnOnce(&[u8]) -> RpcFuture<Result<Vec<u8>>>;
+pub type Handler = dyn FnOnce(&RpcContext, &[u8]) -> RpcFuture<Result<Vec<u8>>>;
+
+/// Handles the requests to the methods not registered, with the fq_name of
+/// the method, see `ServerBuilder::set_default_handler`.
+pub type DefaultHandler =
+    dyn Fn(&RpcContext, &str, &[u8]) -> RpcFuture<Result<Vec<u8>>> + Send + Sync;
+
+/// Handles the requests to a method in place of the registered handler, see
+/// `Server::hot_swap_handler`.
+pub type MethodHandler = dyn Fn(&RpcContext, &[u8]) -> RpcFuture<Result<Vec<u8>>> + Send + Sync;
+
+/// Checks the requests to every method of a server and its replies, e.g.
+/// to log them or to validate them, see `ServerBuilder::build_with_middleware`.
+pub trait Middleware: Send + Sync + 'static {
+    /// Called before the handler, an `Err` is replied without calling it.
+    fn before(&self, fq_name: &str, req: &[u8]) -> Result<()>;
+    /// Called with a successful reply of the handler, an `Err` is replied
+    /// in its place.
+    fn after(&self, fq_name: &str, rsp: &[u8]) -> Result<()>;
+}
+
+/// The information about an RPC, besides the request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcContext {
+    end_name: String,
+    rpc_id: u64,
+}
+
+impl RpcContext {
+    pub(crate) fn new(end_name: String, rpc_id: u64) -> RpcContext {
+        RpcContext { end_name, rpc_id }
+    }
+
+    /// The name of the Client that sent the RPC.
+    pub fn end_name(&self) -> &str {
+        &self.end_name
+    }
+
+    /// The id of the RPC, unique in the process.
+    pub fn rpc_id(&self) -> u64 {
+        self.rpc_id
+    }
+}
 
 pub trait HandlerFactory: Sync + Send + 'static {
-    fn handler(&self, name: &'static str) -> Box<Handler>;
+    fn handler(&self, name: &str) -> Box<Handler>;
+    /// The names of the methods without the service name, e.g. `handler2`.
+    fn method_names(&self) -> &'static [&'static str];
+}
+
+/// The methods registered on a server, by service.
+#[derive(Default)]
+pub(crate) struct Services {
+    // service name -> service methods
+    factories: HashMap<Cow<'static, str>, Arc<dyn HandlerFactory>>,
+    // fq_name -> the handler in place of the one of its factory
+    swapped: HashMap<String, Arc<MethodHandler>>,
+    // fq_names of the methods removed from their factories
+    removed: HashSet<String>,
+}
+
+impl Services {
+    fn add(
+        &mut self,
+        service_name: Cow<'static, str>,
+        factory: Box<dyn HandlerFactory>,
+    ) -> Result<()> {
+        if self.factories.contains_key(&service_name) {
+            return Err(Error::Other(format!(
+                "{} has already registered",
+                service_name
+            )));
+        }
+        self.factories.insert(service_name, factory.into());
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        service_name: Cow<'static, str>,
+        factory: Box<dyn HandlerFactory>,
+    ) -> bool {
+        self.forget_methods(&service_name);
+        self.factories
+            .insert(service_name, factory.into())
+            .is_some()
+    }
+
+    fn remove(&mut self, service_name: &str) {
+        self.forget_methods(service_name);
+        self.factories.remove(service_name);
+    }
+
+    /// Removes a method, the service is removed with its last method.
+    fn remove_method(&mut self, fq_name: &str) -> bool {
+        let (service_name, _) = match fq_name.split_once('.') {
+            Some(names) if self.find(fq_name).is_some() => names,
+            _ => return false,
+        };
+        self.swapped.remove(fq_name);
+        self.removed.insert(fq_name.to_owned());
+        let factory = &self.factories[service_name];
+        let all_removed = factory
+            .method_names()
+            .iter()
+            .all(|name| self.removed.contains(&format!("{}.{}", service_name, name)));
+        if all_removed {
+            let service_name = service_name.to_owned();
+            self.remove(&service_name);
+        }
+        true
+    }
+
+    /// Removes the methods whose fq_names start with `prefix`, returns how
+    /// many are removed.
+    fn remove_prefix(&mut self, prefix: &str) -> usize {
+        let names: Vec<_> = self
+            .method_names()
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        for name in &names {
+            self.remove_method(name);
+        }
+        names.len()
+    }
+
+    /// Swaps the handler of a method, returns the one swapped out.
+    fn swap(&mut self, fq_name: &str, handler: Arc<MethodHandler>) -> Option<Arc<MethodHandler>> {
+        let (factory, method_name) = self.find(fq_name)?;
+        let factory = factory.clone();
+        let old = match self.swapped.insert(fq_name.to_owned(), handler) {
+            Some(old) => old,
+            None => {
+                Arc::new(move |ctx: &RpcContext, req: &[u8]| factory.handler(method_name)(ctx, req))
+            }
+        };
+        Some(old)
+    }
+
+    /// Finds the factory serving `fq_name` and the method name in it.
+    fn find(&self, fq_name: &str) -> Option<(&Arc<dyn HandlerFactory>, &'static str)> {
+        let (service_name, method_name) = fq_name.split_once('.')?;
+        let factory = self.factories.get(service_name)?;
+        let method_name = factory
+            .method_names()
+            .iter()
+            .find(|&&name| name == method_name)?;
+        if self.removed.contains(fq_name) {
+            return None;
+        }
+        Some((factory, method_name))
+    }
+
+    /// The fully qualified names of the methods, sorted.
+    fn method_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .factories
+            .iter()
+            .flat_map(|(service_name, factory)| {
+                factory
+                    .method_names()
+                    .iter()
+                    .map(move |name| format!("{}.{}", service_name, name))
+            })
+            .filter(|name| !self.removed.contains(name))
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn unknown_method(&self, fq_name: &str) -> Error {
+        Error::Unimplemented(format!(
+            "unknown {}, registered methods: {:?}",
+            fq_name,
+            self.method_names()
+        ))
+    }
+
+    // Forgets the swapped and removed methods of a service.
+    fn forget_methods(&mut self, service_name: &str) {
+        let in_service =
+            |fq_name: &String| fq_name.split_once('.').map(|(s, _)| s) == Some(service_name);
+        self.swapped.retain(|fq_name, _| !in_service(fq_name));
+        self.removed.retain(|fq_name| !in_service(fq_name));
+    }
+}
+
+/// The key counting dispatches of unknown methods.
+pub const UNKNOWN_METHOD: &str = "<unknown>";
+
+/// How long the handlers of a method take, network delays excluded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MethodLatency {
+    /// The number of calls that have finished.
+    pub calls: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+/// The statistics of a method.
+#[derive(Default)]
+pub(crate) struct MethodStats {
+    pub(crate) count: AtomicUsize,
+    calls: AtomicU64,
+    // in nanoseconds
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+impl MethodStats {
+    fn record(&self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn latency(&self) -> MethodLatency {
+        MethodLatency {
+            calls: self.calls.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.calls.store(0, Ordering::Relaxed);
+        self.total.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
 }
 
 pub struct ServerBuilder {
     name: String,
-    // Service name -> service methods
-    pub(crate) services: HashMap<&'static str, Box<dyn HandlerFactory>>,
+    pub(crate) services: Services,
+    interceptors: Vec<Arc<dyn RpcHooks>>,
+    default_handler: Option<Arc<DefaultHandler>>,
+    reflection: bool,
+    health: bool,
+    max_concurrency: Option<usize>,
+    max_response_size: Option<usize>,
+    latency_stats: bool,
+    client_counts: bool,
 }
 
 impl ServerBuilder {
     pub fn new(name: String) -> ServerBuilder {
         ServerBuilder {
             name,
-            services: HashMap::new(),
+            services: Services::default(),
+            interceptors: vec![],
+            default_handler: None,
+            reflection: true,
+            health: true,
+            max_concurrency: None,
+            max_response_size: None,
+            latency_stats: true,
+            client_counts: false,
         }
     }
 
+    /// Limits the number of requests dispatched at the same time, the rest
+    /// wait in the queue of the server for their turns.
+    pub fn set_max_concurrency(&mut self, n: usize) {
+        assert!(n > 0, "max concurrency must be positive");
+        self.max_concurrency = Some(n);
+    }
+
+    /// Rejects the replies larger than `bytes` with `Error::MessageTooLarge`
+    /// right after the handler returns, instead of sending them.
+    pub fn set_max_response_size(&mut self, bytes: usize) {
+        self.max_response_size = Some(bytes);
+    }
+
+    /// Does not register the reflection service `__reflection`, which lists
+    /// the methods of the server.
+    pub fn disable_reflection(&mut self) {
+        self.reflection = false;
+    }
+
+    /// Does not register the health check service `__labrpc`, see
+    /// `Client::health_check`.
+    pub fn disable_health_check(&mut self) {
+        self.health = false;
+    }
+
+    /// Does not record how long the handlers take, see
+    /// `Server::method_latency`.
+    pub fn disable_latency_stats(&mut self) {
+        self.latency_stats = false;
+    }
+
+    /// Counts the dispatches of the requests from every client end, see
+    /// `Server::count_by_client`. It is off by default, for it takes a lock
+    /// on every dispatch.
+    pub fn enable_client_counts(&mut self) {
+        self.client_counts = true;
+    }
+
+    /// Adds hooks run by the server around every request it receives, in
+    /// the order they are added.
+    ///
+    /// An `Err` from `before_dispatch` is replied to the client without
+    /// calling the handler and the rest interceptors. Every `after_dispatch`
+    /// sees the reply of the handler, as transformed by the previous ones.
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn RpcHooks>) {
+        self.interceptors.push(interceptor);
+    }
+
+    /// Handles the requests to the methods not registered with `handler`,
+    /// e.g. to forward them to another server, instead of failing them with
+    /// `Error::Unimplemented`.
+    pub fn set_default_handler(&mut self, handler: Arc<DefaultHandler>) {
+        self.default_handler = Some(handler);
+    }
+
+    /// Registers the service under `service_name`, which can be computed at
+    /// runtime, e.g. `shard7`. Fails if a service of the same name has
+    /// already registered.
     pub fn add_service(
         &mut self,
-        service_name: &'static str,
+        service_name: impl Into<Cow<'static, str>>,
         factory: Box<dyn HandlerFactory>,
     ) -> Result<()> {
-        match self.services.entry(service_name) {
-            Entry::Occupied(_) => Err(Error::Other(format!(
-                "{} has already registered",
-                service_name
-            ))),
-            Entry::Vacant(entry) => {
-                entry.insert(factory);
-                Ok(())
-            }
+        self.services.add(service_name.into(), factory)
+    }
+
+    /// Registers the service, replacing the registered one of the same name
+    /// if any. Returns whether a service was replaced.
+    pub fn replace_service(
+        &mut self,
+        service_name: impl Into<Cow<'static, str>>,
+        factory: Box<dyn HandlerFactory>,
+    ) -> bool {
+        self.services.replace(service_name.into(), factory)
+    }
+
+    /// Unregisters the methods whose fully qualified names start with
+    /// `prefix`, e.g. `junk.` for the service `junk`, or `shard` for all the
+    /// services named like `shard7`. Returns how many methods are removed.
+    pub fn remove_service(&mut self, prefix: &str) -> usize {
+        self.services.remove_prefix(prefix)
+    }
+
+    /// Replaces the handler of a registered method, e.g. `junk.handler2`,
+    /// the other methods of the service are not affected. Fails with
+    /// `Error::Unimplemented` if the method is not registered.
+    pub fn replace_handler(&mut self, fq_name: &str, handler: Arc<MethodHandler>) -> Result<()> {
+        match self.services.swap(fq_name, handler) {
+            Some(_) => Ok(()),
+            None => Err(self.services.unknown_method(fq_name)),
         }
     }
 
+    /// Unregisters a method, the service is unregistered with its last
+    /// method. Returns whether it had registered.
+    pub fn remove_handler(&mut self, fq_name: &str) -> bool {
+        self.services.remove_method(fq_name)
+    }
+
+    /// The fully qualified names of the methods registered so far, sorted.
+    /// The reflection and health check services are registered on `build`.
+    pub fn method_names(&self) -> Vec<String> {
+        self.services.method_names()
+    }
+
     pub fn build(self) -> Server {
-        Server {
-            core: Arc::new(ServerCore {
+        self.build_with_middleware(vec![])
+    }
+
+    /// Builds the server, whose handlers are wrapped in `middlewares`, the
+    /// first one outermost. Their `before`s are called in order before the
+    /// handler and their `after`s in reverse order after it, the first
+    /// `Err` of them is replied to the client, skipping the rest.
+    pub fn build_with_middleware(mut self, middlewares: Vec<Box<dyn Middleware>>) -> Server {
+        let core = Arc::new_cyclic(|server| {
+            if self.reflection {
+                let reflection = Reflection {
+                    server: server.clone(),
+                };
+                reflection::add_service(reflection, &mut self).unwrap();
+            }
+            if self.health {
+                let health = Health {
+                    server: server.clone(),
+                    started: Instant::now(),
+                };
+                health::add_service(health, &mut self).unwrap();
+            }
+            let mut method_stats: HashMap<_, _> = self
+                .services
+                .method_names()
+                .into_iter()
+                .map(|name| (name, Arc::default()))
+                .collect();
+            method_stats.insert(UNKNOWN_METHOD.to_owned(), Arc::default());
+            ServerCore {
                 name: self.name,
-                services: self.services,
+                services: RwLock::new(self.services),
+                interceptors: self.interceptors,
+                middlewares,
+                default_handler: self.default_handler,
+                method_stats: RwLock::new(method_stats),
+                client_counts: if self.client_counts {
+                    Some(Mutex::default())
+                } else {
+                    None
+                },
+                latency_stats: self.latency_stats,
                 id: ID_ALLOC.fetch_add(1, Ordering::Relaxed),
                 count: AtomicUsize::new(0),
-            }),
-        }
+                in_flight: AtomicUsize::new(0),
+                idle: (Mutex::new(()), Condvar::new()),
+                max_queue_depth: AtomicUsize::new(usize::MAX),
+                shutdown: AtomicBool::new(false),
+                permits: self.max_concurrency.map(|n| Arc::new(Semaphore::new(n))),
+                max_response_size: self.max_response_size,
+                method_delays: Mutex::default(),
+                kill_waiters: Mutex::default(),
+            }
+        });
+        Server { core }
     }
 }
 
@@ -64,8 +452,34 @@ pub(crate) struct ServerCore {
     pub(crate) name: String,
     pub(crate) id: usize,
 
-    pub(crate) services: HashMap<&'static str, Box<dyn HandlerFactory>>,
+    pub(crate) services: RwLock<Services>,
+    pub(crate) interceptors: Vec<Arc<dyn RpcHooks>>,
+    // wrap the handlers, the first one outermost
+    middlewares: Vec<Box<dyn Middleware>>,
+    default_handler: Option<Arc<DefaultHandler>>,
     pub(crate) count: AtomicUsize,
+    // fq_name -> stats, unknown methods are counted under UNKNOWN_METHOD
+    pub(crate) method_stats: RwLock<HashMap<String, Arc<MethodStats>>>,
+    // client end name -> dispatch count, if enabled
+    client_counts: Option<Mutex<HashMap<String, usize>>>,
+    // whether to record how long the handlers take
+    latency_stats: bool,
+    // number of requests being dispatched
+    pub(crate) in_flight: AtomicUsize,
+    // notified when in_flight drops to 0, see `Server::wait_idle`
+    idle: (Mutex<()>, Condvar),
+    // requests are rejected when in_flight reaches it
+    pub(crate) max_queue_depth: AtomicUsize,
+    // new requests are rejected once it is set
+    pub(crate) shutdown: AtomicBool,
+    // limits the requests dispatched at the same time
+    permits: Option<Arc<Semaphore>>,
+    // replies larger than it are rejected
+    max_response_size: Option<usize>,
+    // fq_name -> the time the handler takes before it starts
+    method_delays: Mutex<HashMap<String, Duration>>,
+    // notified when the server is killed, one for each RPC in flight
+    kill_waiters: Mutex<Vec<oneshot::Sender<()>>>,
 }
 
 #[derive(Clone)]
@@ -78,39 +492,478 @@ impl Server {
         self.core.count.load(Ordering::Relaxed)
     }
 
+    /// Resets the counts of the dispatches and the latency statistics.
+    pub fn reset_count(&self) {
+        self.core.count.store(0, Ordering::Relaxed);
+        if let Some(counts) = &self.core.client_counts {
+            counts.lock().unwrap().clear();
+        }
+        for stats in self.core.method_stats.read().unwrap().values() {
+            stats.reset();
+        }
+    }
+
+    /// The number of dispatches of a method.
+    pub fn method_count(&self, fq_name: &str) -> usize {
+        self.core
+            .method_stats
+            .read()
+            .unwrap()
+            .get(fq_name)
+            .map_or(0, |stats| stats.count.load(Ordering::Relaxed))
+    }
+
+    /// The number of dispatches of every method, sorted by the names.
+    pub fn method_counts(&self) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = self
+            .core
+            .method_stats
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| (name.clone(), stats.count.load(Ordering::Relaxed)))
+            .collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// The number of dispatches of the requests from every client end,
+    /// sorted by the names. It is empty unless enabled by
+    /// `ServerBuilder::enable_client_counts`.
+    pub fn count_by_client(&self) -> Vec<(String, usize)> {
+        let counts = match &self.core.client_counts {
+            Some(counts) => counts.lock().unwrap(),
+            None => return vec![],
+        };
+        let mut counts: Vec<_> = counts.iter().map(|(name, n)| (name.clone(), *n)).collect();
+        counts.sort_unstable();
+        counts
+    }
+
+    /// How long the handlers of a method have taken, from the time they
+    /// start to the time they reply. It is all zero if the latency statistics
+    /// are disabled.
+    pub fn method_latency(&self, fq_name: &str) -> MethodLatency {
+        self.core
+            .method_stats
+            .read()
+            .unwrap()
+            .get(fq_name)
+            .map(|stats| stats.latency())
+            .unwrap_or_default()
+    }
+
     pub fn name(&self) -> &str {
         &self.core.name
     }
 
-    pub(crate) fn dispatch(&self, fq_name: &'static str, req: &[u8]) -> RpcFuture<Result<Vec<u8>>> {
+    /// The limit of the size of replies, see
+    /// `ServerBuilder::set_max_response_size`.
+    pub fn max_response_size(&self) -> Option<usize> {
+        self.core.max_response_size
+    }
+
+    /// The number of requests that are being dispatched.
+    pub fn in_flight(&self) -> usize {
+        self.core.in_flight.load(Ordering::Acquire)
+    }
+
+    /// Rejects new requests with `Error::Shutdown`, the requests being
+    /// dispatched are left to finish. See also `wait_idle`.
+    pub fn shutdown(&self) {
+        self.core.shutdown.store(true, Ordering::Release);
+    }
+
+    /// Whether the server has been shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.core.shutdown.load(Ordering::Acquire)
+    }
+
+    /// Blocks until there is no request being dispatched, returns `false` if
+    /// it does not happen within `timeout`.
+    pub fn wait_idle(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let (lock, idle) = &self.core.idle;
+        let mut guard = lock.lock().unwrap();
+        while self.in_flight() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            guard = idle.wait_timeout(guard, deadline - now).unwrap().0;
+        }
+        true
+    }
+
+    /// Makes the handler of the method wait `delay` before it starts, as if it
+    /// took long to compute. The reply is sent after the delay.
+    pub fn set_method_delay(&self, fq_name: &str, delay: Duration) {
+        let mut delays = self.core.method_delays.lock().unwrap();
+        delays.insert(fq_name.to_owned(), delay);
+    }
+
+    /// Removes the delay set by `set_method_delay`.
+    pub fn clear_method_delay(&self, fq_name: &str) {
+        self.core.method_delays.lock().unwrap().remove(fq_name);
+    }
+
+    /// Returns a receiver completed once the server is killed by the
+    /// network.
+    pub(crate) fn killed(&self) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        let mut waiters = self.core.kill_waiters.lock().unwrap();
+        // forget the RPCs that have finished.
+        waiters.retain(|tx| !tx.is_canceled());
+        waiters.push(tx);
+        rx
+    }
+
+    /// Notifies the RPCs in flight that the server has been killed.
+    pub(crate) fn kill(&self) {
+        for tx in self.core.kill_waiters.lock().unwrap().drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Waits for a turn to dispatch a request, the request can be dispatched
+    /// until the returned permit is dropped.
+    pub(crate) async fn acquire(&self) -> Option<Permit> {
+        match self.core.permits {
+            Some(ref permits) => Some(permits.clone().acquire().await),
+            None => None,
+        }
+    }
+
+    /// Tries to reserve a slot in the dispatch queue, returns `None` if the
+    /// queue is full.
+    pub(crate) fn enqueue(&self) -> Option<Queued<'_>> {
+        let depth = self.core.max_queue_depth.load(Ordering::Acquire);
+        let prev = self.core.in_flight.fetch_add(1, Ordering::AcqRel);
+        let queued = Queued { core: &self.core };
+        if prev >= depth {
+            None
+        } else {
+            Some(queued)
+        }
+    }
+
+    /// The fully qualified names of the methods it serves, sorted.
+    pub fn method_names(&self) -> Vec<String> {
+        self.core.services.read().unwrap().method_names()
+    }
+
+    /// Whether it serves the method, `fq_name` is like `junk.handler2`.
+    pub fn has_method(&self, fq_name: &str) -> bool {
+        let services = self.core.services.read().unwrap();
+        services.find(fq_name).is_some()
+    }
+
+    /// Registers a service on the running server, fails if a service of the
+    /// same name has already registered. The requests dispatched meanwhile
+    /// see either all or none of its methods.
+    pub fn add_service(
+        &self,
+        service_name: impl Into<Cow<'static, str>>,
+        factory: Box<dyn HandlerFactory>,
+    ) -> Result<()> {
+        let service_name = service_name.into();
+        let mut services = self.core.services.write().unwrap();
+        let mut stats = self.core.method_stats.write().unwrap();
+        let names = factory.method_names();
+        services.add(service_name.clone(), factory)?;
+        for name in names {
+            stats
+                .entry(format!("{}.{}", service_name, name))
+                .or_default();
+        }
+        Ok(())
+    }
+
+    /// Registers a service on the running server, replacing the registered
+    /// one of the same name if any. The requests being dispatched finish
+    /// with the old service, the following ones are dispatched to the new
+    /// one. Returns whether a service was replaced.
+    pub fn replace_service(
+        &self,
+        service_name: impl Into<Cow<'static, str>>,
+        factory: Box<dyn HandlerFactory>,
+    ) -> bool {
+        let service_name = service_name.into();
+        let mut services = self.core.services.write().unwrap();
+        let mut stats = self.core.method_stats.write().unwrap();
+        for name in factory.method_names() {
+            stats
+                .entry(format!("{}.{}", service_name, name))
+                .or_default();
+        }
+        services.replace(service_name, factory)
+    }
+
+    /// Unregisters the methods whose fully qualified names start with
+    /// `prefix` from the running server, see `ServerBuilder::remove_service`.
+    /// The RPCs to them fail with `Error::Unimplemented` since then. Returns
+    /// how many methods are removed.
+    pub fn remove_service_live(&self, prefix: &str) -> usize {
+        self.core.services.write().unwrap().remove_prefix(prefix)
+    }
+
+    /// Unregisters a method, e.g. `junk.handler2`, the RPCs to it fail with
+    /// `Error::Unimplemented` since then while the other methods of the
+    /// service are still served. Fails with `Error::Unimplemented` if the
+    /// method is not registered.
+    pub fn remove_handler(&self, fq_name: &str) -> Result<()> {
+        let mut services = self.core.services.write().unwrap();
+        if services.remove_method(fq_name) {
+            Ok(())
+        } else {
+            Err(services.unknown_method(fq_name))
+        }
+    }
+
+    /// Replaces the handler of a registered method without restarting the
+    /// server, e.g. to simulate an upgrade. The requests dispatched after it
+    /// returns are handled by `handler`, until the service is replaced or
+    /// removed. Returns the replaced handler, which can be swapped back
+    /// later. Fails with `Error::Unimplemented` if the method is not
+    /// registered.
+    pub fn hot_swap_handler(
+        &self,
+        fq_name: &str,
+        handler: Arc<MethodHandler>,
+    ) -> Result<Arc<MethodHandler>> {
+        let mut services = self.core.services.write().unwrap();
+        match services.swap(fq_name, handler) {
+            Some(old) => Ok(old),
+            None => Err(services.unknown_method(fq_name)),
+        }
+    }
+
+    pub(crate) fn dispatch(
+        &self,
+        ctx: &RpcContext,
+        fq_name: &str,
+        req: &[u8],
+    ) -> RpcFuture<Result<Vec<u8>>> {
         self.core.count.fetch_add(1, Ordering::Relaxed);
-        let mut names = fq_name.split('.');
-        let service_name = match names.next() {
-            Some(n) => n,
-            None => {
-                return Box::pin(future::err(Error::Unimplemented(format!(
-                    "unknown {}",
-                    fq_name
-                ))));
+        if let Some(counts) = &self.core.client_counts {
+            let mut counts = counts.lock().unwrap();
+            match counts.get_mut(ctx.end_name()) {
+                Some(n) => *n += 1,
+                None => {
+                    counts.insert(ctx.end_name().to_owned(), 1);
+                }
+            }
+        }
+        let stats = {
+            let stats = self.core.method_stats.read().unwrap();
+            match stats.get(fq_name) {
+                Some(stats) => {
+                    stats.count.fetch_add(1, Ordering::Relaxed);
+                    Some(stats.clone())
+                }
+                None => {
+                    stats[UNKNOWN_METHOD].count.fetch_add(1, Ordering::Relaxed);
+                    None
+                }
             }
         };
-        let method_name = match names.next() {
-            Some(n) => n,
-            None => {
-                return Box::pin(future::err(Error::Unimplemented(format!(
-                    "unknown {}",
-                    fq_name
-                ))));
+        if self.is_shutdown() {
+            return Box::pin(future::err(Error::Shutdown));
+        }
+        for interceptor in &self.core.interceptors {
+            if let Err(e) = interceptor.before_dispatch(fq_name, req) {
+                return Box::pin(future::err(e));
+            }
+        }
+        for middleware in &self.core.middlewares {
+            if let Err(e) = middleware.before(fq_name, req) {
+                return Box::pin(future::err(e));
             }
+        }
+        let mut fut = self.dispatch_to_handler(ctx, fq_name, req);
+        if !self.core.middlewares.is_empty() {
+            let server = self.clone();
+            let fq_name = fq_name.to_owned();
+            fut = Box::pin(fut.map(move |res| {
+                let rsp = res?;
+                for middleware in server.core.middlewares.iter().rev() {
+                    middleware.after(&fq_name, &rsp)?;
+                }
+                Ok(rsp)
+            }));
+        }
+        if let Some(limit) = self.core.max_response_size {
+            fut = Box::pin(fut.map(move |res| match res {
+                Ok(resp) if resp.len() > limit => {
+                    debug!("reply too large {} bytes", resp.len());
+                    Err(Error::MessageTooLarge)
+                }
+                res => res,
+            }));
+        }
+        let delay = self
+            .core
+            .method_delays
+            .lock()
+            .unwrap()
+            .get(fq_name)
+            .cloned();
+        if let Some(delay) = delay {
+            fut = Box::pin(async move {
+                Delay::new(delay).await;
+                fut.await
+            });
+        }
+        if let Some(stats) = stats.filter(|_| self.core.latency_stats) {
+            fut = Box::pin(async move {
+                let start = Instant::now();
+                let res = fut.await;
+                stats.record(start.elapsed());
+                res
+            });
+        }
+        if self.core.interceptors.is_empty() {
+            return fut;
+        }
+        let server = self.clone();
+        let fq_name = fq_name.to_owned();
+        Box::pin(fut.map(move |res| {
+            server
+                .core
+                .interceptors
+                .iter()
+                .fold(res, |res, interceptor| {
+                    interceptor.after_dispatch(&fq_name, res)
+                })
+        }))
+    }
+
+    fn dispatch_to_handler(
+        &self,
+        ctx: &RpcContext,
+        fq_name: &str,
+        req: &[u8],
+    ) -> RpcFuture<Result<Vec<u8>>> {
+        let services = self.core.services.read().unwrap();
+        let swapped = services.swapped.get(fq_name);
+        // A panicking handler must not take down the network, report it
+        // to the client instead.
+        let res = match (services.find(fq_name), swapped, &self.core.default_handler) {
+            (Some(_), Some(handler), _) => {
+                panic::catch_unwind(AssertUnwindSafe(|| handler(ctx, req)))
+            }
+            (Some((factory, method_name)), None, _) => {
+                panic::catch_unwind(AssertUnwindSafe(|| {
+                    let handle = factory.handler(method_name);
+                    handle(ctx, req)
+                }))
+            }
+            (None, _, Some(handler)) => {
+                panic::catch_unwind(AssertUnwindSafe(|| handler(ctx, fq_name, req)))
+            }
+            (None, _, None) => return Box::pin(future::err(services.unknown_method(fq_name))),
         };
-        if let Some(factory) = self.core.services.get(service_name) {
-            let handle = factory.handler(method_name);
-            handle(req)
-        } else {
-            Box::pin(future::err(Error::Unimplemented(format!(
-                "unknown {}",
-                fq_name
-            ))))
+        match res {
+            Ok(fut) => Box::pin(
+                AssertUnwindSafe(fut)
+                    .catch_unwind()
+                    .map(|res| res.unwrap_or_else(|e| Err(Error::HandlerPanic(panic_message(e))))),
+            ),
+            Err(e) => Box::pin(future::err(Error::HandlerPanic(panic_message(e)))),
+        }
+    }
+}
+
+fn panic_message(e: Box<dyn Any + Send>) -> String {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_owned()
+    }
+}
+
+/// An asynchronous semaphore, the waiters get their permits in FIFO order.
+pub(crate) struct Semaphore {
+    state: Mutex<SemaphoreState>,
+}
+
+struct SemaphoreState {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            state: Mutex::new(SemaphoreState {
+                available: permits,
+                waiters: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub(crate) async fn acquire(self: Arc<Self>) -> Permit {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                drop(state);
+                return Permit {
+                    semaphore: Some(self),
+                };
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            rx
+        };
+        // the semaphore lives as long as the sender, so it never fails.
+        rx.await.unwrap()
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(waiter) = state.waiters.pop_front() {
+            let permit = Permit {
+                semaphore: Some(self.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // the waiter is gone, it is released to the next one.
+                Err(mut permit) => permit.semaphore = None,
+            }
+        }
+        state.available += 1;
+    }
+}
+
+/// A permit to dispatch a request, it is released on drop.
+pub(crate) struct Permit {
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.semaphore.take() {
+            semaphore.release();
+        }
+    }
+}
+
+/// A slot in the dispatch queue of a server, it is released on drop.
+pub(crate) struct Queued<'a> {
+    core: &'a ServerCore,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if self.core.in_flight.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // the waiters check in_flight under the lock, so that they
+            // cannot miss the notification.
+            let (lock, idle) = &self.core.idle;
+            let _guard = lock.lock().unwrap();
+            idle.notify_all();
         }
     }
 }
@@ -120,6 +973,7 @@ impl fmt::Debug for Server {
         f.debug_struct("Server")
             .field("name", &self.core.name)
             .field("id", &self.core.id)
+            .field("methods", &self.method_names())
             .finish()
     }
 }
@@ -0,0 +1,143 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use prost::encoding::{decode_varint, encode_varint};
+
+use crate::error::{Error, Result};
+
+// A stream is replied as one encoded reply holding its chunks one after
+// another, each framed as `varint(len + 1) chunk`, and a `varint(0)`
+// marking the end of the stream.
+const END_OF_STREAM: u64 = 0;
+
+/// The chunks sent by a server-streaming method, declared as `rpc
+/// name(Args) returns stream Chunk;` in `service!`.
+///
+/// The chunks are replied when the method returns `Ok`, a method failing
+/// fails the call without replying any chunk.
+pub struct ChunkSink<T> {
+    // None once the stream has ended.
+    frames: Arc<Mutex<Option<Vec<u8>>>>,
+    _chunk: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for ChunkSink<T> {
+    fn clone(&self) -> ChunkSink<T> {
+        ChunkSink {
+            frames: self.frames.clone(),
+            _chunk: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for ChunkSink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChunkSink")
+            .field("ended", &self.frames.lock().unwrap().is_none())
+            .finish()
+    }
+}
+
+impl<T: labcodec::Message> ChunkSink<T> {
+    /// Creates a sink of its own, e.g. to call a streaming method of a
+    /// service directly in a test.
+    pub fn new() -> ChunkSink<T> {
+        ChunkSink {
+            frames: Arc::new(Mutex::new(Some(vec![]))),
+            _chunk: PhantomData,
+        }
+    }
+
+    /// Appends a chunk to the stream, fails with `Error::Stopped` if the
+    /// stream has ended, i.e. the method has returned.
+    pub fn send(&self, chunk: &T) -> Result<()> {
+        let mut buf = vec![];
+        labcodec::encode(chunk, &mut buf).map_err(Error::Encode)?;
+        let mut frames = self.frames.lock().unwrap();
+        let frames = frames.as_mut().ok_or(Error::Stopped)?;
+        encode_varint(buf.len() as u64 + 1, frames);
+        frames.extend_from_slice(&buf);
+        Ok(())
+    }
+
+    /// Ends the stream and returns the encoded reply of it, the chunks sent
+    /// through the clones of the sink afterwards are rejected.
+    pub fn finish(&self) -> Vec<u8> {
+        let mut frames = self.frames.lock().unwrap().take().unwrap_or_default();
+        encode_varint(END_OF_STREAM, &mut frames);
+        frames
+    }
+}
+
+/// The chunks replied by a server-streaming method, decoded one by one as it
+/// is iterated.
+pub struct ChunkStream<T> {
+    frames: Vec<u8>,
+    pos: usize,
+    ended: bool,
+    _chunk: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for ChunkStream<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChunkStream")
+            .field("pos", &self.pos)
+            .field("len", &self.frames.len())
+            .field("ended", &self.ended)
+            .finish()
+    }
+}
+
+impl<T: labcodec::Message> ChunkStream<T> {
+    /// Reads the chunks of an encoded reply made by `ChunkSink::finish`.
+    pub fn new(frames: Vec<u8>) -> ChunkStream<T> {
+        ChunkStream {
+            frames,
+            pos: 0,
+            ended: false,
+            _chunk: PhantomData,
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<T>> {
+        let mut rest = &self.frames[self.pos..];
+        if rest.is_empty() {
+            return Err(Error::Other("stream ended without its end".to_owned()));
+        }
+        let len = decode_varint(&mut rest).map_err(Error::Decode)?;
+        self.pos = self.frames.len() - rest.len();
+        if len == END_OF_STREAM {
+            return Ok(None);
+        }
+        let len = (len - 1) as usize;
+        if len > rest.len() {
+            return Err(Error::Other(format!(
+                "chunk of {} bytes in {} bytes left",
+                len,
+                rest.len()
+            )));
+        }
+        self.pos += len;
+        labcodec::decode(&rest[..len])
+            .map(Some)
+            .map_err(Error::Decode)
+    }
+}
+
+impl<T: labcodec::Message> Iterator for ChunkStream<T> {
+    type Item = Result<T>;
+
+    /// The next chunk, or an error if the reply is malformed, which ends
+    /// the stream.
+    fn next(&mut self) -> Option<Result<T>> {
+        if self.ended {
+            return None;
+        }
+        let res = self.next_chunk();
+        if !matches!(res, Ok(Some(_))) {
+            self.ended = true;
+        }
+        res.transpose()
+    }
+}
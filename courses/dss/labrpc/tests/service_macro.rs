@@ -0,0 +1,92 @@
+//! `service!` used by another crate, in a nested module importing nothing.
+
+#[derive(Clone, PartialEq, prost_derive::Message)]
+pub struct HelloArgs {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost_derive::Message)]
+pub struct HelloReply {
+    #[prost(string, tag = "1")]
+    pub greeting: String,
+}
+
+pub mod outer {
+    pub mod inner {
+        labrpc::service! {
+            /// Greets the caller.
+            service greeter {
+                rpc hello(crate::HelloArgs) returns (crate::HelloReply);
+                #[timeout_ms = 1000]
+                rpc hello_ctx(crate::HelloArgs) returns (crate::HelloReply) with ctx;
+                rpc ping() returns ();
+                rpc hellos(crate::HelloArgs) returns stream crate::HelloReply;
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct Greeter;
+
+        #[async_trait::async_trait]
+        impl greeter::Service for Greeter {
+            async fn hello(&self, args: crate::HelloArgs) -> labrpc::Result<crate::HelloReply> {
+                Ok(crate::HelloReply {
+                    greeting: format!("hello {}", args.name),
+                })
+            }
+
+            async fn hello_ctx(
+                &self,
+                ctx: labrpc::RpcContext,
+                args: crate::HelloArgs,
+            ) -> labrpc::Result<crate::HelloReply> {
+                Ok(crate::HelloReply {
+                    greeting: format!("hello {} from {}", args.name, ctx.end_name()),
+                })
+            }
+
+            async fn ping(&self) -> labrpc::Result<()> {
+                Ok(())
+            }
+
+            async fn hellos(
+                &self,
+                args: crate::HelloArgs,
+                sink: labrpc::ChunkSink<crate::HelloReply>,
+            ) -> labrpc::Result<()> {
+                for i in 0..2 {
+                    sink.send(&crate::HelloReply {
+                        greeting: format!("hello {} #{}", args.name, i),
+                    })?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_service_in_other_crate() {
+    use outer::inner::{greeter, Greeter};
+
+    let mut builder = labrpc::ServerBuilder::new("server".to_owned());
+    greeter::add_service(Greeter, &mut builder).unwrap();
+    let net = labrpc::Network::new();
+    net.add_server(builder.build());
+    let client = greeter::Client::connect(&net, "client".to_owned(), "server");
+
+    let args = HelloArgs {
+        name: "labrpc".to_owned(),
+    };
+    let reply = futures::executor::block_on(client.hello(&args)).unwrap();
+    assert_eq!(reply.greeting, "hello labrpc");
+    let reply = futures::executor::block_on(client.hello_ctx(&args)).unwrap();
+    assert_eq!(reply.greeting, "hello labrpc from client");
+    futures::executor::block_on(client.ping()).unwrap();
+    let replies = futures::executor::block_on(client.hellos(&args))
+        .unwrap()
+        .map(|reply| reply.unwrap().greeting)
+        .collect::<Vec<_>>();
+    assert_eq!(replies, ["hello labrpc #0", "hello labrpc #1"]);
+}
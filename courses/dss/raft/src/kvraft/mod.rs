@@ -3,5 +3,6 @@ pub mod client;
 pub mod config;
 pub mod errors;
 pub mod server;
+pub mod shardctrl;
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::proto::shardctrlpb::*;
+
+pub struct Clerk {
+    pub name: String,
+    pub servers: Vec<ShardCtrlClient>,
+    // You will have to modify this struct.
+}
+
+impl fmt::Debug for Clerk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clerk").field("name", &self.name).finish()
+    }
+}
+
+impl Clerk {
+    pub fn new(name: String, servers: Vec<ShardCtrlClient>) -> Clerk {
+        // You'll have to add code here.
+        // Clerk { name, servers }
+        crate::your_code_here((name, servers))
+    }
+
+    /// fetches the config of the number, -1 for the latest one.
+    /// keeps trying forever in the face of all other errors.
+    pub fn query(&self, num: i64) -> Config {
+        // You will have to modify this function.
+        crate::your_code_here(num)
+    }
+
+    /// adds new replica groups.
+    pub fn join(&self, groups: HashMap<u64, Servers>) {
+        // You will have to modify this function.
+        crate::your_code_here(groups)
+    }
+
+    /// removes replica groups.
+    pub fn leave(&self, gids: Vec<u64>) {
+        // You will have to modify this function.
+        crate::your_code_here(gids)
+    }
+
+    /// assigns a shard to a replica group.
+    pub fn move_shard(&self, shard: u64, gid: u64) {
+        // You will have to modify this function.
+        crate::your_code_here((shard, gid))
+    }
+}
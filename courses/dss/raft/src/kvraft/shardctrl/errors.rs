@@ -0,0 +1,25 @@
+use std::{error, fmt, result};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The gid 0 is reserved for the shards not assigned to any group.
+    ReservedGid,
+    /// The shard isn't less than `N_SHARDS`.
+    InvalidShard(u64),
+    /// The group hasn't joined.
+    UnknownGroup(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
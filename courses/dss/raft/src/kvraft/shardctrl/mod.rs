@@ -0,0 +1,167 @@
+//! The shard controller, it manages a sequence of numbered configurations,
+//! each assigns the shards to the replica groups. It is replicated by Raft,
+//! every server applies the committed operations to a [`ShardCtrl`].
+//!
+//! An invalid operation leaves the configs unchanged and returns an
+//! [`Error`](errors::Error), the server replies it in `ChangeReply.err`.
+//!
+//! Only the state machine is implemented here. [`server`] and [`client`] are
+//! left to the lab like the rest of kvraft: replicating the controller needs
+//! the Raft `Node` from Lab 2, which is a skeleton in this crate.
+
+use std::collections::HashMap;
+
+use self::errors::{Error, Result};
+use crate::proto::shardctrlpb::*;
+
+pub mod client;
+pub mod errors;
+pub mod server;
+#[cfg(test)]
+mod tests;
+
+/// The number of shards.
+pub const N_SHARDS: usize = 10;
+
+/// The replicated state machine of the shard controller.
+pub struct ShardCtrl {
+    // indexed by config num, configs[0] assigns no shard.
+    configs: Vec<Config>,
+}
+
+impl Default for ShardCtrl {
+    fn default() -> ShardCtrl {
+        ShardCtrl::new()
+    }
+}
+
+impl ShardCtrl {
+    pub fn new() -> ShardCtrl {
+        let first = Config {
+            num: 0,
+            shards: vec![0; N_SHARDS],
+            groups: HashMap::new(),
+        };
+        ShardCtrl {
+            configs: vec![first],
+        }
+    }
+
+    /// Adds new replica groups and rebalances the shards.
+    pub fn join(&mut self, groups: HashMap<u64, Servers>) -> Result<()> {
+        if groups.contains_key(&0) {
+            return Err(Error::ReservedGid);
+        }
+        let mut config = self.next_config();
+        config.groups.extend(groups);
+        rebalance(&mut config);
+        self.configs.push(config);
+        Ok(())
+    }
+
+    /// Removes replica groups and hands their shards to the remaining ones.
+    pub fn leave(&mut self, gids: &[u64]) -> Result<()> {
+        let mut config = self.next_config();
+        if gids.contains(&0) {
+            return Err(Error::ReservedGid);
+        }
+        if let Some(&gid) = gids.iter().find(|gid| !config.groups.contains_key(gid)) {
+            return Err(Error::UnknownGroup(gid));
+        }
+        for gid in gids {
+            config.groups.remove(gid);
+        }
+        for shard in config.shards.iter_mut() {
+            if gids.contains(shard) {
+                *shard = 0;
+            }
+        }
+        rebalance(&mut config);
+        self.configs.push(config);
+        Ok(())
+    }
+
+    /// Assigns a shard to a replica group, without rebalancing.
+    pub fn move_shard(&mut self, shard: u64, gid: u64) -> Result<()> {
+        if shard >= N_SHARDS as u64 {
+            return Err(Error::InvalidShard(shard));
+        }
+        let mut config = self.next_config();
+        if !config.groups.contains_key(&gid) {
+            return Err(Error::UnknownGroup(gid));
+        }
+        config.shards[shard as usize] = gid;
+        self.configs.push(config);
+        Ok(())
+    }
+
+    /// Returns the config of the number, or the latest one if the number is
+    /// -1 or larger than the latest number.
+    pub fn query(&self, num: i64) -> Config {
+        if num < 0 || num as usize >= self.configs.len() {
+            self.configs.last().unwrap().clone()
+        } else {
+            self.configs[num as usize].clone()
+        }
+    }
+
+    fn next_config(&self) -> Config {
+        let mut config = self.configs.last().unwrap().clone();
+        config.num += 1;
+        config
+    }
+}
+
+/// Balances the shards across the groups, moving as few shards as possible.
+///
+/// Every group ends up with `N_SHARDS / groups` or one more shards. The
+/// groups holding the most shards keep the extra ones, the ties are broken by
+/// gid, so every server computes the same assignment.
+fn rebalance(config: &mut Config) {
+    if config.groups.is_empty() {
+        config.shards = vec![0; N_SHARDS];
+        return;
+    }
+
+    let mut owned: HashMap<u64, Vec<usize>> =
+        config.groups.keys().map(|&gid| (gid, vec![])).collect();
+    let mut free = vec![];
+    for (shard, gid) in config.shards.iter().enumerate() {
+        match owned.get_mut(gid) {
+            Some(shards) => shards.push(shard),
+            None => free.push(shard),
+        }
+    }
+
+    let mut gids: Vec<u64> = owned.keys().cloned().collect();
+    gids.sort_by_key(|gid| (usize::MAX - owned[gid].len(), *gid));
+    let target = N_SHARDS / gids.len();
+    let extra = N_SHARDS % gids.len();
+    let quota = |i: usize| if i < extra { target + 1 } else { target };
+
+    // takes the shards beyond the quotas.
+    for (i, gid) in gids.iter().enumerate() {
+        let shards = owned.get_mut(gid).unwrap();
+        while shards.len() > quota(i) {
+            free.push(shards.pop().unwrap());
+        }
+    }
+    // gives them to the groups below the quotas.
+    free.sort_unstable();
+    let mut free = free.into_iter();
+    for (i, gid) in gids.iter().enumerate() {
+        let shards = owned.get_mut(gid).unwrap();
+        while shards.len() < quota(i) {
+            match free.next() {
+                Some(shard) => shards.push(shard),
+                None => break,
+            }
+        }
+    }
+
+    for (gid, shards) in owned {
+        for shard in shards {
+            config.shards[shard] = gid;
+        }
+    }
+}
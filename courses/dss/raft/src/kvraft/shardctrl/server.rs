@@ -0,0 +1,79 @@
+use futures::channel::mpsc::unbounded;
+
+use super::ShardCtrl;
+use crate::proto::shardctrlpb::*;
+use crate::raft;
+
+pub struct ShardCtrlServer {
+    pub rf: raft::Node,
+    me: usize,
+    // the configs, updated by the committed operations
+    ctrl: ShardCtrl,
+    // Your definitions here.
+}
+
+impl ShardCtrlServer {
+    pub fn new(
+        servers: Vec<crate::proto::raftpb::RaftClient>,
+        me: usize,
+        persister: Box<dyn raft::persister::Persister>,
+    ) -> ShardCtrlServer {
+        // You may need initialization code here.
+
+        let (tx, apply_ch) = unbounded();
+        let rf = raft::Raft::new(servers, me, persister, tx);
+
+        crate::your_code_here((rf, apply_ch))
+    }
+}
+
+impl ShardCtrlServer {
+    /// Only for suppressing deadcode warnings.
+    #[doc(hidden)]
+    pub fn __suppress_deadcode(&mut self) {
+        let _ = &self.me;
+        let _ = &self.ctrl;
+    }
+}
+
+#[derive(Clone)]
+pub struct Node {
+    // Your definitions here.
+}
+
+impl Node {
+    pub fn new(ctrl: ShardCtrlServer) -> Node {
+        // Your code here.
+        crate::your_code_here(ctrl);
+    }
+
+    /// the tester calls kill() when a ShardCtrlServer instance won't
+    /// be needed again.
+    pub fn kill(&self) {
+        // Your code here, if desired.
+    }
+}
+
+#[async_trait::async_trait]
+impl ShardCtrlService for Node {
+    // CAVEATS: Please avoid locking or sleeping here, it may jam the network.
+    async fn join(&self, arg: JoinRequest) -> labrpc::Result<ChangeReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    async fn leave(&self, arg: LeaveRequest) -> labrpc::Result<ChangeReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    async fn move_shard(&self, arg: MoveRequest) -> labrpc::Result<ChangeReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+
+    async fn query(&self, arg: QueryRequest) -> labrpc::Result<QueryReply> {
+        // Your code here.
+        crate::your_code_here(arg)
+    }
+}
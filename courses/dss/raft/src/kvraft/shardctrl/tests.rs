@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+
+use super::*;
+
+fn groups(gids: &[u64]) -> HashMap<u64, Servers> {
+    gids.iter()
+        .map(|&gid| {
+            let names = (0..3).map(|i| format!("server-{}-{}", gid, i)).collect();
+            (gid, Servers { names })
+        })
+        .collect()
+}
+
+// Checks every group has N_SHARDS / groups or one more shards.
+fn check_balanced(config: &Config) {
+    assert_eq!(config.shards.len(), N_SHARDS);
+    if config.groups.is_empty() {
+        assert!(config.shards.iter().all(|&gid| gid == 0));
+        return;
+    }
+    let mut counts: HashMap<u64, usize> = config.groups.keys().map(|&gid| (gid, 0)).collect();
+    for gid in &config.shards {
+        *counts
+            .get_mut(gid)
+            .expect("shard assigned to unknown group") += 1;
+    }
+    let min = counts.values().min().unwrap();
+    let max = counts.values().max().unwrap();
+    assert!(max - min <= 1, "unbalanced {:?}", config);
+}
+
+fn moved(from: &Config, to: &Config) -> usize {
+    from.shards
+        .iter()
+        .zip(&to.shards)
+        .filter(|(a, b)| a != b && **a != 0)
+        .count()
+}
+
+#[test]
+fn test_join_leave_4a() {
+    let mut ctrl = ShardCtrl::new();
+    assert_eq!(ctrl.query(-1).num, 0);
+    check_balanced(&ctrl.query(-1));
+
+    ctrl.join(groups(&[1])).unwrap();
+    let c1 = ctrl.query(-1);
+    assert_eq!(c1.num, 1);
+    assert!(c1.shards.iter().all(|&gid| gid == 1));
+
+    ctrl.join(groups(&[2])).unwrap();
+    let c2 = ctrl.query(-1);
+    check_balanced(&c2);
+    assert_eq!(moved(&c1, &c2), N_SHARDS / 2);
+
+    ctrl.join(groups(&[3, 4])).unwrap();
+    let c3 = ctrl.query(-1);
+    check_balanced(&c3);
+    // only the shards of the new groups move.
+    let new = c3.shards.iter().filter(|&&gid| gid >= 3).count();
+    assert_eq!(moved(&c2, &c3), new);
+
+    ctrl.leave(&[1]).unwrap();
+    let c4 = ctrl.query(-1);
+    check_balanced(&c4);
+    assert!(!c4.groups.contains_key(&1));
+    // only the shards of the left group move.
+    assert_eq!(
+        moved(&c3, &c4),
+        c3.shards.iter().filter(|&&gid| gid == 1).count()
+    );
+
+    ctrl.leave(&[2, 3, 4]).unwrap();
+    let c5 = ctrl.query(-1);
+    check_balanced(&c5);
+    assert_eq!(c5.num, 5);
+
+    // old configs are kept.
+    assert_eq!(ctrl.query(2), c2);
+    assert_eq!(ctrl.query(100), c5);
+}
+
+#[test]
+fn test_many_groups_4a() {
+    let mut ctrl = ShardCtrl::new();
+    let gids: Vec<u64> = (1..=N_SHARDS as u64 + 2).collect();
+    for gid in &gids {
+        ctrl.join(groups(&[*gid])).unwrap();
+        check_balanced_or_idle(&ctrl.query(-1));
+    }
+    for gid in &gids[..gids.len() - 1] {
+        ctrl.leave(&[*gid]).unwrap();
+        check_balanced_or_idle(&ctrl.query(-1));
+    }
+    let last = ctrl.query(-1);
+    assert!(last.shards.iter().all(|&gid| gid == *gids.last().unwrap()));
+}
+
+// With more groups than shards, some groups have no shard.
+fn check_balanced_or_idle(config: &Config) {
+    if config.groups.len() <= N_SHARDS {
+        check_balanced(config);
+        return;
+    }
+    let mut assigned: Vec<u64> = config.shards.clone();
+    assigned.sort_unstable();
+    assigned.dedup();
+    assert_eq!(assigned.len(), N_SHARDS, "{:?}", config);
+}
+
+#[test]
+fn test_move_4a() {
+    let mut ctrl = ShardCtrl::new();
+    ctrl.join(groups(&[1, 2])).unwrap();
+    let before = ctrl.query(-1);
+    let shard = before.shards.iter().position(|&gid| gid == 1).unwrap();
+
+    ctrl.move_shard(shard as u64, 2).unwrap();
+    let after = ctrl.query(-1);
+    assert_eq!(after.num, before.num + 1);
+    assert_eq!(after.shards[shard], 2);
+    assert_eq!(moved(&before, &after), 1);
+}
+
+#[test]
+fn test_invalid_ops_4a() {
+    let mut ctrl = ShardCtrl::new();
+    assert_eq!(ctrl.join(groups(&[0, 1])), Err(errors::Error::ReservedGid));
+    assert_eq!(ctrl.query(-1).num, 0);
+
+    ctrl.join(groups(&[1, 2])).unwrap();
+    let before = ctrl.query(-1);
+    assert_eq!(
+        ctrl.move_shard(N_SHARDS as u64, 1),
+        Err(errors::Error::InvalidShard(N_SHARDS as u64))
+    );
+    assert_eq!(ctrl.move_shard(0, 3), Err(errors::Error::UnknownGroup(3)));
+    assert_eq!(ctrl.move_shard(0, 0), Err(errors::Error::UnknownGroup(0)));
+    assert_eq!(ctrl.leave(&[0]), Err(errors::Error::ReservedGid));
+    assert_eq!(ctrl.leave(&[1, 3]), Err(errors::Error::UnknownGroup(3)));
+    // the rejected operations leave the configs unchanged.
+    assert_eq!(ctrl.query(-1), before);
+}
+
+#[test]
+fn test_deterministic_4a() {
+    let mut a = ShardCtrl::new();
+    let mut b = ShardCtrl::new();
+    for ctrl in [&mut a, &mut b].iter_mut() {
+        ctrl.join(groups(&[5, 3, 9])).unwrap();
+        ctrl.leave(&[3]).unwrap();
+        ctrl.join(groups(&[7])).unwrap();
+    }
+    assert_eq!(a.query(-1), b.query(-1));
+}
@@ -28,3 +28,17 @@ pub mod kvraftpb {
     }
     pub use self::kv::{add_service as add_kv_service, Client as KvClient, Service as KvService};
 }
+
+pub mod shardctrlpb {
+    include!(concat!(env!("OUT_DIR"), "/shardctrlpb.rs"));
+
+    labrpc::service! {
+        service shardctrl {
+            rpc join(JoinRequest) returns (ChangeReply);
+            rpc leave(LeaveRequest) returns (ChangeReply);
+            rpc move_shard(MoveRequest) returns (ChangeReply);
+            rpc query(QueryRequest) returns (QueryReply);
+        }
+    }
+    pub use self::shardctrl::{Client as ShardCtrlClient, Service as ShardCtrlService};
+}
@@ -91,9 +91,11 @@ impl Config {
     pub fn new(n: usize, unreliable: bool) -> Config {
         init_logger();
 
-        let net = labrpc::Network::new();
-        net.set_reliable(!unreliable);
-        net.set_long_delays(true);
+        let net = labrpc::Network::create_with_config(labrpc::NetworkConfig {
+            reliable: !unreliable,
+            long_delays: true,
+            ..Default::default()
+        });
         let storage = Storage {
             logs: vec![HashMap::new(); n],
             max_index: 0,
@@ -128,6 +130,17 @@ impl Config {
             cfg.connect(i);
         }
 
+        // net.wait_until_stable sees the changes of leadership.
+        let rafts = cfg.rafts.clone();
+        cfg.net.register_stability_probe(Arc::new(move || {
+            let rafts = rafts.lock().unwrap();
+            let states: Vec<_> = rafts
+                .iter()
+                .map(|rf| rf.as_ref().map(|rf| (rf.term(), rf.is_leader())))
+                .collect();
+            format!("{:?}", states)
+        }));
+
         cfg
     }
 
@@ -378,32 +391,36 @@ impl Config {
         // listen to messages from Raft indicating newly committed messages.
         let (tx, apply_ch) = unbounded();
         let storage = self.storage.clone();
-        let apply = apply_ch.for_each(move |cmd: raft::ApplyMsg| {
-            if !cmd.command_valid {
+        let apply = apply_ch.for_each(move |msg: raft::ApplyMsg| {
+            let (command, command_index) = match msg {
+                raft::ApplyMsg::Command {
+                    command,
+                    command_index,
+                } => (command, command_index),
                 // ignore other types of ApplyMsg
-                return future::ready(());
-            }
-            match labcodec::decode(&cmd.command) {
+                raft::ApplyMsg::Snapshot { .. } => return future::ready(()),
+            };
+            match labcodec::decode(&command) {
                 Ok(entry) => {
                     let mut s = storage.lock().unwrap();
                     for (j, log) in s.logs.iter().enumerate() {
-                        if let Some(old) = log.get(&cmd.command_index) {
+                        if let Some(old) = log.get(&command_index) {
                             if *old != entry {
                                 // some server has already committed a different value for this entry!
                                 panic!(
                                     "commit index={:?} server={:?} {:?} != server={:?} {:?}",
-                                    cmd.command_index, i, entry, j, old
+                                    command_index, i, entry, j, old
                                 );
                             }
                         }
                     }
                     let log = &mut s.logs[i];
-                    if cmd.command_index > 1 && log.get(&(cmd.command_index - 1)).is_none() {
-                        panic!("server {} apply out of order {}", i, cmd.command_index);
+                    if command_index > 1 && log.get(&(command_index - 1)).is_none() {
+                        panic!("server {} apply out of order {}", i, command_index);
                     }
-                    log.insert(cmd.command_index, entry);
-                    if cmd.command_index > s.max_index {
-                        s.max_index = cmd.command_index;
+                    log.insert(command_index, entry);
+                    if command_index > s.max_index {
+                        s.max_index = command_index;
                     }
                 }
                 Err(e) => {
@@ -5,7 +5,17 @@ pub enum Error {
     Encode(labcodec::EncodeError),
     Decode(labcodec::DecodeError),
     Rpc(labrpc::Error),
-    NotLeader,
+    /// This peer isn't the leader. `leader_hint` is the peer it believes to
+    /// be the leader of the current term, if any, so that the caller can
+    /// retry it directly instead of trying all the peers.
+    NotLeader {
+        leader_hint: Option<usize>,
+    },
+    /// This peer has not heard from the leader recently, so its state may be
+    /// too stale to serve reads. The caller should ask the leader instead.
+    LeaseExpired,
+    /// The wait for the peer to make progress timed out.
+    Timeout,
 }
 
 impl fmt::Display for Error {
@@ -1,5 +1,6 @@
 use std::sync::mpsc::{sync_channel, Receiver};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 use futures::channel::mpsc::UnboundedSender;
 
@@ -14,10 +15,21 @@ use self::errors::*;
 use self::persister::*;
 use crate::proto::raftpb::*;
 
-pub struct ApplyMsg {
-    pub command_valid: bool,
-    pub command: Vec<u8>,
-    pub command_index: u64,
+/// A message Raft sends on `apply_ch` to the service.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApplyMsg {
+    /// A committed command to apply to the state machine.
+    Command {
+        command: Vec<u8>,
+        command_index: u64,
+    },
+    /// A snapshot to replace the state machine with, which covers the
+    /// entries up to `index`, whose term is `term`.
+    Snapshot {
+        data: Vec<u8>,
+        index: u64,
+        term: u64,
+    },
 }
 
 /// State of a raft peer.
@@ -38,6 +50,92 @@ impl State {
     }
 }
 
+/// The index of the highest log entry applied by a peer, which other
+/// threads can wait on without locking the raft.
+#[derive(Debug, Default)]
+pub struct AppliedIndex {
+    index: Mutex<u64>,
+    advanced: Condvar,
+}
+
+impl AppliedIndex {
+    /// The index of the highest log entry applied.
+    pub fn get(&self) -> u64 {
+        *self.index.lock().unwrap()
+    }
+
+    /// Records that the entries up to `index` have been applied, and wakes
+    /// up the waiters. It never goes backwards.
+    pub fn advance(&self, index: u64) {
+        let mut applied = self.index.lock().unwrap();
+        if index > *applied {
+            *applied = index;
+            self.advanced.notify_all();
+        }
+    }
+
+    /// Blocks until the entry at `index` has been applied, or fails with
+    /// [`Error::Timeout`] if it is not within `timeout`.
+    pub fn wait_for(&self, index: u64, timeout: Duration) -> Result<()> {
+        let applied = self.index.lock().unwrap();
+        let (_applied, res) = self
+            .advanced
+            .wait_timeout_while(applied, timeout, |applied| *applied < index)
+            .unwrap();
+        if res.timed_out() {
+            Err(Error::Timeout)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The role of a raft peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// A snapshot of the state of a raft peer, for observability.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RaftMetrics {
+    pub role: Role,
+    pub term: u64,
+    pub leader_id: Option<usize>,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    /// The number of entries after the snapshot.
+    pub log_len: usize,
+    pub snapshot_index: u64,
+    /// The number of elections this peer has started.
+    pub elections_started: u64,
+    /// The number of votes this peer has granted to others or itself.
+    pub votes_granted: u64,
+    /// The number of AppendEntries this peer has sent, including heartbeats.
+    pub append_entries_sent: u64,
+}
+
+/// The local state machine of the service, which a follower serves reads
+/// from, see `Raft::follower_read`.
+pub trait StateMachine: Send + Sync {
+    /// The value of `key`, `None` if there is no such key.
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// How a raft peer is set up, see `Raft::with_config`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RaftConfig {
+    /// Seeds the election timeouts together with the index of the peer, so
+    /// that each peer picks the same timeouts in every run with the seed.
+    /// They are random if it is `None`.
+    pub seed: Option<u64>,
+    /// How often the leader sends heartbeats, which must be several times
+    /// shorter than the election timeout. It is 100ms if it is `None`.
+    pub heartbeat_interval: Option<Duration>,
+}
+
 // A single Raft peer.
 pub struct Raft {
     // RPC end points of all peers
@@ -66,6 +164,17 @@ impl Raft {
         me: usize,
         persister: Box<dyn Persister>,
         apply_ch: UnboundedSender<ApplyMsg>,
+    ) -> Raft {
+        Raft::with_config(peers, me, persister, apply_ch, RaftConfig::default())
+    }
+
+    /// Like `new`, but set up by `config`.
+    pub fn with_config(
+        peers: Vec<RaftClient>,
+        me: usize,
+        persister: Box<dyn Persister>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
     ) -> Raft {
         let raft_state = persister.raft_state();
 
@@ -80,7 +189,70 @@ impl Raft {
         // initialize from state persisted before a crash
         rf.restore(&raft_state);
 
-        crate::your_code_here((rf, apply_ch))
+        crate::your_code_here((rf, apply_ch, config))
+    }
+
+    /// The index of the highest log entry applied, shared with the threads
+    /// waiting for entries to be applied.
+    pub fn applied_index(&self) -> Arc<AppliedIndex> {
+        // Your code here.
+        crate::your_code_here(())
+    }
+
+    /// A snapshot of the current state of this peer.
+    pub fn metrics(&self) -> RaftMetrics {
+        // Your code here.
+        crate::your_code_here(())
+    }
+
+    /// Reverts to a follower of the current term whatever the role, e.g. to
+    /// hand the leadership over. A leader stops sending heartbeats, and the
+    /// election timer is reset, so the peer starts an election when it
+    /// fires unless it hears from a leader first.
+    pub fn step_down(&mut self) {
+        // Your code here (2A).
+        crate::your_code_here(())
+    }
+
+    /// Sets the local state machine of the service, which
+    /// `follower_read` reads from.
+    pub fn set_state_machine(&mut self, state_machine: Arc<dyn StateMachine>) {
+        // Your code here.
+        crate::your_code_here(state_machine)
+    }
+
+    /// Serves a read of `key` on a follower with bounded staleness, from the
+    /// local state machine and without contacting the leader.
+    ///
+    /// Returns [`Error::LeaseExpired`] if this peer is not a follower, it has
+    /// no state machine, or it has not heard from the leader within the
+    /// lease, a bit shorter than the heartbeat interval. The service should
+    /// redirect the read to the leader then.
+    pub fn follower_read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // Your code here.
+        crate::your_code_here(key)
+    }
+
+    /// Compacts the log entries up to `last_applied` into the snapshot
+    /// taken by the application, and persists them together.
+    ///
+    /// It does nothing if the entries have been compacted already.
+    pub fn snapshot_if_needed(&mut self, last_applied: u64, snapshot: Vec<u8>) {
+        // Your code here (3B).
+        crate::your_code_here((last_applied, snapshot))
+    }
+
+    /// Replaces the log up to `index`, whose entry is of `term`, with
+    /// `snapshot`. The entries after `index` are kept if the log agrees on
+    /// the entry at `index`, otherwise the whole log is discarded.
+    ///
+    /// The compacted state and the snapshot are persisted in a single
+    /// `save_state_and_snapshot`, so a crash in between can not lose the
+    /// entries: either the old log or the new snapshot survives. It does
+    /// nothing if the snapshot is not newer than the current one.
+    pub fn persist_snapshot(&mut self, snapshot: Vec<u8>, index: u64, term: u64) {
+        // Your code here (3B).
+        crate::your_code_here((snapshot, index, term))
     }
 
     /// save Raft's persistent state to stable storage,
@@ -165,7 +337,7 @@ impl Raft {
         if is_leader {
             Ok((index, term))
         } else {
-            Err(Error::NotLeader)
+            Err(Error::NotLeader { leader_hint: None })
         }
     }
 }
@@ -212,7 +384,8 @@ impl Node {
 
     /// the service using Raft (e.g. a k/v server) wants to start
     /// agreement on the next command to be appended to Raft's log. if this
-    /// server isn't the leader, returns [`Error::NotLeader`]. otherwise start
+    /// server isn't the leader, returns [`Error::NotLeader`] with the leader it
+    /// knows of as the hint. otherwise start
     /// the agreement and return immediately. there is no guarantee that this
     /// command will ever be committed to the Raft log, since the leader
     /// may fail or lose an election. even if the Raft instance has been killed,
@@ -248,6 +421,50 @@ impl Node {
         crate::your_code_here(())
     }
 
+    /// A snapshot of the current state of this peer, for observability.
+    pub fn metrics(&self) -> RaftMetrics {
+        // Your code here.
+        // Example:
+        // self.raft.lock().unwrap().metrics()
+        crate::your_code_here(())
+    }
+
+    /// Reverts this peer to a follower, see [`Raft::step_down`].
+    pub fn step_down(&self) {
+        // Your code here.
+        // Example:
+        // self.raft.lock().unwrap().step_down()
+        crate::your_code_here(())
+    }
+
+    /// Index of the highest log entry known to be committed by this peer.
+    pub fn commit_index(&self) -> u64 {
+        // Your code here.
+        // Example:
+        // self.raft.lock().unwrap().commit_index
+        crate::your_code_here(())
+    }
+
+    /// Index of the highest log entry applied by this peer.
+    pub fn last_applied(&self) -> u64 {
+        // Your code here.
+        // Example:
+        // self.raft.lock().unwrap().last_applied
+        crate::your_code_here(())
+    }
+
+    /// Blocks until the entry at `index` has been applied by this peer, or
+    /// fails with [`Error::Timeout`] if it is not within `timeout`.
+    ///
+    /// It must not hold the raft while waiting, or the entry could never
+    /// be applied.
+    pub fn wait_for_apply(&self, index: u64, timeout: Duration) -> Result<()> {
+        // Your code here.
+        // Example, with `applied` taken from `Raft::applied_index` in new:
+        // self.applied.wait_for(index, timeout)
+        crate::your_code_here((index, timeout))
+    }
+
     /// The current state of this peer.
     pub fn get_state(&self) -> State {
         State {
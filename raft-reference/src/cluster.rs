@@ -0,0 +1,179 @@
+//! A cluster of nodes on a labrpc network, for the tests running the peers
+//! end to end. It is a trimmed down version of the tester of the lab crate.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::unbounded;
+use futures::future;
+use futures::stream::StreamExt;
+
+use ::raft::raft::persister::{Persister, SimplePersister};
+use ::raft::raft::{ApplyMsg, RaftConfig};
+
+use crate::proto::raftpb::*;
+use crate::{Node, Raft};
+
+static ID: AtomicUsize = AtomicUsize::new(0);
+
+fn uniqstring() -> String {
+    format!("cluster-{}", ID.fetch_add(1, Ordering::Relaxed))
+}
+
+pub struct Cluster {
+    pub net: labrpc::Network,
+    n: usize,
+    nodes: Vec<Option<Node>>,
+    // whether each server is on the net
+    connected: Vec<bool>,
+    saved: Vec<Arc<SimplePersister>>,
+    // the messages each server has sent on apply_ch since it was started
+    applied: Vec<Arc<Mutex<Vec<ApplyMsg>>>>,
+    // the port file names each sends to
+    endnames: Vec<Vec<String>>,
+    config: RaftConfig,
+}
+
+impl Cluster {
+    /// Starts `n` connected servers set up by `config`.
+    pub fn new(n: usize, config: RaftConfig) -> Cluster {
+        let mut cluster = Cluster {
+            net: labrpc::Network::new(),
+            n,
+            nodes: vec![None; n],
+            connected: vec![false; n],
+            saved: (0..n).map(|_| Arc::new(SimplePersister::new())).collect(),
+            applied: (0..n).map(|_| Arc::default()).collect(),
+            endnames: vec![vec![]; n],
+            config,
+        };
+        for i in 0..n {
+            cluster.start1(i);
+            cluster.connect(i);
+        }
+        cluster
+    }
+
+    /// The node of server `i`, which must be running.
+    pub fn node(&self, i: usize) -> &Node {
+        self.nodes[i].as_ref().unwrap()
+    }
+
+    /// Waits for server `i` to send `n` messages on apply_ch since it was
+    /// started, and returns them.
+    pub fn wait_applied(&self, i: usize, n: usize) -> Vec<ApplyMsg> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let applied = self.applied[i].lock().unwrap().clone();
+            if applied.len() >= n {
+                return applied;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("server {} did not apply {} messages", i, n);
+    }
+
+    /// Starts or restarts server `i` from its persisted state.
+    pub fn start1(&mut self, i: usize) {
+        self.crash1(i);
+
+        // a fresh set of outgoing ClientEnds, so that the crashed instance
+        // can't send.
+        self.endnames[i] = (0..self.n).map(|_| uniqstring()).collect();
+        let clients = self.endnames[i]
+            .iter()
+            .enumerate()
+            .map(|(j, name)| {
+                let cli = self.net.create_client(name.clone());
+                self.net.connect(name, &format!("{}", j));
+                RaftClient::new(cli)
+            })
+            .collect();
+
+        let (tx, apply_ch) = unbounded();
+        let applied = Arc::new(Mutex::new(vec![]));
+        self.applied[i] = applied.clone();
+        self.net.spawn_poller(apply_ch.for_each(move |msg| {
+            applied.lock().unwrap().push(msg);
+            future::ready(())
+        }));
+
+        let persister = Box::new(self.saved[i].clone());
+        let rf = Raft::with_config(clients, i, persister, tx, self.config);
+        let node = Node::new(rf);
+        self.nodes[i] = Some(node.clone());
+
+        let mut builder = labrpc::ServerBuilder::new(format!("{}", i));
+        add_raft_service(node, &mut builder).unwrap();
+        self.net.add_server(builder.build());
+    }
+
+    /// Shuts down server `i` but keeps its persisted state.
+    pub fn crash1(&mut self, i: usize) {
+        self.disconnect(i);
+        self.net.delete_server(&format!("{}", i));
+
+        // a fresh persister, in case the old instance continues to update
+        // it.
+        let p = SimplePersister::new();
+        p.save_state_and_snapshot(self.saved[i].raft_state(), self.saved[i].snapshot());
+        self.saved[i] = Arc::new(p);
+
+        if let Some(node) = self.nodes[i].take() {
+            node.kill();
+        }
+    }
+
+    /// Detaches server `i` from the net.
+    pub fn disconnect(&mut self, i: usize) {
+        self.connected[i] = false;
+        self.enable(i, false);
+    }
+
+    /// Attaches server `i` to the net.
+    pub fn connect(&mut self, i: usize) {
+        self.connected[i] = true;
+        self.enable(i, true);
+    }
+
+    fn enable(&self, i: usize, enabled: bool) {
+        for j in (0..self.n).filter(|&j| self.connected[j] || !enabled) {
+            // outgoing and incoming ClientEnds
+            if let Some(name) = self.endnames[i].get(j) {
+                self.net.enable(name, enabled);
+            }
+            if let Some(name) = self.endnames[j].get(i) {
+                self.net.enable(name, enabled);
+            }
+        }
+    }
+
+    /// Waits for a single leader among the connected servers, and returns
+    /// it.
+    pub fn check_one_leader(&self) -> usize {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            let leaders: Vec<_> = (0..self.n)
+                .filter(|&i| self.connected[i] && self.node(i).is_leader())
+                .collect();
+            if let Some(&leader) = leaders.iter().max_by_key(|&&i| self.node(i).term()) {
+                let term = self.node(leader).term();
+                let count = leaders.iter().filter(|&&i| self.node(i).term() == term);
+                assert_eq!(count.count(), 1, "term {} has more than one leader", term);
+                return leader;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("expected one leader, got none");
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for node in self.nodes.iter().flatten() {
+            node.kill();
+        }
+    }
+}
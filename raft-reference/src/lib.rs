@@ -0,0 +1,1106 @@
+//! A reference implementation of the Raft lab.
+//!
+//! It lives out of the course workspace, so that the students do not get
+//! a solution together with the labs. It implements the interface of
+//! `raft::raft` on top of the persister and the errors of the lab crate,
+//! with the messages and the RPCs of its own.
+
+#![deny(clippy::all)]
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::channel::mpsc::UnboundedSender;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::warn;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::proto::raftpb::*;
+use ::raft::raft::errors::*;
+use ::raft::raft::persister::*;
+use ::raft::raft::{AppliedIndex, ApplyMsg, RaftConfig, RaftMetrics, Role, State, StateMachine};
+
+#[cfg(test)]
+mod cluster;
+pub mod proto;
+#[cfg(test)]
+mod tests;
+
+// The tester limits the leader to tens of heartbeats per second.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+// How often a node checks its election timer and heartbeats, much shorter
+// than both of them.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+// A follower starts an election if it hears nothing from the leader for a
+// random timeout in this range, several heartbeat intervals long.
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(300);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(600);
+
+/// A request the leader sends to a follower to replicate its log.
+#[derive(Clone, Debug, PartialEq)]
+enum PeerRpc {
+    AppendEntries(AppendEntriesArgs),
+    /// Sent instead of AppendEntries if the entries the follower needs have
+    /// been compacted into the snapshot.
+    InstallSnapshot(InstallSnapshotArgs),
+}
+
+// A single Raft peer.
+pub struct Raft {
+    // RPC end points of all peers
+    peers: Vec<RaftClient>,
+    // Object to hold this peer's persisted state
+    persister: Box<dyn Persister>,
+    // this peer's index into peers[]
+    me: usize,
+    apply_ch: UnboundedSender<ApplyMsg>,
+
+    role: Role,
+    // latest term this peer has seen
+    current_term: u64,
+    // the candidate this peer voted for in current_term
+    voted_for: Option<u64>,
+    // log entries after the snapshot, log[0] is a placeholder holding the
+    // term of the last entry in the snapshot, so the entry at index i is
+    // log[i - snapshot_index].
+    log: Vec<LogEntry>,
+    // index of the last entry in the snapshot
+    snapshot_index: u64,
+    // the peer believed to be the leader of current_term
+    leader_id: Option<usize>,
+    // index of the highest log entry known to be committed
+    commit_index: u64,
+    // index of the highest log entry applied to the state machine
+    last_applied: u64,
+    // last_applied shared with the threads waiting for it
+    applied: Arc<AppliedIndex>,
+    // a follower may serve reads until then, in nanoseconds since the Unix
+    // epoch, 0 if it has never heard from a leader
+    lease_expires_at: AtomicU64,
+    // the local state machine of the service, which followers read from
+    state_machine: Option<Arc<dyn StateMachine>>,
+    // votes received in the current election, including its own
+    votes_received: usize,
+    // a follower or candidate starts an election then
+    election_deadline: Instant,
+    // the leader sends the next round of heartbeats then
+    heartbeat_deadline: Instant,
+    // how often the leader sends heartbeats
+    heartbeat_interval: Duration,
+    // picks the election timeouts
+    rng: StdRng,
+
+    // for each peer, index of the next log entry to send to that peer
+    next_index: Vec<u64>,
+    // for each peer, index of the highest log entry known to be
+    // replicated on that peer
+    match_index: Vec<u64>,
+
+    // counters for metrics
+    elections_started: u64,
+    votes_granted: u64,
+    append_entries_sent: u64,
+}
+
+impl Raft {
+    /// the service or tester wants to create a Raft server. the ports
+    /// of all the Raft servers (including this one) are in peers. this
+    /// server's port is peers[me]. all the servers' peers arrays
+    /// have the same order. persister is a place for this server to
+    /// save its persistent state, and also initially holds the most
+    /// recent saved state, if any. apply_ch is a channel on which the
+    /// tester or service expects Raft to send ApplyMsg messages.
+    /// This method must return quickly.
+    pub fn new(
+        peers: Vec<RaftClient>,
+        me: usize,
+        persister: Box<dyn Persister>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+    ) -> Raft {
+        Raft::with_config(peers, me, persister, apply_ch, RaftConfig::default())
+    }
+
+    /// Like `new`, but set up by `config`.
+    pub fn with_config(
+        peers: Vec<RaftClient>,
+        me: usize,
+        persister: Box<dyn Persister>,
+        apply_ch: UnboundedSender<ApplyMsg>,
+        config: RaftConfig,
+    ) -> Raft {
+        let raft_state = persister.raft_state();
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed.wrapping_add(me as u64)),
+            None => StdRng::from_entropy(),
+        };
+
+        let n = peers.len();
+        let mut rf = Raft {
+            peers,
+            persister,
+            me,
+            apply_ch,
+            role: Role::Follower,
+            current_term: 0,
+            voted_for: None,
+            log: vec![LogEntry::default()],
+            snapshot_index: 0,
+            leader_id: None,
+            commit_index: 0,
+            last_applied: 0,
+            applied: Arc::default(),
+            lease_expires_at: AtomicU64::new(0),
+            state_machine: None,
+            votes_received: 0,
+            election_deadline: Instant::now(),
+            heartbeat_deadline: Instant::now(),
+            heartbeat_interval: config.heartbeat_interval.unwrap_or(HEARTBEAT_INTERVAL),
+            rng,
+            next_index: vec![1; n],
+            match_index: vec![0; n],
+            elections_started: 0,
+            votes_granted: 0,
+            append_entries_sent: 0,
+        };
+
+        // initialize from state persisted before a crash
+        rf.restore(&raft_state);
+        rf.reset_election_timer();
+
+        rf
+    }
+
+    /// The peer believed to be the leader of the current term, `None` if it
+    /// is unknown yet.
+    fn leader_id(&self) -> Option<usize> {
+        self.leader_id
+    }
+
+    /// The index of the highest log entry applied, shared with the threads
+    /// waiting for entries to be applied.
+    pub fn applied_index(&self) -> Arc<AppliedIndex> {
+        self.applied.clone()
+    }
+
+    /// A snapshot of the current state of this peer.
+    pub fn metrics(&self) -> RaftMetrics {
+        RaftMetrics {
+            role: self.role,
+            term: self.current_term,
+            leader_id: self.leader_id,
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            log_len: self.log.len() - 1,
+            snapshot_index: self.snapshot_index,
+            elections_started: self.elections_started,
+            votes_granted: self.votes_granted,
+            append_entries_sent: self.append_entries_sent,
+        }
+    }
+
+    /// The index of the last entry in the log.
+    fn last_log_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64 - 1
+    }
+
+    /// The term of the last entry in the snapshot.
+    fn snapshot_term(&self) -> u64 {
+        self.log[0].term
+    }
+
+    /// The term of the entry at `index`, `None` if there is no such entry or
+    /// the entry has been compacted into the snapshot.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.log
+            .get((index - self.snapshot_index) as usize)
+            .map(|e| e.term)
+    }
+
+    /// Computes the `conflict_term` and `conflict_index` of a rejected
+    /// AppendEntries whose `prev_log_index` does not match the log.
+    ///
+    /// `conflict_term` is the term of the entry at `prev_log_index` and
+    /// `conflict_index` is the first index of that term, so that the leader
+    /// can skip all the entries of the conflicting term at once. If the log
+    /// is too short, `conflict_term` is 0 and `conflict_index` is the index
+    /// right after the last entry.
+    fn conflict_at(&self, prev_log_index: u64) -> (u64, u64) {
+        let conflict_term = match self.term_at(prev_log_index) {
+            Some(term) => term,
+            None => return (0, self.last_log_index() + 1),
+        };
+        let mut conflict_index = prev_log_index;
+        while conflict_index > self.snapshot_index + 1
+            && self.term_at(conflict_index - 1) == Some(conflict_term)
+        {
+            conflict_index -= 1;
+        }
+        (conflict_term, conflict_index)
+    }
+
+    /// Computes the next index to send after a follower rejected an
+    /// AppendEntries with `conflict_term` and `conflict_index`.
+    ///
+    /// If the leader has entries of `conflict_term`, the follower may agree
+    /// on them, so it resumes right after the last one. Otherwise the whole
+    /// conflicting term is skipped.
+    fn next_index_on_conflict(&self, conflict_term: u64, conflict_index: u64) -> u64 {
+        if conflict_term != 0 {
+            let last = (self.snapshot_index + 1..=self.last_log_index())
+                .rev()
+                .find(|&i| self.term_at(i) == Some(conflict_term));
+            if let Some(last) = last {
+                return last + 1;
+            }
+        }
+        conflict_index.max(1)
+    }
+
+    /// Steps down to a follower of `term`, which is newer than the current
+    /// one. The election timer is left alone, a newer term alone does not
+    /// mean there is a leader.
+    fn become_follower(&mut self, term: u64) {
+        self.step_down_to(term);
+        self.persist();
+    }
+
+    /// Reverts to a follower of the current term whatever the role, e.g. to
+    /// hand the leadership over. A leader stops sending heartbeats, and the
+    /// election timer is reset, so the peer starts an election when it
+    /// fires unless it hears from a leader first.
+    pub fn step_down(&mut self) {
+        self.step_down_to(self.current_term);
+        self.reset_election_timer();
+    }
+
+    /// Reverts to a follower of `term`, which is not older than the current
+    /// one. The vote is cleared if the term advances, as it was cast in an
+    /// older term. The caller persists the state.
+    ///
+    /// It does not reset the election timer: that is only done on granting
+    /// a vote, hearing from the current leader or starting an election, so
+    /// a peer with a stale log can not keep delaying the elections by
+    /// sending RequestVote of ever newer terms.
+    fn step_down_to(&mut self, term: u64) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader_id = None;
+        self.votes_received = 0;
+    }
+
+    /// Starts an election for the next term and votes for itself. Returns
+    /// the RequestVote to send to the other peers.
+    ///
+    /// The vote is persisted before the RequestVote can be sent, so the
+    /// peer never votes for another candidate in the term after a restart.
+    fn become_candidate(&mut self) -> RequestVoteArgs {
+        self.current_term += 1;
+        self.voted_for = Some(self.me as u64);
+        self.role = Role::Candidate;
+        self.leader_id = None;
+        self.votes_received = 1;
+        self.elections_started += 1;
+        self.votes_granted += 1;
+        self.reset_election_timer();
+        self.persist();
+        RequestVoteArgs {
+            term: self.current_term,
+            candidate_id: self.me as u64,
+            last_log_index: self.last_log_index(),
+            last_log_term: self.term_at(self.last_log_index()).unwrap(),
+        }
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader_id = Some(self.me);
+        let last_log_index = self.last_log_index();
+        self.next_index = vec![last_log_index + 1; self.peers.len()];
+        self.match_index = vec![0; self.peers.len()];
+        self.match_index[self.me] = last_log_index;
+        // asserts the leadership right away.
+        self.heartbeat_deadline = Instant::now();
+    }
+
+    /// Picks a new random election timeout from now.
+    fn reset_election_timer(&mut self) {
+        self.election_deadline = Instant::now() + self.election_timeout();
+    }
+
+    /// A random election timeout, the same sequence of them for a peer
+    /// seeded by `RaftConfig::seed`.
+    fn election_timeout(&mut self) -> Duration {
+        self.rng
+            .gen_range(ELECTION_TIMEOUT_MIN, ELECTION_TIMEOUT_MAX)
+    }
+
+    /// Whether a follower or candidate has heard nothing from a leader for
+    /// its election timeout at `now`, and should start a new election.
+    fn election_timeout_elapsed(&self, now: Instant) -> bool {
+        self.role != Role::Leader && now >= self.election_deadline
+    }
+
+    /// The AppendEntries carrying the entries from `next_index` on to
+    /// `server`, which are none if it is up to date.
+    ///
+    /// Returns `None` if the entries `server` needs have been compacted into
+    /// the snapshot.
+    fn append_entries_args(&self, server: usize) -> Option<AppendEntriesArgs> {
+        let prev_log_index = self.next_index[server] - 1;
+        let prev_log_term = self.term_at(prev_log_index)?;
+        let start = (prev_log_index + 1 - self.snapshot_index) as usize;
+        Some(AppendEntriesArgs {
+            term: self.current_term,
+            leader_id: self.me as u64,
+            prev_log_index,
+            prev_log_term,
+            entries: self.log[start..].to_vec(),
+            leader_commit: self.commit_index,
+        })
+    }
+
+    /// The InstallSnapshot to send to `server` if the entries it needs have
+    /// been compacted into the snapshot, `None` if AppendEntries can still
+    /// catch it up.
+    fn install_snapshot_args(&self, server: usize) -> Option<InstallSnapshotArgs> {
+        if self.next_index[server] > self.snapshot_index {
+            return None;
+        }
+        Some(InstallSnapshotArgs {
+            term: self.current_term,
+            leader_id: self.me as u64,
+            last_included_index: self.snapshot_index,
+            last_included_term: self.snapshot_term(),
+            data: self.persister.snapshot(),
+        })
+    }
+
+    /// The heartbeats the leader should send at `now`, one for each of the
+    /// other peers. Returns nothing if this peer is not the leader or it has
+    /// sent heartbeats within the heartbeat interval.
+    ///
+    /// A heartbeat is an AppendEntries, which carries `leader_commit` so the
+    /// followers learn the commit index even if there are no new entries,
+    /// or an InstallSnapshot for the followers behind the snapshot.
+    fn send_heartbeats(&mut self, now: Instant) -> Vec<(usize, PeerRpc)> {
+        if self.role != Role::Leader || now < self.heartbeat_deadline {
+            return vec![];
+        }
+        self.heartbeat_deadline = now + self.heartbeat_interval;
+        let heartbeats: Vec<_> = (0..self.peers.len())
+            .filter(|&server| server != self.me)
+            .filter_map(|server| {
+                let rpc = match self.append_entries_args(server) {
+                    Some(args) => PeerRpc::AppendEntries(args),
+                    None => PeerRpc::InstallSnapshot(self.install_snapshot_args(server)?),
+                };
+                Some((server, rpc))
+            })
+            .collect();
+        self.append_entries_sent += heartbeats
+            .iter()
+            .filter(|(_, rpc)| matches!(rpc, PeerRpc::AppendEntries(_)))
+            .count() as u64;
+        heartbeats
+    }
+
+    /// Handles a RequestVote from a candidate. The vote is granted if this
+    /// peer has not voted for another candidate in the term and the log of
+    /// the candidate is at least as up-to-date as its own. The vote is
+    /// persisted before the reply is sent.
+    fn handle_request_vote(&mut self, args: &RequestVoteArgs) -> RequestVoteReply {
+        if args.term > self.current_term {
+            self.become_follower(args.term);
+        }
+        let last_log_index = self.last_log_index();
+        let last_log_term = self.term_at(last_log_index).unwrap();
+        let up_to_date =
+            (args.last_log_term, args.last_log_index) >= (last_log_term, last_log_index);
+        let vote_granted = args.term == self.current_term
+            && self.voted_for.is_none_or(|c| c == args.candidate_id)
+            && up_to_date;
+        if vote_granted {
+            if self.voted_for.is_none() {
+                self.voted_for = Some(args.candidate_id);
+                self.votes_granted += 1;
+                self.persist();
+            }
+            self.reset_election_timer();
+        }
+        RequestVoteReply {
+            term: self.current_term,
+            vote_granted,
+        }
+    }
+
+    /// Counts the vote in the reply of a RequestVote, and becomes the leader
+    /// once a majority of the peers have voted for it.
+    ///
+    /// Only the votes for the current election are counted, the replies to
+    /// an earlier election are stale even if they arrive after this one has
+    /// started.
+    fn handle_request_vote_reply(&mut self, args: &RequestVoteArgs, reply: &RequestVoteReply) {
+        if reply.term > self.current_term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if self.role != Role::Candidate || args.term != self.current_term {
+            // a stale reply.
+            return;
+        }
+        if reply.vote_granted {
+            self.votes_received += 1;
+            if self.votes_received > self.peers.len() / 2 {
+                self.become_leader();
+            }
+        }
+    }
+
+    /// Handles an AppendEntries from the leader, heartbeats included.
+    ///
+    /// Unless the request is from a stale term, i.e. `reply.term` equals
+    /// `args.term`, the sender is recognized as the leader and the election
+    /// timer is reset. Entries at or below `commit_index` are never
+    /// overwritten.
+    fn handle_append_entries(&mut self, args: &AppendEntriesArgs) -> AppendEntriesReply {
+        if args.term < self.current_term {
+            return AppendEntriesReply {
+                term: self.current_term,
+                success: false,
+                ..Default::default()
+            };
+        }
+        let mut changed = args.term > self.current_term;
+        self.step_down_to(args.term);
+        self.leader_id = Some(args.leader_id as usize);
+        self.reset_election_timer();
+        self.extend_lease();
+
+        let (mut prev_log_index, mut entries) = (args.prev_log_index, &args.entries[..]);
+        if prev_log_index < self.snapshot_index {
+            // the entries in the snapshot are committed, skip them.
+            let skip = ((self.snapshot_index - prev_log_index) as usize).min(entries.len());
+            prev_log_index += skip as u64;
+            entries = &entries[skip..];
+        }
+        if prev_log_index == args.prev_log_index
+            && self.term_at(prev_log_index) != Some(args.prev_log_term)
+        {
+            if changed {
+                self.persist();
+            }
+            let (conflict_term, conflict_index) = self.conflict_at(prev_log_index);
+            return AppendEntriesReply {
+                term: self.current_term,
+                success: false,
+                conflict_term,
+                conflict_index,
+            };
+        }
+
+        for (i, entry) in entries.iter().enumerate() {
+            let index = prev_log_index + 1 + i as u64;
+            match self.term_at(index) {
+                // already in the log, a duplicated or reordered request
+                // must not truncate the entries after it.
+                Some(term) if term == entry.term => continue,
+                // a committed entry is never overwritten, the request
+                // conflicting with it is stale or bogus.
+                Some(_) if index <= self.commit_index => {
+                    warn!(
+                        "{} ignores a conflicting entry at {} committed at {}",
+                        self.me, index, self.commit_index
+                    );
+                    if changed {
+                        self.persist();
+                    }
+                    return AppendEntriesReply {
+                        term: self.current_term,
+                        success: false,
+                        conflict_term: 0,
+                        conflict_index: self.commit_index + 1,
+                    };
+                }
+                Some(_) => self.log.truncate((index - self.snapshot_index) as usize),
+                None => {}
+            }
+            self.log.extend_from_slice(&entries[i..]);
+            changed = true;
+            break;
+        }
+        if changed {
+            self.persist();
+        }
+
+        let last_new_index = prev_log_index + entries.len() as u64;
+        if args.leader_commit > self.commit_index {
+            self.commit_index = self
+                .commit_index
+                .max(args.leader_commit.min(last_new_index));
+        }
+        AppendEntriesReply {
+            term: self.current_term,
+            success: true,
+            ..Default::default()
+        }
+    }
+
+    /// Extends the lease of a follower which has just heard from the leader,
+    /// to a bit less than the heartbeat interval.
+    fn extend_lease(&self) {
+        let expires_at = unix_nanos(SystemTime::now()) + lease(self.heartbeat_interval);
+        self.lease_expires_at.store(expires_at, Ordering::SeqCst);
+    }
+
+    /// Sets the local state machine of the service, which
+    /// `follower_read` reads from.
+    pub fn set_state_machine(&mut self, state_machine: Arc<dyn StateMachine>) {
+        self.state_machine = Some(state_machine);
+    }
+
+    /// Serves a read of `key` on a follower with bounded staleness, from the
+    /// local state machine and without contacting the leader.
+    ///
+    /// Returns [`Error::LeaseExpired`] if this peer is not a follower, it has
+    /// no state machine, or it has not heard from the leader within the
+    /// lease, a bit shorter than the heartbeat interval. The service should
+    /// redirect the read to the leader then.
+    pub fn follower_read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let expires_at = self.lease_expires_at.load(Ordering::SeqCst);
+        match &self.state_machine {
+            Some(state_machine)
+                if self.role == Role::Follower && unix_nanos(SystemTime::now()) < expires_at =>
+            {
+                Ok(state_machine.get(key))
+            }
+            _ => Err(Error::LeaseExpired),
+        }
+    }
+
+    /// Updates `next_index` and `match_index` of the peer by the reply of an
+    /// AppendEntries, and commits the entries replicated on a majority.
+    fn handle_append_entries_reply(
+        &mut self,
+        server: usize,
+        args: &AppendEntriesArgs,
+        reply: &AppendEntriesReply,
+    ) {
+        if reply.term > self.current_term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if args.term != self.current_term || reply.term != self.current_term {
+            // a stale reply.
+            return;
+        }
+        if reply.success {
+            let matched = args.prev_log_index + args.entries.len() as u64;
+            if matched > self.match_index[server] {
+                self.match_index[server] = matched;
+                self.advance_commit_index();
+            }
+            self.next_index[server] = self.match_index[server] + 1;
+        } else {
+            self.next_index[server] =
+                self.next_index_on_conflict(reply.conflict_term, reply.conflict_index);
+        }
+    }
+
+    /// Handles an InstallSnapshot from the leader. The snapshot is sent to
+    /// the service on `apply_ch` and replaces the entries it covers, unless
+    /// they are committed already.
+    fn handle_install_snapshot(&mut self, args: &InstallSnapshotArgs) -> InstallSnapshotReply {
+        if args.term < self.current_term {
+            return InstallSnapshotReply {
+                term: self.current_term,
+            };
+        }
+        let changed = args.term > self.current_term;
+        self.step_down_to(args.term);
+        self.leader_id = Some(args.leader_id as usize);
+        self.reset_election_timer();
+        self.extend_lease();
+
+        if args.last_included_index <= self.commit_index {
+            // a stale or duplicated snapshot, the log has the entries.
+            if changed {
+                self.persist();
+            }
+        } else {
+            let msg = ApplyMsg::Snapshot {
+                data: args.data.clone(),
+                index: args.last_included_index,
+                term: args.last_included_term,
+            };
+            // the entries applied before are sent before the snapshot.
+            let _ = self.apply_ch.unbounded_send(msg);
+            self.persist_snapshot(
+                args.data.clone(),
+                args.last_included_index,
+                args.last_included_term,
+            );
+        }
+        InstallSnapshotReply {
+            term: self.current_term,
+        }
+    }
+
+    /// Handles the reply of an InstallSnapshot sent to `server`.
+    fn handle_install_snapshot_reply(
+        &mut self,
+        server: usize,
+        args: &InstallSnapshotArgs,
+        reply: &InstallSnapshotReply,
+    ) {
+        if reply.term > self.current_term {
+            self.become_follower(reply.term);
+            return;
+        }
+        if args.term != self.current_term || reply.term != self.current_term {
+            // a stale reply.
+            return;
+        }
+        if args.last_included_index > self.match_index[server] {
+            self.match_index[server] = args.last_included_index;
+        }
+        self.next_index[server] = self.match_index[server] + 1;
+    }
+
+    /// Commits the highest entry of the current term replicated on a
+    /// majority of the peers, together with the entries before it. The
+    /// entries of earlier terms are never committed by counting replicas.
+    fn advance_commit_index(&mut self) {
+        let mut matched: Vec<_> = (0..self.peers.len())
+            .map(|server| {
+                if server == self.me {
+                    self.last_log_index()
+                } else {
+                    self.match_index[server]
+                }
+            })
+            .collect();
+        matched.sort_unstable();
+        // a majority of the peers have replicated the entries up to it.
+        let index = matched[(matched.len() - 1) / 2];
+        if index > self.commit_index && self.term_at(index) == Some(self.current_term) {
+            self.commit_index = index;
+        }
+    }
+
+    /// Whether a majority of the peers together with the hypothetical new
+    /// members `extra` have replicated some entry after `commit_index`,
+    /// i.e. whether the leader could still commit with them. The new
+    /// members have replicated nothing yet, the ids already in the cluster
+    /// or repeated in `extra` are counted once.
+    ///
+    /// A leader checks it before changing the membership, so that the
+    /// change does not leave the cluster without a majority.
+    pub fn has_quorum_with(&self, extra: &[usize]) -> bool {
+        if self.role != Role::Leader {
+            return false;
+        }
+        let n = self.peers.len();
+        let new_members = extra
+            .iter()
+            .filter(|&&server| server >= n)
+            .collect::<HashSet<_>>()
+            .len();
+        let matched = (0..n)
+            .filter(|&server| {
+                let match_index = if server == self.me {
+                    self.last_log_index()
+                } else {
+                    self.match_index[server]
+                };
+                match_index > self.commit_index
+            })
+            .count();
+        matched > (n + new_members) / 2
+    }
+
+    /// Sends the entries committed but not applied yet on `apply_ch`, in
+    /// order. Returns the number of entries sent.
+    ///
+    /// `apply_ch` is unbounded, so it never blocks while holding the raft.
+    /// The entries in the snapshot are never sent, the service restores
+    /// them from the snapshot instead.
+    fn apply_committed_entries(&mut self) -> usize {
+        let mut applied = 0;
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let entry = &self.log[(index - self.snapshot_index) as usize];
+            let msg = ApplyMsg::Command {
+                command: entry.data.clone(),
+                command_index: index,
+            };
+            if self.apply_ch.unbounded_send(msg).is_err() {
+                // the service has gone.
+                break;
+            }
+            self.last_applied = index;
+            applied += 1;
+        }
+        self.applied.advance(self.last_applied);
+        applied
+    }
+
+    /// Compacts the log entries up to `last_applied` into the snapshot
+    /// taken by the application, and persists them together.
+    ///
+    /// It does nothing if the entries have been compacted already.
+    pub fn snapshot_if_needed(&mut self, last_applied: u64, snapshot: Vec<u8>) {
+        if last_applied <= self.snapshot_index {
+            return;
+        }
+        let term = self.term_at(last_applied).unwrap_or_else(|| {
+            panic!(
+                "snapshot at {} beyond the last log index {}",
+                last_applied,
+                self.last_log_index()
+            )
+        });
+        self.persist_snapshot(snapshot, last_applied, term);
+    }
+
+    /// Replaces the log up to `index`, whose entry is of `term`, with
+    /// `snapshot`. The entries after `index` are kept if the log agrees on
+    /// the entry at `index`, otherwise the whole log is discarded.
+    ///
+    /// The compacted state and the snapshot are persisted in a single
+    /// `save_state_and_snapshot`, so a crash in between can not lose the
+    /// entries: either the old log or the new snapshot survives. It does
+    /// nothing if the snapshot is not newer than the current one.
+    pub fn persist_snapshot(&mut self, snapshot: Vec<u8>, index: u64, term: u64) {
+        if index <= self.snapshot_index {
+            return;
+        }
+        if self.term_at(index) == Some(term) {
+            // the entry at index becomes the placeholder.
+            self.log.drain(..(index - self.snapshot_index) as usize);
+            self.log[0].data.clear();
+        } else {
+            self.log = vec![LogEntry { term, data: vec![] }];
+        }
+        self.snapshot_index = index;
+        // the entries in a snapshot are committed and applied.
+        self.commit_index = self.commit_index.max(index);
+        self.last_applied = self.last_applied.max(index);
+        self.applied.advance(self.last_applied);
+        let state = self.encode_state();
+        self.persister.save_state_and_snapshot(state, snapshot);
+    }
+
+    fn encode_state(&self) -> Vec<u8> {
+        let state = PersistentState {
+            current_term: self.current_term,
+            voted_for: self.voted_for.map(|candidate_id| Vote { candidate_id }),
+            log: self.log.clone(),
+            snapshot_index: self.snapshot_index,
+        };
+        let mut data = vec![];
+        labcodec::encode(&state, &mut data).unwrap();
+        data
+    }
+
+    /// save Raft's persistent state to stable storage,
+    /// where it can later be retrieved after a crash and restart.
+    /// see paper's Figure 2 for a description of what should be persistent.
+    fn persist(&mut self) {
+        let data = self.encode_state();
+        self.persister.save_raft_state(data);
+    }
+
+    /// restore previously persisted state.
+    fn restore(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            // bootstrap without any state?
+            return;
+        }
+        match labcodec::decode::<PersistentState>(data) {
+            Ok(state) => {
+                self.current_term = state.current_term;
+                self.voted_for = state.voted_for.map(|vote| vote.candidate_id);
+                self.log = state.log;
+                self.snapshot_index = state.snapshot_index;
+                // the service restores the snapshot by itself, the entries
+                // in it must not be applied again.
+                self.commit_index = state.snapshot_index;
+                self.last_applied = state.snapshot_index;
+                self.applied.advance(self.last_applied);
+            }
+            Err(e) => {
+                panic!("{:?}", e);
+            }
+        }
+    }
+
+    /// Sends the RequestVote of an election to all the other peers at once.
+    ///
+    /// The returned stream yields the replies as they arrive, together with
+    /// the server replying. The candidate hands each of them to
+    /// `handle_request_vote_reply` and drops the stream once it is no longer
+    /// a candidate of `args.term`, i.e. it has won or seen a newer term,
+    /// without waiting for the slow or unreachable peers.
+    fn send_request_vote_parallel(
+        &self,
+        args: &RequestVoteArgs,
+    ) -> FuturesUnordered<BoxFuture<'static, (usize, Result<RequestVoteReply>)>> {
+        (0..self.peers.len())
+            .filter(|&server| server != self.me)
+            .map(|server| {
+                let call = self.peers[server].request_vote(args);
+                async move { (server, call.await.map_err(Error::Rpc)) }.boxed()
+            })
+            .collect()
+    }
+
+    /// Appends `command` to the log of the leader, which replicates it to
+    /// the followers with the next heartbeats. Returns the index and the term
+    /// of the entry.
+    fn start<M>(&mut self, command: &M) -> Result<(u64, u64)>
+    where
+        M: labcodec::Message,
+    {
+        if self.role != Role::Leader {
+            return Err(Error::NotLeader {
+                leader_hint: self.leader_id(),
+            });
+        }
+        let mut buf = vec![];
+        labcodec::encode(command, &mut buf).map_err(Error::Encode)?;
+        self.log.push(LogEntry {
+            term: self.current_term,
+            data: buf,
+        });
+        self.persist();
+        self.advance_commit_index();
+        Ok((self.last_log_index(), self.current_term))
+    }
+}
+
+/// Nanoseconds since the Unix epoch at `t`.
+fn unix_nanos(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64
+}
+
+/// How long a follower may serve reads after hearing from the leader, in
+/// nanoseconds. It is 90% of the heartbeat interval.
+fn lease(heartbeat_interval: Duration) -> u64 {
+    (heartbeat_interval * 9 / 10).as_nanos() as u64
+}
+
+// A Node drives the raft, which is shared by the RPC handlers and a ticker
+// thread firing the election timer and the heartbeats.
+#[derive(Clone)]
+pub struct Node {
+    raft: Arc<Mutex<Raft>>,
+    // last_applied of the raft, waited on without locking it
+    applied: Arc<AppliedIndex>,
+    // stops the ticker thread
+    killed: Arc<AtomicBool>,
+}
+
+impl Node {
+    /// Create a new raft service.
+    pub fn new(raft: Raft) -> Node {
+        let node = Node {
+            applied: raft.applied_index(),
+            raft: Arc::new(Mutex::new(raft)),
+            killed: Arc::default(),
+        };
+        let ticker = node.clone();
+        thread::spawn(move || {
+            while !ticker.killed.load(Ordering::SeqCst) {
+                ticker.tick(Instant::now());
+                thread::sleep(TICK_INTERVAL);
+            }
+        });
+        node
+    }
+
+    /// Starts an election if the election timer has fired, and sends the
+    /// heartbeats if they are due, at `now`. The replies are handled as they
+    /// arrive, without blocking the ticker.
+    fn tick(&self, now: Instant) {
+        let mut rf = self.raft.lock().unwrap();
+        if rf.election_timeout_elapsed(now) {
+            let args = rf.become_candidate();
+            let mut votes = rf.send_request_vote_parallel(&args);
+            let raft = self.raft.clone();
+            rf.peers[rf.me].spawn(async move {
+                while let Some((_, res)) = votes.next().await {
+                    let mut rf = raft.lock().unwrap();
+                    if let Ok(reply) = res {
+                        rf.handle_request_vote_reply(&args, &reply);
+                    }
+                    if rf.role != Role::Candidate || rf.current_term != args.term {
+                        break;
+                    }
+                }
+            });
+        }
+        for (server, rpc) in rf.send_heartbeats(now) {
+            self.send_peer_rpc(&rf, server, rpc);
+        }
+    }
+
+    /// Sends `rpc` from the leader `rf` to `server`, and hands the reply
+    /// back to the raft once it arrives.
+    fn send_peer_rpc(&self, rf: &Raft, server: usize, rpc: PeerRpc) {
+        let peer = &rf.peers[server];
+        let raft = self.raft.clone();
+        match rpc {
+            PeerRpc::AppendEntries(args) => {
+                let call = peer.append_entries(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = call.await {
+                        let mut rf = raft.lock().unwrap();
+                        rf.handle_append_entries_reply(server, &args, &reply);
+                        rf.apply_committed_entries();
+                    }
+                });
+            }
+            PeerRpc::InstallSnapshot(args) => {
+                let call = peer.install_snapshot(&args);
+                peer.spawn(async move {
+                    if let Ok(reply) = call.await {
+                        let mut rf = raft.lock().unwrap();
+                        rf.handle_install_snapshot_reply(server, &args, &reply);
+                    }
+                });
+            }
+        }
+    }
+
+    /// the service using Raft (e.g. a k/v server) wants to start
+    /// agreement on the next command to be appended to Raft's log. if this
+    /// server isn't the leader, returns [`Error::NotLeader`] with the leader it
+    /// knows of as the hint. otherwise start
+    /// the agreement and return immediately. there is no guarantee that this
+    /// command will ever be committed to the Raft log, since the leader
+    /// may fail or lose an election. even if the Raft instance has been killed,
+    /// this function should return gracefully.
+    ///
+    /// the first value of the tuple is the index that the command will appear
+    /// at if it's ever committed. the second is the current term.
+    ///
+    /// This method must return without blocking on the raft.
+    pub fn start<M>(&self, command: &M) -> Result<(u64, u64)>
+    where
+        M: labcodec::Message,
+    {
+        let mut rf = self.raft.lock().unwrap();
+        let res = rf.start(command);
+        rf.apply_committed_entries();
+        res
+    }
+
+    /// The current term of this peer.
+    pub fn term(&self) -> u64 {
+        self.raft.lock().unwrap().current_term
+    }
+
+    /// Whether this peer believes it is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.raft.lock().unwrap().role == Role::Leader
+    }
+
+    /// A snapshot of the current state of this peer, for observability.
+    pub fn metrics(&self) -> RaftMetrics {
+        self.raft.lock().unwrap().metrics()
+    }
+
+    /// Reverts this peer to a follower, see [`Raft::step_down`].
+    pub fn step_down(&self) {
+        self.raft.lock().unwrap().step_down()
+    }
+
+    /// Compacts the log into the snapshot taken by the service, see
+    /// [`Raft::snapshot_if_needed`]. The leader sends it to the followers
+    /// which are behind it.
+    pub fn snapshot_if_needed(&self, last_applied: u64, snapshot: Vec<u8>) {
+        self.raft
+            .lock()
+            .unwrap()
+            .snapshot_if_needed(last_applied, snapshot)
+    }
+
+    /// Sets the local state machine of the service, see
+    /// [`Raft::set_state_machine`].
+    pub fn set_state_machine(&self, state_machine: Arc<dyn StateMachine>) {
+        self.raft.lock().unwrap().set_state_machine(state_machine)
+    }
+
+    /// Serves a read of `key` on a follower from the local state machine,
+    /// see [`Raft::follower_read`].
+    pub fn follower_read(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.raft.lock().unwrap().follower_read(key)
+    }
+
+    /// Index of the highest log entry known to be committed by this peer.
+    pub fn commit_index(&self) -> u64 {
+        self.raft.lock().unwrap().commit_index
+    }
+
+    /// Index of the highest log entry applied by this peer.
+    pub fn last_applied(&self) -> u64 {
+        self.raft.lock().unwrap().last_applied
+    }
+
+    /// Blocks until the entry at `index` has been applied by this peer, or
+    /// fails with [`Error::Timeout`] if it is not within `timeout`.
+    ///
+    /// It must not hold the raft while waiting, or the entry could never
+    /// be applied.
+    pub fn wait_for_apply(&self, index: u64, timeout: Duration) -> Result<()> {
+        self.applied.wait_for(index, timeout)
+    }
+
+    /// The current state of this peer.
+    pub fn get_state(&self) -> State {
+        State {
+            term: self.term(),
+            is_leader: self.is_leader(),
+        }
+    }
+
+    /// the tester calls kill() when a Raft instance won't be
+    /// needed again. It stops the ticker thread, so the peer neither starts
+    /// elections nor sends heartbeats any more.
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::SeqCst);
+    }
+}
+
+#[async_trait::async_trait]
+impl RaftService for Node {
+    // The handlers lock the raft only to update it, never across an await.
+    async fn request_vote(&self, args: RequestVoteArgs) -> labrpc::Result<RequestVoteReply> {
+        Ok(self.raft.lock().unwrap().handle_request_vote(&args))
+    }
+
+    async fn append_entries(&self, args: AppendEntriesArgs) -> labrpc::Result<AppendEntriesReply> {
+        let mut rf = self.raft.lock().unwrap();
+        let reply = rf.handle_append_entries(&args);
+        rf.apply_committed_entries();
+        Ok(reply)
+    }
+
+    async fn install_snapshot(
+        &self,
+        args: InstallSnapshotArgs,
+    ) -> labrpc::Result<InstallSnapshotReply> {
+        Ok(self.raft.lock().unwrap().handle_install_snapshot(&args))
+    }
+}
@@ -0,0 +1,14 @@
+pub mod raftpb {
+    include!(concat!(env!("OUT_DIR"), "/raftpb.rs"));
+
+    labrpc::service! {
+        service raft {
+            rpc request_vote(RequestVoteArgs) returns (RequestVoteReply);
+            rpc append_entries(AppendEntriesArgs) returns (AppendEntriesReply);
+            rpc install_snapshot(InstallSnapshotArgs) returns (InstallSnapshotReply);
+        }
+    }
+    pub use self::raft::{
+        add_service as add_raft_service, Client as RaftClient, Service as RaftService,
+    };
+}
@@ -0,0 +1,1211 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::channel::mpsc::unbounded;
+use futures::executor::block_on;
+use futures::stream::StreamExt;
+use futures_timer::Delay;
+
+use ::raft::raft::errors::Error;
+use ::raft::raft::persister::{Persister, SimplePersister};
+use ::raft::raft::{ApplyMsg, RaftConfig, Role, StateMachine};
+
+use crate::cluster::Cluster;
+use crate::proto::raftpb::*;
+use crate::{PeerRpc, Raft, ELECTION_TIMEOUT_MAX, ELECTION_TIMEOUT_MIN};
+
+/// Makes a standalone Raft peer with the given log terms, peers are not
+/// connected to any network.
+fn make_raft(servers: usize, me: usize, terms: &[u64]) -> Raft {
+    let net = labrpc::Network::new();
+    let peers = (0..servers)
+        .map(|i| RaftClient::new(net.create_client(format!("{}-{}", me, i))))
+        .collect();
+    let (tx, _apply_ch) = unbounded();
+    let mut rf = Raft::new(peers, me, Box::new(SimplePersister::new()), tx);
+    for &term in terms {
+        rf.log.push(LogEntry { term, data: vec![] });
+    }
+    rf.current_term = terms.last().cloned().unwrap_or(0);
+    rf
+}
+
+#[test]
+fn test_fast_backup_conflict_2b() {
+    //            index: 1  2  3  4  5  6
+    let rf = make_raft(3, 0, &[1, 1, 2, 2, 2, 4]);
+
+    // the log is too short.
+    assert_eq!(rf.conflict_at(7), (0, 7));
+    assert_eq!(rf.conflict_at(100), (0, 7));
+    // skip back to the first index of the conflicting term.
+    assert_eq!(rf.conflict_at(5), (2, 3));
+    assert_eq!(rf.conflict_at(3), (2, 3));
+    assert_eq!(rf.conflict_at(2), (1, 1));
+    assert_eq!(rf.conflict_at(6), (4, 6));
+}
+
+#[test]
+fn test_fast_backup_next_index_2b() {
+    //                index: 1  2  3  4  5  6  7
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 3, 3, 5, 5]);
+    let args = AppendEntriesArgs {
+        term: 5,
+        prev_log_index: 6,
+        prev_log_term: 5,
+        ..Default::default()
+    };
+    let reject = |conflict_term, conflict_index| AppendEntriesReply {
+        term: 5,
+        success: false,
+        conflict_term,
+        conflict_index,
+    };
+
+    // the follower's log is too short.
+    rf.handle_append_entries_reply(1, &args, &reject(0, 3));
+    assert_eq!(rf.next_index[1], 3);
+    // the leader has no entry of term 4, skip the whole term.
+    rf.handle_append_entries_reply(1, &args, &reject(4, 4));
+    assert_eq!(rf.next_index[1], 4);
+    // the leader has entries of term 3, resume after the last one.
+    rf.handle_append_entries_reply(1, &args, &reject(3, 4));
+    assert_eq!(rf.next_index[1], 6);
+    rf.handle_append_entries_reply(1, &args, &reject(1, 1));
+    assert_eq!(rf.next_index[1], 3);
+
+    // a stale reply is ignored.
+    rf.handle_append_entries_reply(
+        1,
+        &args,
+        &AppendEntriesReply {
+            term: 4,
+            ..reject(2, 2)
+        },
+    );
+    assert_eq!(rf.next_index[1], 3);
+
+    // the follower agrees.
+    let args = AppendEntriesArgs {
+        term: 5,
+        prev_log_index: 2,
+        prev_log_term: 1,
+        entries: rf.log[3..].to_vec(),
+        ..Default::default()
+    };
+    let reply = AppendEntriesReply {
+        term: 5,
+        success: true,
+        ..Default::default()
+    };
+    rf.handle_append_entries_reply(1, &args, &reply);
+    assert_eq!(rf.match_index[1], 7);
+    assert_eq!(rf.next_index[1], 8);
+}
+
+#[test]
+fn test_not_leader_hint_2b() {
+    let mut rf = make_raft(3, 0, &[1]);
+
+    assert_eq!(rf.start(&1u64), Err(Error::NotLeader { leader_hint: None }));
+    rf.leader_id = Some(2);
+    assert_eq!(
+        rf.start(&1u64),
+        Err(Error::NotLeader {
+            leader_hint: Some(2)
+        })
+    );
+}
+
+#[test]
+fn test_snapshot_if_needed_3b() {
+    //                index: 1  2  3  4  5
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 3, 3]);
+    rf.log[4].data = vec![4];
+
+    rf.snapshot_if_needed(3, vec![33]);
+    assert_eq!(rf.snapshot_index, 3);
+    assert_eq!(rf.snapshot_term(), 2);
+    assert_eq!(rf.last_log_index(), 5);
+    assert_eq!(rf.term_at(2), None);
+    assert_eq!(rf.term_at(3), Some(2));
+    assert_eq!(rf.term_at(4), Some(3));
+    assert_eq!(rf.log[1].data, vec![4]);
+    assert_eq!(rf.persister.snapshot(), vec![33]);
+
+    // idempotent.
+    rf.snapshot_if_needed(2, vec![22]);
+    rf.snapshot_if_needed(3, vec![22]);
+    assert_eq!(rf.snapshot_index, 3);
+    assert_eq!(rf.persister.snapshot(), vec![33]);
+
+    // the compacted log survives a restart.
+    let persister = SimplePersister::new();
+    persister.save_state_and_snapshot(rf.persister.raft_state(), rf.persister.snapshot());
+    let (tx, _apply_ch) = unbounded();
+    let rf1 = Raft::new(vec![], 0, Box::new(persister), tx);
+    assert_eq!(rf1.current_term, rf.current_term);
+    assert_eq!(rf1.snapshot_index, 3);
+    assert_eq!(rf1.log, rf.log);
+
+    rf.snapshot_if_needed(5, vec![55]);
+    assert_eq!(rf.snapshot_index, 5);
+    assert_eq!(rf.snapshot_term(), 3);
+    assert_eq!(rf.last_log_index(), 5);
+    assert_eq!(rf.log.len(), 1);
+    assert_eq!(rf.conflict_at(6), (0, 6));
+}
+
+/// Crashes on `save_state_and_snapshot` before anything is written once
+/// `crash` is set.
+struct CrashingPersister {
+    inner: Arc<SimplePersister>,
+    crash: Arc<AtomicBool>,
+}
+
+impl Persister for CrashingPersister {
+    fn raft_state(&self) -> Vec<u8> {
+        self.inner.raft_state()
+    }
+    fn save_raft_state(&self, state: Vec<u8>) {
+        self.inner.save_raft_state(state)
+    }
+    fn save_state_and_snapshot(&self, state: Vec<u8>, snapshot: Vec<u8>) {
+        if self.crash.load(Ordering::SeqCst) {
+            panic!("crash");
+        }
+        self.inner.save_state_and_snapshot(state, snapshot)
+    }
+    fn snapshot(&self) -> Vec<u8> {
+        self.inner.snapshot()
+    }
+}
+
+#[test]
+fn test_persist_snapshot_3b() {
+    let restart = |persister: &Arc<SimplePersister>| {
+        let (tx, _apply_ch) = unbounded();
+        Raft::new(vec![], 0, Box::new(persister.clone()), tx)
+    };
+    let persister = Arc::new(SimplePersister::new());
+    let crash = Arc::new(AtomicBool::new(true));
+
+    //                index: 1  2  3  4  5
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 3, 3]);
+    rf.persister = Box::new(CrashingPersister {
+        inner: persister.clone(),
+        crash: crash.clone(),
+    });
+    rf.persist();
+
+    // crashes after the log is truncated in memory but before anything is
+    // persisted, nothing is lost.
+    let res = panic::catch_unwind(AssertUnwindSafe(|| rf.persist_snapshot(vec![33], 3, 2)));
+    assert!(res.is_err());
+    let rf1 = restart(&persister);
+    assert_eq!(rf1.snapshot_index, 0);
+    assert_eq!(log_terms(&rf1), vec![1, 1, 2, 3, 3]);
+    assert!(persister.snapshot().is_empty());
+
+    crash.store(false, Ordering::SeqCst);
+    let mut rf = restart(&persister);
+    rf.persister = Box::new(persister.clone());
+    rf.persist_snapshot(vec![33], 3, 2);
+    let rf1 = restart(&persister);
+    assert_eq!(rf1.snapshot_index, 3);
+    assert_eq!(rf1.snapshot_term(), 2);
+    assert_eq!(log_terms(&rf1), vec![3, 3]);
+    assert_eq!(persister.snapshot(), vec![33]);
+
+    // a snapshot the log does not agree on replaces the whole log.
+    rf.persist_snapshot(vec![77], 7, 4);
+    assert_eq!(rf.last_log_index(), 7);
+    assert_eq!(rf.snapshot_term(), 4);
+    assert_eq!(rf.commit_index, 7);
+    assert_eq!(rf.last_applied, 7);
+    // an older one changes nothing.
+    rf.persist_snapshot(vec![55], 5, 3);
+    assert_eq!(rf.snapshot_index, 7);
+    assert_eq!(persister.snapshot(), vec![77]);
+}
+
+#[test]
+fn test_apply_committed_entries_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 3, 3]);
+    for (i, entry) in rf.log.iter_mut().enumerate() {
+        entry.data = vec![i as u8];
+    }
+    let (tx, mut apply_ch) = unbounded();
+    rf.apply_ch = tx;
+    let mut applied = || {
+        let mut msgs = vec![];
+        while let Ok(msg) = apply_ch.try_recv() {
+            match msg {
+                ApplyMsg::Command {
+                    command,
+                    command_index,
+                } => {
+                    assert_eq!(command, vec![command_index as u8]);
+                    msgs.push(command_index);
+                }
+                msg => panic!("unexpected {:?}", msg),
+            }
+        }
+        msgs
+    };
+
+    assert_eq!(rf.apply_committed_entries(), 0);
+    rf.commit_index = 3;
+    assert_eq!(rf.apply_committed_entries(), 3);
+    assert_eq!(applied(), vec![1, 2, 3]);
+    assert_eq!(rf.last_applied, 3);
+    assert_eq!(rf.apply_committed_entries(), 0);
+    rf.commit_index = 4;
+    rf.apply_committed_entries();
+    assert_eq!(applied(), vec![4]);
+
+    // the entries in the snapshot are not applied again after a restart.
+    rf.snapshot_if_needed(4, vec![44]);
+    let persister = SimplePersister::new();
+    persister.save_state_and_snapshot(rf.persister.raft_state(), rf.persister.snapshot());
+    let (tx, mut apply_ch) = unbounded();
+    let mut rf = Raft::new(vec![], 0, Box::new(persister), tx);
+    assert_eq!((rf.commit_index, rf.last_applied), (4, 4));
+    rf.commit_index = 5;
+    assert_eq!(rf.apply_committed_entries(), 1);
+    let msg = apply_ch.try_recv().unwrap();
+    assert_eq!(
+        msg,
+        ApplyMsg::Command {
+            command: vec![5],
+            command_index: 5,
+        }
+    );
+}
+
+#[test]
+fn test_wait_for_apply_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 1]);
+    let (tx, _apply_ch) = unbounded();
+    rf.apply_ch = tx;
+    let applied = rf.applied_index();
+    assert_eq!(
+        applied.wait_for(1, Duration::from_millis(10)),
+        Err(Error::Timeout)
+    );
+
+    let waiter = {
+        let applied = applied.clone();
+        thread::spawn(move || {
+            let t0 = Instant::now();
+            applied.wait_for(2, Duration::from_secs(5)).unwrap();
+            t0.elapsed()
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    rf.commit_index = 1;
+    rf.apply_committed_entries();
+    assert_eq!(applied.get(), 1);
+    rf.commit_index = 2;
+    rf.apply_committed_entries();
+    // woken up as soon as the entry is applied.
+    let waited = waiter.join().unwrap();
+    assert!(waited < Duration::from_secs(1), "{:?}", waited);
+    // the applied entries need no wait.
+    applied.wait_for(1, Duration::from_millis(0)).unwrap();
+}
+
+#[test]
+fn test_has_quorum_with_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 1]);
+    assert!(!rf.has_quorum_with(&[]));
+    let args = rf.become_candidate();
+    rf.handle_request_vote_reply(
+        &args,
+        &RequestVoteReply {
+            term: args.term,
+            vote_granted: true,
+        },
+    );
+    rf.commit_index = 2;
+    // only the leader has the entry after the commit index.
+    assert!(!rf.has_quorum_with(&[]));
+
+    rf.match_index[1] = 3;
+    assert!(rf.has_quorum_with(&[]));
+    // 2 of 4.
+    assert!(!rf.has_quorum_with(&[3]));
+    rf.match_index[2] = 3;
+    assert!(rf.has_quorum_with(&[3]));
+    // 3 of 5, the existing members are not counted twice.
+    assert!(rf.has_quorum_with(&[3, 4, 1]));
+    // 3 of 6.
+    assert!(!rf.has_quorum_with(&[3, 4, 5]));
+    // 3 of 5, the repeated new members are counted once.
+    assert!(rf.has_quorum_with(&[3, 4, 3, 4, 4]));
+    assert!(rf.has_quorum_with(&[3, 3, 3, 3]));
+}
+
+#[test]
+fn test_metrics_3b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 3, 3]);
+    let metrics = rf.metrics();
+    assert_eq!(metrics.role, Role::Follower);
+    assert_eq!(metrics.term, 3);
+    assert_eq!(metrics.leader_id, None);
+    assert_eq!(metrics.log_len, 5);
+    assert_eq!(metrics.snapshot_index, 0);
+
+    rf.snapshot_if_needed(3, vec![33]);
+    let metrics = rf.metrics();
+    assert_eq!(metrics.log_len, 2);
+    assert_eq!(metrics.snapshot_index, 3);
+}
+
+fn append_args(
+    term: u64,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    terms: &[u64],
+    leader_commit: u64,
+) -> AppendEntriesArgs {
+    AppendEntriesArgs {
+        term,
+        leader_id: 1,
+        prev_log_index,
+        prev_log_term,
+        entries: terms
+            .iter()
+            .map(|&term| LogEntry { term, data: vec![] })
+            .collect(),
+        leader_commit,
+    }
+}
+
+fn log_terms(rf: &Raft) -> Vec<u64> {
+    rf.log[1..].iter().map(|e| e.term).collect()
+}
+
+#[test]
+fn test_append_entries_term_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 2]);
+
+    // a stale leader is rejected.
+    let reply = rf.handle_append_entries(&append_args(1, 3, 2, &[1], 3));
+    assert!(!reply.success);
+    assert_eq!(reply.term, 2);
+    assert_eq!(rf.leader_id, None);
+    assert_eq!(log_terms(&rf), vec![1, 1, 2]);
+
+    // a heartbeat from a newer leader.
+    let reply = rf.handle_append_entries(&append_args(3, 3, 2, &[], 2));
+    assert!(reply.success);
+    assert_eq!(reply.term, 3);
+    assert_eq!(rf.current_term, 3);
+    assert_eq!(rf.role, Role::Follower);
+    assert_eq!(rf.leader_id, Some(1));
+    assert_eq!(rf.commit_index, 2);
+    assert_eq!(log_terms(&rf), vec![1, 1, 2]);
+}
+
+#[test]
+fn test_append_entries_conflict_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 2]);
+
+    // the log is too short.
+    let reply = rf.handle_append_entries(&append_args(3, 6, 3, &[3], 0));
+    assert!(!reply.success);
+    assert_eq!((reply.conflict_term, reply.conflict_index), (0, 5));
+
+    // mismatch at prev_log_index.
+    let reply = rf.handle_append_entries(&append_args(3, 4, 3, &[3], 0));
+    assert!(!reply.success);
+    assert_eq!((reply.conflict_term, reply.conflict_index), (2, 3));
+    assert_eq!(log_terms(&rf), vec![1, 1, 2, 2]);
+
+    // conflicting entries are replaced.
+    let reply = rf.handle_append_entries(&append_args(3, 2, 1, &[3, 3, 3], 0));
+    assert!(reply.success);
+    assert_eq!(log_terms(&rf), vec![1, 1, 3, 3, 3]);
+}
+
+#[test]
+fn test_append_entries_stale_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 2]);
+
+    // a delayed request with a prefix of the log truncates nothing.
+    let reply = rf.handle_append_entries(&append_args(2, 1, 1, &[1, 2], 0));
+    assert!(reply.success);
+    assert_eq!(log_terms(&rf), vec![1, 1, 2, 2]);
+
+    // the entries in the snapshot are skipped.
+    rf.snapshot_if_needed(2, vec![]);
+    let reply = rf.handle_append_entries(&append_args(2, 0, 0, &[1, 1, 2, 2, 2], 0));
+    assert!(reply.success);
+    assert_eq!(rf.snapshot_index, 2);
+    assert_eq!(log_terms(&rf), vec![2, 2, 2]);
+}
+
+#[test]
+fn test_append_entries_committed_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1, 2, 2]);
+    rf.handle_append_entries(&append_args(2, 4, 2, &[], 3));
+    assert_eq!(rf.commit_index, 3);
+
+    // a request conflicting with a committed entry is rejected, instead of
+    // overwriting it.
+    let reply = rf.handle_append_entries(&append_args(2, 1, 1, &[1, 3, 3], 3));
+    assert!(!reply.success);
+    assert_eq!((reply.conflict_term, reply.conflict_index), (0, 4));
+    assert_eq!(log_terms(&rf), vec![1, 1, 2, 2]);
+    assert_eq!(rf.commit_index, 3);
+}
+
+#[test]
+fn test_append_entries_commit_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1]);
+
+    // commit_index is bounded by the last new entry.
+    let reply = rf.handle_append_entries(&append_args(1, 1, 1, &[], 2));
+    assert!(reply.success);
+    assert_eq!(rf.commit_index, 1);
+    let reply = rf.handle_append_entries(&append_args(1, 2, 1, &[1], 5));
+    assert!(reply.success);
+    assert_eq!(rf.commit_index, 3);
+    // and never goes backwards.
+    let reply = rf.handle_append_entries(&append_args(1, 1, 1, &[], 1));
+    assert!(reply.success);
+    assert_eq!(rf.commit_index, 3);
+}
+
+#[test]
+fn test_advance_commit_index_2b() {
+    //                 index: 1  2  3
+    let mut rf = make_raft(5, 0, &[1, 1, 2]);
+    rf.current_term = 3;
+    rf.become_leader();
+    let (index, term) = rf.start(&1u64).unwrap();
+    assert_eq!((index, term), (4, 3));
+
+    let reply = AppendEntriesReply {
+        term: 3,
+        success: true,
+        ..Default::default()
+    };
+    let replicate = |rf: &mut Raft, server, matched| {
+        let args = append_args(3, matched, 0, &[], 0);
+        rf.handle_append_entries_reply(server, &args, &reply);
+    };
+    // the entries of an earlier term are not committed by counting replicas.
+    replicate(&mut rf, 1, 3);
+    replicate(&mut rf, 2, 3);
+    assert_eq!(rf.commit_index, 0);
+    // not a majority yet.
+    replicate(&mut rf, 1, 4);
+    assert_eq!(rf.commit_index, 0);
+    // an entry of the current term commits the entries before it.
+    replicate(&mut rf, 3, 4);
+    assert_eq!(rf.commit_index, 4);
+}
+
+struct Store(Vec<u8>);
+
+impl StateMachine for Store {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        if key == b"x" {
+            Some(self.0.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_follower_read_2b() {
+    let mut rf = make_raft(3, 0, &[1, 1]);
+    let reply = rf.handle_append_entries(&append_args(1, 2, 1, &[], 2));
+    assert!(reply.success);
+    // no state machine to read from.
+    assert_eq!(rf.follower_read(b"x"), Err(Error::LeaseExpired));
+
+    let mut rf = make_raft(3, 0, &[1, 1]);
+    rf.set_state_machine(Arc::new(Store(b"1".to_vec())));
+    // never heard from a leader.
+    assert_eq!(rf.follower_read(b"x"), Err(Error::LeaseExpired));
+
+    let reply = rf.handle_append_entries(&append_args(1, 2, 1, &[], 2));
+    assert!(reply.success);
+    assert_eq!(rf.follower_read(b"x"), Ok(Some(b"1".to_vec())));
+    assert_eq!(rf.follower_read(b"y"), Ok(None));
+
+    // the lease is 90% of the heartbeat interval.
+    thread::sleep(rf.heartbeat_interval * 9 / 10);
+    assert_eq!(rf.follower_read(b"x"), Err(Error::LeaseExpired));
+
+    // a stale leader does not extend the lease.
+    rf.current_term = 2;
+    let reply = rf.handle_append_entries(&append_args(1, 2, 1, &[], 2));
+    assert!(!reply.success);
+    assert_eq!(rf.follower_read(b"x"), Err(Error::LeaseExpired));
+
+    rf.handle_append_entries(&append_args(2, 2, 1, &[], 2));
+    assert!(rf.follower_read(b"x").is_ok());
+    rf.role = Role::Candidate;
+    assert_eq!(rf.follower_read(b"x"), Err(Error::LeaseExpired));
+}
+
+#[test]
+fn test_node_follower_read_2b() {
+    let config = RaftConfig {
+        seed: Some(1),
+        heartbeat_interval: Some(Duration::from_millis(50)),
+    };
+    let mut cluster = Cluster::new(3, config);
+    let leader = cluster.check_one_leader();
+    let follower = (leader + 1) % 3;
+    for i in 0..3 {
+        cluster
+            .node(i)
+            .set_state_machine(Arc::new(Store(b"1".to_vec())));
+    }
+    assert_eq!(
+        cluster.node(leader).follower_read(b"x"),
+        Err(Error::LeaseExpired)
+    );
+
+    // the lease lapses shortly before each heartbeat.
+    let start = Instant::now();
+    while cluster.node(follower).follower_read(b"x").is_err() {
+        assert!(start.elapsed() < Duration::from_secs(1));
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert_eq!(
+        cluster.node(follower).follower_read(b"x"),
+        Ok(Some(b"1".to_vec()))
+    );
+
+    // a follower cut off from the leader stops serving reads.
+    cluster.disconnect(follower);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(
+        cluster.node(follower).follower_read(b"x"),
+        Err(Error::LeaseExpired)
+    );
+}
+
+#[test]
+fn test_request_vote_reply_2a() {
+    let mut rf = make_raft(5, 0, &[1, 1]);
+    let vote = |term, vote_granted| RequestVoteReply { term, vote_granted };
+
+    let first = rf.become_candidate();
+    assert_eq!(first.term, 2);
+    assert_eq!((first.last_log_index, first.last_log_term), (2, 1));
+    rf.handle_request_vote_reply(&first, &vote(2, true));
+    // the election times out, a new one begins.
+    let second = rf.become_candidate();
+    assert_eq!(second.term, 3);
+    assert_eq!(rf.votes_received, 1);
+
+    // the late votes of the first election do not count.
+    rf.handle_request_vote_reply(&first, &vote(2, true));
+    rf.handle_request_vote_reply(&first, &vote(2, true));
+    assert_eq!(rf.role, Role::Candidate);
+    assert_eq!(rf.votes_received, 1);
+
+    rf.handle_request_vote_reply(&second, &vote(3, false));
+    rf.handle_request_vote_reply(&second, &vote(3, true));
+    assert_eq!(rf.role, Role::Candidate);
+    rf.handle_request_vote_reply(&second, &vote(3, true));
+    assert_eq!(rf.role, Role::Leader);
+    assert_eq!(rf.leader_id, Some(0));
+    assert_eq!(rf.next_index, vec![3; 5]);
+    assert_eq!(rf.match_index, vec![2, 0, 0, 0, 0]);
+    // more votes change nothing.
+    rf.handle_request_vote_reply(&second, &vote(3, true));
+    assert_eq!(rf.role, Role::Leader);
+
+    let metrics = rf.metrics();
+    assert_eq!(metrics.elections_started, 2);
+    assert_eq!(metrics.votes_granted, 2);
+}
+
+#[test]
+fn test_request_vote_reply_newer_term_2a() {
+    let mut rf = make_raft(3, 0, &[1]);
+    let args = rf.become_candidate();
+    rf.handle_request_vote_reply(
+        &args,
+        &RequestVoteReply {
+            term: 5,
+            vote_granted: false,
+        },
+    );
+    assert_eq!(rf.role, Role::Follower);
+    assert_eq!(rf.current_term, 5);
+
+    // a vote for the old election can not make it the leader.
+    rf.handle_request_vote_reply(
+        &args,
+        &RequestVoteReply {
+            term: 2,
+            vote_granted: true,
+        },
+    );
+    assert_eq!(rf.role, Role::Follower);
+}
+
+/// The vote persisted by `rf`.
+fn persisted_vote(rf: &Raft) -> Option<u64> {
+    let state: PersistentState = labcodec::decode(&rf.persister.raft_state()).unwrap();
+    state.voted_for.map(|vote| vote.candidate_id)
+}
+
+#[test]
+fn test_voted_for_2a() {
+    let mut rf = make_raft(3, 0, &[1, 1]);
+    let request = |term, candidate_id, last_log_index, last_log_term| RequestVoteArgs {
+        term,
+        candidate_id,
+        last_log_index,
+        last_log_term,
+    };
+
+    // the self-vote is persisted before the RequestVote is sent.
+    let args = rf.become_candidate();
+    assert_eq!(rf.voted_for, Some(0));
+    assert_eq!(persisted_vote(&rf), Some(0));
+    // no vote for another candidate in the same term.
+    let reply = rf.handle_request_vote(&request(args.term, 1, 2, 1));
+    assert!(!reply.vote_granted);
+
+    let mut rf = make_raft(3, 0, &[1, 1]);
+    // the log of the candidate is not up-to-date.
+    assert!(!rf.handle_request_vote(&request(1, 1, 1, 1)).vote_granted);
+    assert!(!rf.handle_request_vote(&request(1, 1, 5, 0)).vote_granted);
+    assert_eq!(rf.voted_for, None);
+
+    let reply = rf.handle_request_vote(&request(1, 1, 2, 1));
+    assert!(reply.vote_granted);
+    assert_eq!(rf.voted_for, Some(1));
+    assert_eq!(persisted_vote(&rf), Some(1));
+    // the same candidate again, e.g. a retried request.
+    assert!(rf.handle_request_vote(&request(1, 1, 2, 1)).vote_granted);
+    assert!(!rf.handle_request_vote(&request(1, 2, 3, 1)).vote_granted);
+    assert_eq!(rf.metrics().votes_granted, 1);
+
+    // the vote survives a restart.
+    let persister = SimplePersister::new();
+    persister.save_raft_state(rf.persister.raft_state());
+    let (tx, _apply_ch) = unbounded();
+    let mut rf = Raft::new(vec![], 0, Box::new(persister), tx);
+    assert_eq!(rf.voted_for, Some(1));
+    assert!(!rf.handle_request_vote(&request(1, 2, 3, 1)).vote_granted);
+}
+
+#[test]
+fn test_refused_vote_keeps_timer_2a() {
+    // the candidate has a stale log.
+    let mut rf = make_raft(3, 0, &[1, 2]);
+    let deadline = rf.election_deadline;
+    let args = RequestVoteArgs {
+        term: 5,
+        candidate_id: 1,
+        last_log_index: 5,
+        last_log_term: 1,
+    };
+    let reply = rf.handle_request_vote(&args);
+    assert!(!reply.vote_granted);
+    assert_eq!(rf.current_term, 5);
+    assert_eq!(rf.election_deadline, deadline);
+
+    // a granted vote resets it.
+    let args = RequestVoteArgs {
+        term: 6,
+        last_log_index: 2,
+        last_log_term: 2,
+        ..args
+    };
+    let now = Instant::now();
+    rf.election_deadline = now;
+    assert!(rf.handle_request_vote(&args).vote_granted);
+    assert!(rf.election_deadline >= now + ELECTION_TIMEOUT_MIN);
+}
+
+#[test]
+fn test_step_down_2a() {
+    let mut rf = make_raft(3, 0, &[1]);
+    let args = rf.become_candidate();
+    let reply = RequestVoteReply {
+        term: args.term,
+        vote_granted: true,
+    };
+    rf.handle_request_vote_reply(&args, &reply);
+    assert_eq!(rf.role, Role::Leader);
+
+    rf.step_down();
+    assert_eq!(rf.role, Role::Follower);
+    assert_eq!(rf.current_term, 2);
+    assert_eq!(rf.leader_id, None);
+    // the former leader sends no heartbeats.
+    assert!(rf.send_heartbeats(Instant::now()).is_empty());
+    assert!(!rf.election_timeout_elapsed(Instant::now()));
+
+    // and runs for the next term once its election timer fires.
+    thread::sleep(Duration::from_millis(600));
+    assert!(rf.election_timeout_elapsed(Instant::now()));
+    let args = rf.become_candidate();
+    assert_eq!(args.term, 3);
+    assert_eq!(rf.role, Role::Candidate);
+}
+
+#[test]
+fn test_step_down_clears_vote_2a() {
+    let mut rf = make_raft(3, 0, &[1]);
+    let request = |term, candidate_id| RequestVoteArgs {
+        term,
+        candidate_id,
+        last_log_index: 1,
+        last_log_term: 1,
+    };
+    assert!(rf.handle_request_vote(&request(1, 1)).vote_granted);
+
+    // stepping down in the same term keeps the vote.
+    rf.step_down();
+    assert_eq!(rf.voted_for, Some(1));
+    assert!(!rf.handle_request_vote(&request(1, 2)).vote_granted);
+
+    // a newer term from a RequestVote, the vote goes to the new candidate.
+    assert!(rf.handle_request_vote(&request(2, 2)).vote_granted);
+    assert_eq!(rf.voted_for, Some(2));
+
+    // a newer term from an AppendEntries.
+    rf.handle_append_entries(&append_args(3, 1, 1, &[], 0));
+    assert_eq!(rf.current_term, 3);
+    assert_eq!(rf.voted_for, None);
+    assert_eq!(persisted_vote(&rf), None);
+
+    // a newer term from an InstallSnapshot.
+    assert!(rf.handle_request_vote(&request(3, 1)).vote_granted);
+    rf.handle_install_snapshot(&InstallSnapshotArgs {
+        term: 4,
+        leader_id: 2,
+        ..Default::default()
+    });
+    assert_eq!(rf.current_term, 4);
+    assert_eq!(rf.voted_for, None);
+    assert_eq!(persisted_vote(&rf), None);
+
+    // a newer term from a reply, the candidate gives up its own vote.
+    let args = rf.become_candidate();
+    assert_eq!(rf.voted_for, Some(0));
+    let reply = RequestVoteReply {
+        term: args.term + 1,
+        vote_granted: false,
+    };
+    rf.handle_request_vote_reply(&args, &reply);
+    assert_eq!(rf.role, Role::Follower);
+    assert_eq!(rf.voted_for, None);
+    assert_eq!(persisted_vote(&rf), None);
+}
+
+/// Delivers `rpc` from `leader` to `server` and its reply back.
+fn deliver(peers: &mut [Raft], leader: usize, server: usize, rpc: &PeerRpc) {
+    match rpc {
+        PeerRpc::AppendEntries(args) => {
+            let reply = peers[server].handle_append_entries(args);
+            peers[leader].handle_append_entries_reply(server, args, &reply);
+        }
+        PeerRpc::InstallSnapshot(args) => {
+            let reply = peers[server].handle_install_snapshot(args);
+            peers[leader].handle_install_snapshot_reply(server, args, &reply);
+        }
+    }
+}
+
+#[test]
+fn test_heartbeats_2a() {
+    let mut peers: Vec<_> = (0..3).map(|i| make_raft(3, i, &[1, 1])).collect();
+    let args = peers[0].become_candidate();
+    let reply = RequestVoteReply {
+        term: args.term,
+        vote_granted: true,
+    };
+    peers[0].handle_request_vote_reply(&args, &reply);
+    assert_eq!(peers[0].role, Role::Leader);
+    peers[0].commit_index = 2;
+
+    // the heartbeats are sent right after the election.
+    let interval = peers[0].heartbeat_interval;
+    let now = Instant::now();
+    let heartbeats = peers[0].send_heartbeats(now);
+    assert_eq!(heartbeats.len(), 2);
+    for (server, rpc) in &heartbeats {
+        deliver(&mut peers, 0, *server, rpc);
+    }
+    // each heartbeat resets the election timers, which would fire well after
+    // the next heartbeat is due.
+    let next_heartbeat = peers[0].heartbeat_deadline;
+    assert_eq!(next_heartbeat, now + interval);
+    for rf in &peers[1..] {
+        assert!(rf.election_deadline >= next_heartbeat + ELECTION_TIMEOUT_MIN - interval);
+        assert!(!rf.election_timeout_elapsed(next_heartbeat));
+        assert_eq!(rf.role, Role::Follower);
+        assert_eq!(rf.leader_id, Some(0));
+        assert_eq!(rf.current_term, 2);
+        // followers learn the commit index from the heartbeats.
+        assert_eq!(rf.commit_index, 2);
+    }
+
+    // and then once per heartbeat interval.
+    for round in 1..20 {
+        let due = now + interval * round;
+        assert!(peers[0].send_heartbeats(due - interval / 2).is_empty());
+        assert_eq!(peers[0].send_heartbeats(due).len(), 2);
+        assert!(peers[0].send_heartbeats(due).is_empty());
+    }
+    assert_eq!(peers[0].metrics().append_entries_sent, 2 * 20);
+
+    // without heartbeats, the followers time out.
+    let timed_out = Instant::now() + ELECTION_TIMEOUT_MAX;
+    for rf in &peers[1..] {
+        assert!(rf.election_timeout_elapsed(timed_out));
+    }
+    assert!(!peers[0].election_timeout_elapsed(timed_out));
+}
+
+#[test]
+fn test_node_heartbeats_2a() {
+    let config = RaftConfig {
+        seed: Some(1),
+        heartbeat_interval: Some(Duration::from_millis(50)),
+    };
+    let cluster = Cluster::new(3, config);
+    let leader = cluster.check_one_leader();
+    let term = cluster.node(leader).term();
+    let elections = |cluster: &Cluster| -> u64 {
+        (0..3)
+            .map(|i| cluster.node(i).metrics().elections_started)
+            .sum()
+    };
+    let started = elections(&cluster);
+
+    // the heartbeats are six times as frequent as the shortest election
+    // timeout, so no follower starts an election for several timeouts.
+    thread::sleep(ELECTION_TIMEOUT_MAX * 3);
+    assert_eq!(cluster.check_one_leader(), leader);
+    assert_eq!(elections(&cluster), started);
+    for i in 0..3 {
+        assert_eq!(cluster.node(i).term(), term);
+    }
+    let sent = cluster.node(leader).metrics().append_entries_sent;
+    assert!(sent >= 2 * 3 * 6, "{} heartbeats", sent);
+
+    // followers learn the commit index from the heartbeats.
+    let (index, _) = cluster.node(leader).start(&1u64).unwrap();
+    for i in 0..3 {
+        cluster
+            .node(i)
+            .wait_for_apply(index, Duration::from_secs(2))
+            .unwrap();
+    }
+}
+
+#[test]
+fn test_install_snapshot_3b() {
+    let mut peers: Vec<_> = (0..3).map(|i| make_raft(3, i, &[1])).collect();
+    let args = peers[0].become_candidate();
+    let reply = RequestVoteReply {
+        term: args.term,
+        vote_granted: true,
+    };
+    peers[0].handle_request_vote_reply(&args, &reply);
+    let heartbeat = |peers: &mut Vec<Raft>, to: &[usize]| {
+        peers[0].heartbeat_deadline = Instant::now();
+        for (server, rpc) in peers[0].send_heartbeats(Instant::now()) {
+            if to.contains(&server) {
+                deliver(peers, 0, server, &rpc);
+            }
+        }
+    };
+    heartbeat(&mut peers, &[1, 2]);
+
+    // 1000 entries are committed and compacted while follower 2 is down.
+    let term = peers[0].current_term;
+    for i in 0..1000u32 {
+        peers[0].log.push(LogEntry {
+            term,
+            data: i.to_be_bytes().to_vec(),
+        });
+    }
+    heartbeat(&mut peers, &[1]);
+    peers[0].commit_index = 1001;
+    peers[0].snapshot_if_needed(1001, b"snapshot".to_vec());
+    assert_eq!(peers[0].log.len(), 1);
+
+    // follower 2 restarts with an empty log.
+    let (tx, mut apply_ch) = unbounded();
+    peers[2] = Raft::new(vec![], 2, Box::new(SimplePersister::new()), tx);
+    peers[0].next_index[2] = 1;
+    peers[0].heartbeat_deadline = Instant::now();
+    let rpcs = peers[0].send_heartbeats(Instant::now());
+    assert!(rpcs
+        .iter()
+        .any(|(server, rpc)| *server == 2 && matches!(rpc, PeerRpc::InstallSnapshot(_))));
+    assert!(rpcs
+        .iter()
+        .any(|(server, rpc)| *server == 1 && matches!(rpc, PeerRpc::AppendEntries(_))));
+    for (server, rpc) in rpcs {
+        deliver(&mut peers, 0, server, &rpc);
+    }
+    assert_eq!(
+        apply_ch.try_recv().unwrap(),
+        ApplyMsg::Snapshot {
+            data: b"snapshot".to_vec(),
+            index: 1001,
+            term,
+        }
+    );
+    assert_eq!(peers[2].snapshot_index, 1001);
+    assert_eq!(peers[2].persister.snapshot(), b"snapshot".to_vec());
+    assert_eq!((peers[2].commit_index, peers[2].last_applied), (1001, 1001));
+    assert_eq!(peers[0].match_index[2], 1001);
+
+    // it catches up the entries after the snapshot by AppendEntries.
+    peers[0].log.push(LogEntry {
+        term,
+        data: vec![1],
+    });
+    peers[0].commit_index = 1002;
+    heartbeat(&mut peers, &[1, 2]);
+    assert_eq!(peers[0].match_index[2], 1002);
+    assert_eq!(peers[2].last_log_index(), 1002);
+    assert_eq!(peers[2].commit_index, 1002);
+
+    // a stale snapshot does not roll the follower back.
+    let args = InstallSnapshotArgs {
+        term,
+        leader_id: 0,
+        last_included_index: 1001,
+        last_included_term: term,
+        data: b"snapshot".to_vec(),
+    };
+    peers[2].handle_install_snapshot(&args);
+    assert_eq!(peers[2].last_log_index(), 1002);
+    assert!(apply_ch.try_recv().is_err());
+}
+
+/// A peer answering RequestVote after `delay`, in `term`.
+#[derive(Clone)]
+struct Voter {
+    term: u64,
+    grant: bool,
+    delay: Duration,
+}
+
+#[async_trait::async_trait]
+impl RaftService for Voter {
+    async fn request_vote(&self, args: RequestVoteArgs) -> labrpc::Result<RequestVoteReply> {
+        Delay::new(self.delay).await;
+        Ok(RequestVoteReply {
+            term: self.term.max(args.term),
+            vote_granted: self.grant,
+        })
+    }
+
+    async fn append_entries(&self, _: AppendEntriesArgs) -> labrpc::Result<AppendEntriesReply> {
+        Err(labrpc::Error::Unimplemented("append_entries".to_owned()))
+    }
+
+    async fn install_snapshot(
+        &self,
+        _: InstallSnapshotArgs,
+    ) -> labrpc::Result<InstallSnapshotReply> {
+        Err(labrpc::Error::Unimplemented("install_snapshot".to_owned()))
+    }
+}
+
+/// Peer 0 of a cluster whose other peers are `voters`.
+fn make_candidate(voters: &[Voter]) -> (labrpc::Network, Raft) {
+    let net = labrpc::Network::new();
+    let mut peers = vec![];
+    for i in 0..=voters.len() {
+        let end_name = format!("0-{}", i);
+        peers.push(RaftClient::new(net.create_client(end_name.clone())));
+        if i > 0 {
+            let mut builder = labrpc::ServerBuilder::new(i.to_string());
+            add_raft_service(voters[i - 1].clone(), &mut builder).unwrap();
+            net.add_server(builder.build());
+            net.connect(&end_name, &i.to_string());
+            net.enable(&end_name, true);
+        }
+    }
+    let (tx, _apply_ch) = unbounded();
+    let rf = Raft::new(peers, 0, Box::new(SimplePersister::new()), tx);
+    (net, rf)
+}
+
+#[test]
+fn test_install_snapshot_restart_3b() {
+    let mut cluster = Cluster::new(3, RaftConfig::default());
+    let leader = cluster.check_one_leader();
+    let follower = (leader + 1) % 3;
+    cluster.crash1(follower);
+
+    let (mut last, mut term) = (0, 0);
+    for i in 0..1000u64 {
+        let (index, t) = cluster.node(leader).start(&i).unwrap();
+        last = index;
+        term = t;
+    }
+    assert_eq!(last, 1000);
+    cluster
+        .node(leader)
+        .wait_for_apply(last, Duration::from_secs(10))
+        .unwrap();
+    let snapshot = b"applied 1000 entries".to_vec();
+    cluster
+        .node(leader)
+        .snapshot_if_needed(last, snapshot.clone());
+    assert_eq!(cluster.node(leader).metrics().log_len, 0);
+
+    // the entries the follower needs are all in the snapshot.
+    cluster.start1(follower);
+    cluster.connect(follower);
+    cluster
+        .node(follower)
+        .wait_for_apply(last, Duration::from_secs(5))
+        .unwrap();
+    let metrics = cluster.node(follower).metrics();
+    assert_eq!(metrics.snapshot_index, last);
+    assert_eq!(metrics.log_len, 0);
+    assert_eq!(
+        cluster.wait_applied(follower, 1),
+        vec![ApplyMsg::Snapshot {
+            data: snapshot,
+            index: last,
+            term,
+        }]
+    );
+
+    // and it replicates the entries after the snapshot.
+    let leader = cluster.check_one_leader();
+    let (index, _) = cluster.node(leader).start(&1000u64).unwrap();
+    let mut command = vec![];
+    labcodec::encode(&1000u64, &mut command).unwrap();
+    assert_eq!(
+        cluster.wait_applied(follower, 2)[1],
+        ApplyMsg::Command {
+            command,
+            command_index: index,
+        }
+    );
+}
+
+/// Counts the votes as they arrive until the election is decided.
+fn run_election(rf: &mut Raft) {
+    let args = rf.become_candidate();
+    let mut votes = rf.send_request_vote_parallel(&args);
+    block_on(async {
+        while let Some((_, res)) = votes.next().await {
+            if let Ok(reply) = res {
+                rf.handle_request_vote_reply(&args, &reply);
+            }
+            if rf.role != Role::Candidate || rf.current_term != args.term {
+                break;
+            }
+        }
+    });
+}
+
+#[test]
+fn test_seeded_election_timeout_2a() {
+    let make = |me, seed| {
+        let (tx, _apply_ch) = unbounded();
+        let config = RaftConfig {
+            seed,
+            ..Default::default()
+        };
+        Raft::with_config(vec![], me, Box::new(SimplePersister::new()), tx, config)
+    };
+    let timeouts = |seed| {
+        (0..3)
+            .map(|me| {
+                let mut rf = make(me, seed);
+                (0..10).map(|_| rf.election_timeout()).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let seeded = timeouts(Some(42));
+    assert_eq!(seeded, timeouts(Some(42)));
+    assert_ne!(seeded[0], seeded[1]);
+    assert_ne!(seeded, timeouts(Some(7)));
+    assert_ne!(timeouts(None), timeouts(None));
+
+    // the peer timing out first starts the first election.
+    let first_candidate = |seed| {
+        let peers = (0..3).map(|me| make(me, seed)).collect::<Vec<_>>();
+        (0..3).min_by_key(|&i| peers[i].election_deadline).unwrap()
+    };
+    let want = first_candidate(Some(42));
+    for _ in 0..10 {
+        assert_eq!(first_candidate(Some(42)), want);
+    }
+}
+
+#[test]
+fn test_request_vote_parallel_2a() {
+    let voter = Voter {
+        term: 0,
+        grant: true,
+        delay: Duration::from_millis(300),
+    };
+
+    // the requests are sent at once.
+    let (_net, rf) = make_candidate(&vec![voter.clone(); 4]);
+    let start = Instant::now();
+    let args = RequestVoteArgs {
+        term: 1,
+        ..Default::default()
+    };
+    let replies: Vec<_> = block_on(rf.send_request_vote_parallel(&args).collect());
+    assert_eq!(replies.len(), 4);
+    assert!(replies
+        .iter()
+        .all(|(_, res)| res.as_ref().unwrap().vote_granted));
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_millis(900), "{:?}", elapsed);
+
+    // the candidate wins without waiting for the slow peers.
+    let slow = Voter {
+        delay: Duration::from_secs(10),
+        ..voter.clone()
+    };
+    let (_net, mut rf) = make_candidate(&[slow.clone(), voter.clone(), slow, voter.clone()]);
+    let start = Instant::now();
+    run_election(&mut rf);
+    assert_eq!(rf.role, Role::Leader);
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+    // nor after a newer term is seen.
+    let newer = Voter {
+        term: 5,
+        grant: false,
+        delay: Duration::from_millis(0),
+    };
+    let slow = Voter {
+        delay: Duration::from_secs(10),
+        ..voter
+    };
+    let (_net, mut rf) = make_candidate(&[slow.clone(), newer, slow.clone(), slow]);
+    let start = Instant::now();
+    run_election(&mut rf);
+    assert_eq!((rf.role, rf.current_term), (Role::Follower, 5));
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+}